@@ -0,0 +1,305 @@
+use serde::Deserialize;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::sync::mpsc::{SyncSender, sync_channel};
+use tracing::error;
+use haiku_common::shm_accessor::market_data_type::TradeEvent;
+use crate::price::ContractType;
+use crate::shm_slots::ShmSlots;
+
+// Record of a bar slot, the last completed bar of one instrument and one bar spec.
+// Times are the exchange ones in ms. volume is in the unit of the trade sizes (USD contracts for inverse
+// instruments), dollar_volume in USD: the sizes of inverse instruments, price * size for the others.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[repr(C)]
+pub struct Bar {
+    // time bars: the interval, volume and dollar bars: first and last trade
+    pub start_ms: u64,
+    pub end_ms: u64,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: f64,
+    pub dollar_volume: f64,
+    // traded quantity of the base currency (BTC, ETH...), the vwap weights the prices with it
+    pub base_volume: f64,
+    pub vwap: f64,
+    // taker side
+    pub buy_volume: f64,
+    pub sell_volume: f64,
+    pub trade_count: u64,
+}
+
+impl Bar {
+    fn open_with(start_ms: u64, end_ms: u64, trade: &TradeEvent, contract: ContractType) -> Self {
+        let price = trade.price as f64;
+        let mut bar = Self { start_ms, end_ms, open: price, high: price, low: price, close: price, ..Self::default() };
+        bar.add(trade, contract);
+        bar
+    }
+
+    #[inline]
+    fn add(&mut self, trade: &TradeEvent, contract: ContractType) {
+        let (price, size) = (trade.price as f64, trade.size as f64);
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.volume += size;
+        let dollars = contract.notional(price, size);
+        self.dollar_volume += dollars;
+        self.base_volume += match contract {
+            ContractType::Linear => size,
+            ContractType::Inverse => dollars / price,
+        };
+        self.vwap = self.dollar_volume / self.base_volume;
+        if trade.side == 1 {
+            self.buy_volume += size;
+        } else {
+            self.sell_volume += size;
+        }
+        self.trade_count += 1;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BarSpec {
+    // aligned on multiples of the interval since the epoch
+    Time { interval_ms: u64 },
+    // closed by the trade reaching the threshold, which belongs entirely to the bar
+    Volume { threshold: f64 },
+    Dollar { threshold: f64 },
+}
+
+impl fmt::Display for BarSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BarSpec::Time { interval_ms } => write!(f, "time_{}ms", interval_ms),
+            BarSpec::Volume { threshold } => write!(f, "volume_{}", threshold),
+            BarSpec::Dollar { threshold } => write!(f, "dollar_{}", threshold),
+        }
+    }
+}
+
+impl BarSpec {
+    // Adds the trade to the bar in progress, returns the bar it completes
+    pub fn on_trade(&self, current: &mut Option<Bar>, trade: &TradeEvent, contract: ContractType) -> Option<Bar> {
+        let timestamp = trade.timestamp_ns;
+        match *self {
+            BarSpec::Time { interval_ms } => {
+                let completed = current.take_if(|bar| timestamp >= bar.end_ms);
+                match current {
+                    Some(bar) => bar.add(trade, contract),
+                    None => {
+                        let start_ms = timestamp - timestamp % interval_ms;
+                        *current = Some(Bar::open_with(start_ms, start_ms + interval_ms, trade, contract));
+                    }
+                }
+                completed
+            }
+            BarSpec::Volume { threshold } | BarSpec::Dollar { threshold } => {
+                match current {
+                    Some(bar) => {
+                        bar.add(trade, contract);
+                        bar.end_ms = timestamp;
+                    }
+                    None => *current = Some(Bar::open_with(timestamp, timestamp, trade, contract)),
+                }
+                current.take_if(|bar| match self {
+                    BarSpec::Volume { .. } => bar.volume >= threshold,
+                    _ => bar.dollar_volume >= threshold,
+                })
+            }
+        }
+    }
+
+    // Time bars whose interval is over, without waiting for the next trade of the instrument
+    #[inline]
+    pub fn on_clock(&self, current: &mut Option<Bar>, now_ms: u64) -> Option<Bar> {
+        match self {
+            BarSpec::Time { .. } => current.take_if(|bar| now_ms >= bar.end_ms),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BarsConfig {
+    #[serde(default = "default_time_ms")]
+    pub time_ms: Vec<u64>,
+    // size traded per bar, in the unit of the trade sizes
+    #[serde(default)]
+    pub volume: Vec<f64>,
+    // USD traded per bar
+    #[serde(default)]
+    pub dollar: Vec<f64>,
+    #[serde(default = "default_shm_name")]
+    pub shm_name: String,
+    // completed bars are also appended to this CSV file
+    #[serde(default)]
+    pub file: Option<String>,
+}
+
+fn default_time_ms() -> Vec<u64> {
+    vec![1_000, 60_000]
+}
+
+fn default_shm_name() -> String {
+    "haiku_fh_bars".to_string()
+}
+
+impl BarsConfig {
+    // In slot order: time, volume then dollar bars, as configured
+    pub fn specs(&self) -> Vec<BarSpec> {
+        let time = self.time_ms.iter().map(|&interval_ms| BarSpec::Time { interval_ms });
+        let volume = self.volume.iter().map(|&threshold| BarSpec::Volume { threshold });
+        let dollar = self.dollar.iter().map(|&threshold| BarSpec::Dollar { threshold });
+        time.chain(volume).chain(dollar).collect()
+    }
+}
+
+const FILE_HEADER: &str = "instrument_idx,bar,start_ms,end_ms,open,high,low,close,volume,dollar_volume,base_volume,vwap,buy_volume,sell_volume,trade_count";
+
+// Appends the bars from a thread of its own, the writer never waits for the disk
+fn spawn_bar_file(path: &str) -> std::io::Result<SyncSender<(usize, BarSpec, Bar)>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let is_new = file.metadata()?.len() == 0;
+    let mut writer = BufWriter::new(file);
+    if is_new {
+        writeln!(writer, "{}", FILE_HEADER)?;
+    }
+    let (tx, rx) = sync_channel::<(usize, BarSpec, Bar)>(4096);
+    let path = path.to_string();
+    std::thread::Builder::new().name("fh-bars".to_string()).spawn(move || {
+        while let Ok((instrument_idx, spec, bar)) = rx.recv() {
+            let written = writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                instrument_idx, spec, bar.start_ms, bar.end_ms, bar.open, bar.high, bar.low, bar.close,
+                bar.volume, bar.dollar_volume, bar.base_volume, bar.vwap, bar.buy_volume, bar.sell_volume, bar.trade_count
+            )
+            .and_then(|_| writer.flush());
+            if let Err(e) = written {
+                error!("bars: cannot write to {}: {}", path, e);
+            }
+        }
+    })?;
+    Ok(tx)
+}
+
+// Builds the bars of every instrument from the trades, slot instrument_idx * nb_specs + spec index
+pub struct BarPublisher {
+    specs: Vec<BarSpec>,
+    // by instrument index
+    contract_types: Vec<ContractType>,
+    current: Vec<Option<Bar>>,
+    slots: ShmSlots<Bar>,
+    file: Option<SyncSender<(usize, BarSpec, Bar)>>,
+    // latest exchange time of the trades of each instrument
+    last_trade_ms: Vec<u64>,
+}
+
+impl BarPublisher {
+    pub fn new(config: BarsConfig, contract_types: Vec<ContractType>) -> std::io::Result<Self> {
+        let specs = config.specs();
+        let nb_instruments = contract_types.len();
+        let nb_slots = nb_instruments * specs.len();
+        let slots = ShmSlots::create(&config.shm_name, nb_slots)?;
+        let file = config.file.as_deref().map(spawn_bar_file).transpose()?;
+        Ok(Self { specs, contract_types, current: vec![None; nb_slots], slots, file, last_trade_ms: vec![0; nb_instruments] })
+    }
+
+    fn publish(&mut self, slot: usize, bar: &Bar) {
+        self.slots.write(slot, bar);
+        if let Some(file) = &self.file {
+            let nb_specs = self.specs.len();
+            if file.try_send((slot / nb_specs, self.specs[slot % nb_specs], *bar)).is_err() {
+                error!("bars: file queue full, bar {} of book {} not written", self.specs[slot % nb_specs], slot / nb_specs);
+            }
+        }
+    }
+
+    #[inline]
+    pub fn on_trade(&mut self, trade: &TradeEvent) {
+        let instrument_idx = trade.instrument_idx as usize;
+        let (Some(last_trade_ms), Some(&contract)) = (self.last_trade_ms.get_mut(instrument_idx), self.contract_types.get(instrument_idx)) else {
+            return;
+        };
+        *last_trade_ms = (*last_trade_ms).max(trade.timestamp_ns);
+        let first_slot = instrument_idx * self.specs.len();
+        for spec_idx in 0..self.specs.len() {
+            let slot = first_slot + spec_idx;
+            if let Some(bar) = self.specs[spec_idx].on_trade(&mut self.current[slot], trade, contract) {
+                self.publish(slot, &bar);
+            }
+        }
+    }
+
+    // Each instrument on its own exchange clock, the latest of its book updates (`last_updates`, by
+    // instrument index) and of its trades: a lagging instrument does not see its bars closed by another one
+    pub fn on_clock(&mut self, last_updates: &[u64]) {
+        let nb_specs = self.specs.len();
+        for slot in 0..self.current.len() {
+            let instrument_idx = slot / nb_specs;
+            let now_ms = last_updates.get(instrument_idx).copied().unwrap_or(0).max(self.last_trade_ms[instrument_idx]);
+            if let Some(bar) = self.specs[slot % nb_specs].on_clock(&mut self.current[slot], now_ms) {
+                self.publish(slot, &bar);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trade(price: f32, size: f32, side: u8, timestamp: u64) -> TradeEvent {
+        TradeEvent { instrument_idx: 0, price, size, side, timestamp_ns: timestamp, trade_id: 0, padding: [0; 6] }
+    }
+
+    #[test]
+    fn test_bars() {
+        let time = BarSpec::Time { interval_ms: 1_000 };
+        let mut current = None;
+        assert_eq!(time.on_trade(&mut current, &trade(100.0, 1.0, 1, 5_100), ContractType::Linear), None);
+        assert_eq!(time.on_trade(&mut current, &trade(102.0, 1.0, 0, 5_500), ContractType::Linear), None);
+        assert_eq!(time.on_trade(&mut current, &trade(99.0, 2.0, 1, 5_900), ContractType::Linear), None);
+        let bar = time.on_trade(&mut current, &trade(101.0, 1.0, 1, 6_000), ContractType::Linear).unwrap();
+        assert_eq!((bar.start_ms, bar.end_ms), (5_000, 6_000));
+        assert_eq!((bar.open, bar.high, bar.low, bar.close), (100.0, 102.0, 99.0, 99.0));
+        assert_eq!((bar.volume, bar.buy_volume, bar.sell_volume, bar.trade_count), (4.0, 3.0, 1.0, 3));
+        assert_eq!(bar.vwap, 400.0 / 4.0);
+        assert_eq!(time.on_clock(&mut current, 6_999), None);
+        assert_eq!(time.on_clock(&mut current, 7_000).map(|bar| bar.trade_count), Some(1));
+
+        let volume = BarSpec::Volume { threshold: 3.0 };
+        let mut current = None;
+        assert_eq!(volume.on_trade(&mut current, &trade(100.0, 2.0, 1, 1), ContractType::Linear), None);
+        let bar = volume.on_trade(&mut current, &trade(101.0, 2.0, 0, 2), ContractType::Linear).unwrap();
+        assert_eq!((bar.start_ms, bar.end_ms, bar.volume, bar.close), (1, 2, 4.0, 101.0));
+        assert!(current.is_none());
+
+        let config: BarsConfig = serde_json::from_str(r#"{"dollar": [1000000]}"#).unwrap();
+        assert_eq!(config.specs().len(), 3);
+        assert_eq!(config.specs()[2].to_string(), "dollar_1000000");
+    }
+
+    #[test]
+    fn test_inverse_bars() {
+        // sizes of inverse instruments are USD, the vwap weights the prices with the BTC traded
+        let dollar = BarSpec::Dollar { threshold: 300.0 };
+        let mut current = None;
+        assert_eq!(dollar.on_trade(&mut current, &trade(100.0, 100.0, 1, 1), ContractType::Inverse), None);
+        let bar = dollar.on_trade(&mut current, &trade(200.0, 200.0, 1, 2), ContractType::Inverse).unwrap();
+        assert_eq!((bar.volume, bar.dollar_volume, bar.base_volume), (300.0, 300.0, 2.0));
+        assert_eq!(bar.vwap, 150.0);
+
+        // linear would have been 100 * 100 + 200 * 200 dollars over 300 coins
+        let mut current = None;
+        assert_eq!(BarSpec::Volume { threshold: 300.0 }.on_trade(&mut current, &trade(100.0, 100.0, 1, 1), ContractType::Linear), None);
+        let bar = BarSpec::Volume { threshold: 300.0 }.on_trade(&mut current, &trade(200.0, 200.0, 1, 2), ContractType::Linear).unwrap();
+        assert_eq!((bar.dollar_volume, bar.base_volume), (50_000.0, 300.0));
+    }
+}
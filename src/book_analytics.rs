@@ -0,0 +1,178 @@
+use serde::Deserialize;
+use crate::orderbook_management::OrderbookManagerV2;
+use crate::price::ContractType;
+use crate::shm_slots::ShmSlots;
+
+// Record of the analytics slot, one per instrument index. Prices are in the instrument quote,
+// depth_notional is in USD for inverse contracts (sizes already in USD) and in quote * size otherwise.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[repr(C)]
+pub struct BookAnalytics {
+    pub timestamp: u64,
+    pub change_id: u64,
+    pub mid: f64,
+    // touch prices weighted by the size on the other side
+    pub microprice: f64,
+    // same as the microprice with the size weighted average price and total size of the top N levels of each side
+    pub weighted_mid: f64,
+    pub spread_ticks: i64,
+    // (bid size - ask size) / (bid size + ask size) over the top N levels, in [-1, 1]
+    pub imbalance: f64,
+    // ticks from the touch needed to accumulate depth_notional, -1 when the kept book is not deep enough
+    pub bid_ticks_to_notional: i64,
+    pub ask_ticks_to_notional: i64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AnalyticsConfig {
+    // N of weighted_mid and imbalance
+    #[serde(default = "default_levels")]
+    pub levels: usize,
+    // X of bid/ask_ticks_to_notional
+    pub depth_notional: f64,
+    #[serde(default = "default_shm_name")]
+    pub shm_name: String,
+}
+
+fn default_levels() -> usize {
+    5
+}
+
+fn default_shm_name() -> String {
+    "haiku_fh_analytics".to_string()
+}
+
+// Sum of size and of price * size over the first `levels` levels
+#[inline]
+fn side_totals(levels: impl Iterator<Item = (i64, f32)>, nb_levels: usize, to_price: impl Fn(i64) -> f64) -> (f64, f64) {
+    levels
+        .take(nb_levels)
+        .fold((0.0, 0.0), |(size, notional), (ticks, s)| (size + s as f64, notional + to_price(ticks) * s as f64))
+}
+
+#[inline]
+fn ticks_to_notional(mut levels: impl Iterator<Item = (i64, f32)>, target: f64, notional_of: impl Fn(i64, f32) -> f64) -> i64 {
+    let Some((touch, size)) = levels.next() else {
+        return -1;
+    };
+    let mut notional = notional_of(touch, size);
+    if notional >= target {
+        return 0;
+    }
+    for (ticks, size) in levels {
+        notional += notional_of(ticks, size);
+        if notional >= target {
+            return (ticks - touch).abs();
+        }
+    }
+    -1
+}
+
+impl BookAnalytics {
+    // None while one side is empty
+    pub fn compute(book: &OrderbookManagerV2, contract: ContractType, config: &AnalyticsConfig, timestamp: u64) -> Option<Self> {
+        let tick_size = book.tick_size();
+        let to_price = |ticks: i64| tick_size.to_price(ticks);
+        let notional_of = |ticks: i64, size: f32| contract.notional(to_price(ticks), size as f64);
+        let (bid_ticks, bid_size) = book.bid_levels().next()?;
+        let (ask_ticks, ask_size) = book.ask_levels().next()?;
+        let (bid, ask) = (to_price(bid_ticks), to_price(ask_ticks));
+        let (bid_size, ask_size) = (bid_size as f64, ask_size as f64);
+
+        let (bid_volume, bid_notional) = side_totals(book.bid_levels(), config.levels, to_price);
+        let (ask_volume, ask_notional) = side_totals(book.ask_levels(), config.levels, to_price);
+        let (bid_vwap, ask_vwap) = (bid_notional / bid_volume, ask_notional / ask_volume);
+
+        Some(Self {
+            timestamp,
+            change_id: book.last_change_id(),
+            mid: (bid + ask) / 2.0,
+            microprice: (bid * ask_size + ask * bid_size) / (bid_size + ask_size),
+            weighted_mid: (bid_vwap * ask_volume + ask_vwap * bid_volume) / (bid_volume + ask_volume),
+            spread_ticks: ask_ticks - bid_ticks,
+            imbalance: (bid_volume - ask_volume) / (bid_volume + ask_volume),
+            bid_ticks_to_notional: ticks_to_notional(book.bid_levels(), config.depth_notional, notional_of),
+            ask_ticks_to_notional: ticks_to_notional(book.ask_levels(), config.depth_notional, notional_of),
+        })
+    }
+}
+
+// Computes the analytics of a book after each update and writes them to its slot
+pub struct AnalyticsPublisher {
+    config: AnalyticsConfig,
+    // by instrument index
+    contract_types: Vec<ContractType>,
+    slots: ShmSlots<BookAnalytics>,
+}
+
+impl AnalyticsPublisher {
+    pub fn new(config: AnalyticsConfig, contract_types: Vec<ContractType>) -> std::io::Result<Self> {
+        let slots = ShmSlots::create(&config.shm_name, contract_types.len())?;
+        Ok(Self { config, contract_types, slots })
+    }
+
+    #[inline]
+    pub fn publish(&mut self, instrument_idx: usize, book: &OrderbookManagerV2, timestamp: u64) {
+        let contract = self.contract_types.get(instrument_idx).copied().unwrap_or_default();
+        if let Some(analytics) = BookAnalytics::compute(book, contract, &self.config, timestamp) {
+            self.slots.write(instrument_idx, &analytics);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::parsing::parsing_fast_orderbook::OrderbookUpdateDataRaw;
+    use crate::parsing::parsing_orderbook::{OrderbookAction, OrderbookLevel};
+    use crate::price::{Decimal, TickSize};
+
+    fn snapshot_book(bids: &[(f64, f32)], asks: &[(f64, f32)]) -> OrderbookManagerV2 {
+        let level = |&(price, size): &(f64, f32)| OrderbookLevel {
+            action: OrderbookAction::New,
+            price: Decimal::from_f64(price).unwrap(),
+            size,
+        };
+        let mut snapshot = OrderbookUpdateDataRaw::new();
+        bids.iter().map(level).for_each(|l| snapshot.add_bid(l));
+        asks.iter().map(level).for_each(|l| snapshot.add_ask(l));
+        let mut book = OrderbookManagerV2::new(10, TickSize::new(0.5).unwrap());
+        book.apply_snapshot(snapshot, 1, Instant::now()).unwrap();
+        book
+    }
+
+    #[test]
+    fn test_compute() {
+        let config = AnalyticsConfig { levels: 2, depth_notional: 250.0, shm_name: String::new() };
+        let book = snapshot_book(&[(100.0, 3.0), (99.5, 1.0), (99.0, 10.0)], &[(101.0, 1.0), (101.5, 1.0)]);
+        let analytics = BookAnalytics::compute(&book, ContractType::Linear, &config, 42).unwrap();
+
+        assert_eq!(analytics.mid, 100.5);
+        assert_eq!(analytics.microprice, (100.0 * 1.0 + 101.0 * 3.0) / 4.0);
+        assert_eq!(analytics.spread_ticks, 2);
+        assert_eq!(analytics.imbalance, (4.0 - 2.0) / 6.0);
+        let (bid_vwap, ask_vwap) = ((300.0 + 99.5) / 4.0, (101.0 + 101.5) / 2.0);
+        assert_eq!(analytics.weighted_mid, (bid_vwap * 2.0 + ask_vwap * 4.0) / 6.0);
+        // 300 on the bid touch, 202.5 on the two ask levels
+        assert_eq!(analytics.bid_ticks_to_notional, 0);
+        assert_eq!(analytics.ask_ticks_to_notional, -1);
+
+        assert!(BookAnalytics::compute(&snapshot_book(&[(100.0, 1.0)], &[]), ContractType::Linear, &config, 0).is_none());
+    }
+
+    #[test]
+    fn test_compute_inverse() {
+        // BTC-PERPETUAL like: sizes are USD contracts, the notional is the size whatever the price
+        let config = AnalyticsConfig { levels: 2, depth_notional: 250.0, shm_name: String::new() };
+        let book = snapshot_book(&[(100.0, 200.0), (99.5, 100.0)], &[(101.0, 300.0)]);
+        let analytics = BookAnalytics::compute(&book, ContractType::Inverse, &config, 42).unwrap();
+
+        assert_eq!(analytics.bid_ticks_to_notional, 1);
+        assert_eq!(analytics.ask_ticks_to_notional, 0);
+        // linear would have reached 250 on the bid touch
+        let linear = BookAnalytics::compute(&book, ContractType::Linear, &config, 42).unwrap();
+        assert_eq!(linear.bid_ticks_to_notional, 0);
+    }
+}
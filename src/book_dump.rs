@@ -0,0 +1,150 @@
+use serde::Serialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use crate::orderbook_management::{OrderbookManagerV2, ViolationCounts};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BookDumpFormat {
+    Text,
+    Json,
+}
+
+impl BookDumpFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            BookDumpFormat::Text => "txt",
+            BookDumpFormat::Json => "json",
+        }
+    }
+}
+
+// Internal state of one book, copied out of the writer so formatting and IO happen on the control side
+#[derive(Debug, Clone, Serialize)]
+pub struct BookDump {
+    pub instrument_idx: usize,
+    // filled by the control side, the writer only knows indexes
+    pub instrument: String,
+    pub tick_size: f64,
+    pub depth: usize,
+    pub full_depth: bool,
+    pub initialized: bool,
+    pub last_change_id: u64,
+    pub snapshot_count: u64,
+    pub update_count: u64,
+    // exchange timestamp (ms) of the last snapshot or delta applied, 0 if none
+    pub last_update_ms: u64,
+    pub violations: ViolationCounts,
+    // every level kept, (price, size) from the touch outwards
+    pub bids: Vec<(f64, f32)>,
+    pub asks: Vec<(f64, f32)>,
+}
+
+impl BookDump {
+    pub fn from_book(instrument_idx: usize, book: &OrderbookManagerV2, last_update_ms: u64) -> Self {
+        let tick_size = book.tick_size();
+        let levels = |levels: &mut dyn Iterator<Item = (i64, f32)>| -> Vec<(f64, f32)> {
+            levels.map(|(ticks, size)| (tick_size.to_price(ticks), size)).collect()
+        };
+        Self {
+            instrument_idx,
+            instrument: String::new(),
+            tick_size: tick_size.value(),
+            depth: book.depth(),
+            full_depth: book.is_full_depth(),
+            initialized: book.is_initialized(),
+            last_change_id: book.last_change_id(),
+            snapshot_count: book.snapshot_count(),
+            update_count: book.update_count(),
+            last_update_ms,
+            violations: book.violations(),
+            bids: levels(&mut book.bid_levels()),
+            asks: levels(&mut book.ask_levels()),
+        }
+    }
+}
+
+impl fmt::Display for BookDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} (idx {}) tick {} depth {}{}{}",
+            self.instrument,
+            self.instrument_idx,
+            self.tick_size,
+            self.depth,
+            if self.full_depth { " full depth" } else { "" },
+            if self.initialized { "" } else { " NOT INITIALIZED" },
+        )?;
+        writeln!(
+            f,
+            "  change_id {} snapshots {} updates {} last update {} ms",
+            self.last_change_id, self.snapshot_count, self.update_count, self.last_update_ms
+        )?;
+        writeln!(f, "  violations {:?}", self.violations)?;
+        writeln!(f, "  {:>16} {:>14} | {:<16} {:<14}", "bid", "size", "ask", "size")?;
+        for i in 0..self.bids.len().max(self.asks.len()) {
+            let side = |levels: &[(f64, f32)]| match levels.get(i) {
+                Some((price, size)) => (price.to_string(), size.to_string()),
+                None => (String::new(), String::new()),
+            };
+            let (bid, bid_size) = side(&self.bids);
+            let (ask, ask_size) = side(&self.asks);
+            writeln!(f, "  {:>16} {:>14} | {:<16} {:<14}", bid, bid_size, ask, ask_size)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn render(dumps: &[BookDump], format: BookDumpFormat) -> String {
+    match format {
+        BookDumpFormat::Text => dumps.iter().map(|dump| dump.to_string()).collect::<Vec<_>>().join("\n"),
+        BookDumpFormat::Json => serde_json::to_string_pretty(dumps).unwrap_or_else(|e| format!("{{\"error\": \"{}\"}}", e)),
+    }
+}
+
+// To <dir>/books_<unix ms>.<txt|json>, or stdout without a directory. Returns the file written.
+pub fn write_dump(dumps: &[BookDump], format: BookDumpFormat, dir: Option<&str>) -> std::io::Result<Option<PathBuf>> {
+    let output = render(dumps, format);
+    let Some(dir) = dir else {
+        println!("{}", output);
+        return Ok(None);
+    };
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    let path = Path::new(dir).join(format!("books_{}.{}", now_ms, format.extension()));
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(&path, output)?;
+    Ok(Some(path))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::parsing::parsing_fast_orderbook::OrderbookUpdateDataRaw;
+    use crate::parsing::parsing_orderbook::{OrderbookAction, OrderbookLevel};
+    use crate::price::{Decimal, TickSize};
+
+    #[test]
+    fn test_render() {
+        let mut book = OrderbookManagerV2::new(10, TickSize::new(0.5).unwrap());
+        let mut snapshot = OrderbookUpdateDataRaw::new();
+        snapshot.add_bid(OrderbookLevel { action: OrderbookAction::New, price: Decimal::from_f64(100.5).unwrap(), size: 2.0 });
+        book.apply_snapshot(snapshot, 7, Instant::now()).unwrap();
+
+        let mut dump = BookDump::from_book(3, &book, 1_700_000_000_000);
+        dump.instrument = "BTC-PERPETUAL".to_string();
+        assert_eq!(dump.bids, vec![(100.5, 2.0)]);
+        assert_eq!(dump.snapshot_count, 1);
+
+        let text = render(std::slice::from_ref(&dump), BookDumpFormat::Text);
+        assert!(text.starts_with("BTC-PERPETUAL (idx 3)"));
+        assert!(text.contains("change_id 7"));
+        let json: serde_json::Value = serde_json::from_str(&render(&[dump], BookDumpFormat::Json)).unwrap();
+        assert_eq!(json[0]["instrument"], "BTC-PERPETUAL");
+        assert_eq!(json[0]["bids"][0][0], 100.5);
+    }
+}
@@ -1,29 +1,566 @@
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use config::{Environment, File, FileFormat};
+use crate::bars::BarsConfig;
+use crate::book_analytics::AnalyticsConfig;
+use crate::deribit_helper::AuthMethod;
+use crate::health::HealthConfig;
+use crate::order_flow::{MAX_WINDOWS, OrderFlowConfig};
+use crate::journal::JournalConfig;
+use crate::sinks::{SinkConfig, default_sinks};
+use crate::trade_checks::TradeCheckConfig;
+use crate::orderbook_management::BookSettings;
 
-#[derive(Deserialize, Debug)]
-pub struct DeribitConfig {
-    pub url: String,
-    pub key: String,
-    pub secret: String,
-    pub channels: Vec<String>,
+// HAIKU_FH_URL, HAIKU_FH_SECRET, HAIKU_FH_CHANNELS and HAIKU_FH_SUBACCOUNT_IDS (comma separated), ...
+const ENV_PREFIX: &str = "HAIKU_FH";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("environment variable {0} is not set")]
+    MissingEnv(String),
+    #[error("cannot read secret file {path}: {source}")]
+    SecretFile { path: String, source: std::io::Error },
+    #[error("secret file {path} is readable by group/others (mode {mode:o}), expected 600 or stricter")]
+    InsecurePermissions { path: String, mode: u32 },
+    #[error("secret command `{command}` failed: {reason}")]
+    SecretCommand { command: String, reason: String },
+    #[error("secret from {0} is empty")]
+    EmptySecret(String),
+    #[error("unsupported config format for {0}, expected .json, .yaml or .yml")]
+    UnsupportedFormat(String),
+    #[error("{0}")]
+    Load(#[from] config::ConfigError),
+    #[error("invalid url {0}, expected ws:// or wss://")]
+    InvalidUrl(String),
+    #[error("invalid channel {channel}: {reason}")]
+    InvalidChannel { channel: String, reason: String },
+    #[error("channel {channel} refers to {instrument} which is not in the SHM metadata")]
+    UnknownInstrument { channel: String, instrument: String },
+    #[error("invalid book depth {depth} for {instrument}, expected at least 1")]
+    InvalidDepth { instrument: String, depth: usize },
+    #[error("book_depth refers to {0} which is not in the SHM metadata")]
+    UnknownDepthInstrument(String),
+    #[error("invalid analytics settings: {0}")]
+    InvalidAnalytics(String),
+    #[error("invalid order_flow settings: {0}")]
+    InvalidOrderFlow(String),
+    #[error("invalid bars settings: {0}")]
+    InvalidBars(String),
+    #[error("log path {path} is not usable: {reason}")]
+    LogPath { path: String, reason: String },
+}
+
+// Output of each secret command already run, by command line: the config is loaded again on every
+// reload, which should not run the command again unless it changed
+static COMMAND_SECRETS: LazyLock<Mutex<HashMap<String, String>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Where a credential comes from, in the config file:
+//   "secret": "plain value"
+//   "secret": { "env": "DERIBIT_SECRET" }
+//   "secret": { "file": "/run/secrets/deribit" }
+//   "secret": { "command": "pass show deribit/secret" }
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum SecretSource {
+    Env { env: String },
+    File { file: String },
+    Command { command: String },
+    Plain(String),
+}
+
+impl SecretSource {
+    pub fn resolve(&self) -> Result<String, ConfigError> {
+        let (value, origin) = match self {
+            SecretSource::Plain(value) => (value.clone(), "config".to_string()),
+            SecretSource::Env { env } => (
+                std::env::var(env).map_err(|_| ConfigError::MissingEnv(env.clone()))?,
+                format!("env {}", env),
+            ),
+            SecretSource::File { file } => (Self::read_secret_file(file)?, format!("file {}", file)),
+            SecretSource::Command { command } => (Self::command_secret(command)?, format!("command `{}`", command)),
+        };
+        let value = value.trim().to_string();
+        if value.is_empty() {
+            return Err(ConfigError::EmptySecret(origin));
+        }
+        Ok(value)
+    }
+
+    fn read_secret_file(path: &str) -> Result<String, ConfigError> {
+        let metadata = std::fs::metadata(path)
+            .map_err(|source| ConfigError::SecretFile { path: path.to_string(), source })?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = metadata.permissions().mode() & 0o777;
+            if mode & 0o077 != 0 {
+                return Err(ConfigError::InsecurePermissions { path: path.to_string(), mode });
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = metadata;
+        std::fs::read_to_string(path)
+            .map_err(|source| ConfigError::SecretFile { path: path.to_string(), source })
+    }
+
+    fn command_secret(command: &str) -> Result<String, ConfigError> {
+        let mut secrets = COMMAND_SECRETS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(secret) = secrets.get(command) {
+            return Ok(secret.clone());
+        }
+        let secret = Self::run_secret_command(command)?;
+        secrets.insert(command.to_string(), secret.clone());
+        Ok(secret)
+    }
+
+    fn run_secret_command(command: &str) -> Result<String, ConfigError> {
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stderr(std::process::Stdio::inherit())
+            .output()
+            .map_err(|e| ConfigError::SecretCommand { command: command.to_string(), reason: e.to_string() })?;
+        if !output.status.success() {
+            return Err(ConfigError::SecretCommand {
+                command: command.to_string(),
+                reason: output.status.to_string(),
+            });
+        }
+        String::from_utf8(output.stdout).map_err(|_| ConfigError::SecretCommand {
+            command: command.to_string(),
+            reason: "stdout is not valid UTF-8".to_string(),
+        })
+    }
 }
 
-#[derive(Deserialize, Debug)]
+// A resolved credential, never printed
+#[derive(Deserialize, Clone)]
+#[serde(try_from = "SecretSource")]
+pub struct Secret(String);
+
+impl Secret {
+    #[inline]
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl TryFrom<SecretSource> for Secret {
+    type Error = ConfigError;
+
+    fn try_from(source: SecretSource) -> Result<Self, Self::Error> {
+        source.resolve().map(Secret)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+#[derive(Deserialize)]
 pub struct Config {
     pub url: String,
-    pub key: String,
-    pub secret: String,
+    pub key: Secret,
+    pub secret: Secret,
+    #[serde(default)]
+    pub auth_method: AuthMethod,
+    // the main session is exchanged for one scoped to the first subaccount, each other one gets
+    // its own connection for the user.* channels
+    #[serde(default)]
+    pub subaccount_ids: Vec<u64>,
     pub channels: Vec<String>,
+    // levels kept and published per book, e.g. 1, 10, 25, 50
+    #[serde(default = "default_book_depth")]
+    pub default_book_depth: usize,
+    // keep the entire book in memory for every instrument, see BookSettings
+    #[serde(default)]
+    pub full_depth_books: bool,
+    // per instrument exceptions to default_book_depth / full_depth_books
+    #[serde(default)]
+    pub book_depth: Vec<BookDepth>,
+    // books are written to SHM at most once per this many microseconds, 0 writes every update
+    #[serde(default)]
+    pub default_conflation_us: u64,
+    // SHM file of the per instrument "last changed" sequence, see BookChange
+    #[serde(default = "default_book_change_shm")]
+    pub book_change_shm: String,
+    // a book crossed or locked for longer than this is reset and resnapshotted
+    #[serde(default = "default_max_crossed_ms")]
+    pub max_crossed_ms: u64,
+    // mid, microprice, imbalance... of every book, written to their own SHM slots when set
+    #[serde(default)]
+    pub analytics: Option<AnalyticsConfig>,
+    // rolling order flow counters of every book, written to their own SHM slots when set
+    #[serde(default)]
+    pub order_flow: Option<OrderFlowConfig>,
+    // trades cross-checked against the books when set, see TradeChecker
+    #[serde(default)]
+    pub trade_checks: Option<TradeCheckConfig>,
+    // time, volume and dollar bars built from the trades, written to their own SHM slots when set
+    #[serde(default)]
+    pub bars: Option<BarsConfig>,
+    // where books and trades are published, the SHM segments of HaikuSHM by default
+    #[serde(default = "default_sinks")]
+    pub sinks: Vec<SinkConfig>,
+    // every websocket text frame is appended to a binary journal when set
+    #[serde(default)]
+    pub journal: Option<JournalConfig>,
     pub log_path: String,
+    // directory of the book dumps (SIGUSR1 text, SIGUSR2 JSON), stdout when not set
+    #[serde(default)]
+    pub book_dump_path: Option<String>,
+    // "ip:port" of the HTTP endpoint serving /metrics, /health and /ready, none when not set
+    #[serde(default)]
+    pub http_addr: Option<String>,
+    // thresholds of /health and /ready
+    #[serde(default)]
+    pub health: HealthConfig,
+    // can be given by --config-shm instead, which takes precedence
     pub meta_data_path: String,
 }
 
+// A list rather than a map: the config crate lowercases map keys, instrument names are case sensitive
+#[derive(Deserialize, Debug, Clone)]
+pub struct BookDepth {
+    pub instrument: String,
+    pub depth: usize,
+    #[serde(default)]
+    pub full_depth: Option<bool>,
+    #[serde(default)]
+    pub conflation_us: Option<u64>,
+}
+
+fn default_book_depth() -> usize {
+    10
+}
+
+fn default_max_crossed_ms() -> u64 {
+    500
+}
+
+fn default_book_change_shm() -> String {
+    "haiku_fh_book_changes".to_string()
+}
+
+// Command line values, they win over the environment which wins over the file
+#[derive(Debug, Default)]
+pub struct ConfigOverrides {
+    pub url: Option<String>,
+    pub channels: Option<Vec<String>>,
+    pub log_path: Option<String>,
+    pub meta_data_path: Option<String>,
+}
+
+// Written by hand so that adding a field cannot leak a credential by accident
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("url", &self.url)
+            .field("key", &"<redacted>")
+            .field("secret", &"<redacted>")
+            .field("auth_method", &self.auth_method)
+            .field("subaccount_ids", &self.subaccount_ids)
+            .field("channels", &self.channels)
+            .field("default_book_depth", &self.default_book_depth)
+            .field("full_depth_books", &self.full_depth_books)
+            .field("book_depth", &self.book_depth)
+            .field("default_conflation_us", &self.default_conflation_us)
+            .field("book_change_shm", &self.book_change_shm)
+            .field("max_crossed_ms", &self.max_crossed_ms)
+            .field("analytics", &self.analytics)
+            .field("order_flow", &self.order_flow)
+            .field("trade_checks", &self.trade_checks)
+            .field("bars", &self.bars)
+            .field("sinks", &self.sinks)
+            .field("journal", &self.journal)
+            .field("log_path", &self.log_path)
+            .field("book_dump_path", &self.book_dump_path)
+            .field("http_addr", &self.http_addr)
+            .field("health", &self.health)
+            .field("meta_data_path", &self.meta_data_path)
+            .finish()
+    }
+}
+
 impl Config {
 
-    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let content = std::fs::read_to_string(path)?;
-        let cfg_data = serde_json::from_str(&content)?;
+    pub fn load(path: &str, overrides: &ConfigOverrides) -> Result<Self, ConfigError> {
+        let format = match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("json") => FileFormat::Json,
+            Some("yaml") | Some("yml") => FileFormat::Yaml,
+            _ => return Err(ConfigError::UnsupportedFormat(path.to_string())),
+        };
+
+        let mut builder = config::Config::builder()
+            .add_source(File::new(path, format))
+            .add_source(
+                Environment::with_prefix(ENV_PREFIX)
+                    .prefix_separator("_")
+                    .separator("__"),
+            );
+
+        // lists are not split by the Environment source without type guessing, which would turn numeric secrets into integers
+        if let Ok(channels) = std::env::var(format!("{}_CHANNELS", ENV_PREFIX)) {
+            builder = builder.set_override("channels", split_list(&channels))?;
+        }
+        if let Ok(subaccount_ids) = std::env::var(format!("{}_SUBACCOUNT_IDS", ENV_PREFIX)) {
+            builder = builder.set_override("subaccount_ids", split_list(&subaccount_ids))?;
+        }
+
+        let cfg_data = builder
+            .set_override_option("url", overrides.url.clone())?
+            .set_override_option("channels", overrides.channels.clone())?
+            .set_override_option("log_path", overrides.log_path.clone())?
+            .set_override_option("meta_data_path", overrides.meta_data_path.clone())?
+            .build()?
+            .try_deserialize()?;
 
         Ok(cfg_data)
     }
+
+    // Checks what can only be checked once the SHM metadata is known
+    pub fn validate(&self, instrument_index: &HashMap<String, usize>) -> Result<(), ConfigError> {
+        if !(self.url.starts_with("wss://") || self.url.starts_with("ws://")) {
+            return Err(ConfigError::InvalidUrl(self.url.clone()));
+        }
+
+        for channel in &self.channels {
+            if let Some(instrument) = Self::check_channel(channel)? {
+                if !instrument_index.contains_key(instrument) {
+                    return Err(ConfigError::UnknownInstrument {
+                        channel: channel.clone(),
+                        instrument: instrument.to_string(),
+                    });
+                }
+            }
+        }
+
+        // a depth above SHM_BOOK_LEVELS is kept in the books (analytics, order flow, dumps), the SHM only gets its first levels
+        if self.default_book_depth == 0 {
+            return Err(ConfigError::InvalidDepth { instrument: "default".to_string(), depth: 0 });
+        }
+        for book_depth in &self.book_depth {
+            if book_depth.depth == 0 {
+                return Err(ConfigError::InvalidDepth {
+                    instrument: book_depth.instrument.clone(),
+                    depth: book_depth.depth,
+                });
+            }
+            if !instrument_index.contains_key(&book_depth.instrument) {
+                return Err(ConfigError::UnknownDepthInstrument(book_depth.instrument.clone()));
+            }
+        }
+
+        if let Some(analytics) = &self.analytics {
+            if analytics.levels == 0 {
+                return Err(ConfigError::InvalidAnalytics("levels must be at least 1".to_string()));
+            }
+            if !analytics.depth_notional.is_finite() || analytics.depth_notional <= 0.0 {
+                return Err(ConfigError::InvalidAnalytics("depth_notional must be a positive number".to_string()));
+            }
+        }
+
+        if let Some(order_flow) = &self.order_flow {
+            let invalid = |reason: String| Err(ConfigError::InvalidOrderFlow(reason));
+            if order_flow.bucket_ms == 0 {
+                return invalid("bucket_ms must be at least 1".to_string());
+            }
+            if order_flow.windows_ms.is_empty() || order_flow.windows_ms.len() > MAX_WINDOWS {
+                return invalid(format!("expected 1 to {} windows", MAX_WINDOWS));
+            }
+            if let Some(window) = order_flow.windows_ms.iter().find(|&&w| w == 0 || w % order_flow.bucket_ms != 0) {
+                return invalid(format!("window {} is not a positive multiple of bucket_ms {}", window, order_flow.bucket_ms));
+            }
+        }
+
+        if let Some(bars) = &self.bars {
+            let invalid = |reason: String| Err(ConfigError::InvalidBars(reason));
+            if bars.specs().is_empty() {
+                return invalid("no bar configured".to_string());
+            }
+            if bars.time_ms.contains(&0) {
+                return invalid("time_ms must be at least 1".to_string());
+            }
+            if let Some(threshold) = bars.volume.iter().chain(&bars.dollar).find(|t| !t.is_finite() || **t <= 0.0) {
+                return invalid(format!("threshold {} is not a positive number", threshold));
+            }
+        }
+
+        Self::check_log_path(&self.log_path)
+    }
+
+    pub fn book_settings_for(&self, instrument: &str) -> BookSettings {
+        match self.book_depth.iter().find(|d| d.instrument == instrument) {
+            Some(d) => BookSettings {
+                depth: d.depth,
+                full_depth: d.full_depth.unwrap_or(self.full_depth_books),
+                conflation: Duration::from_micros(d.conflation_us.unwrap_or(self.default_conflation_us)),
+            },
+            None => self.default_book_settings(),
+        }
+    }
+
+    fn default_book_settings(&self) -> BookSettings {
+        BookSettings {
+            depth: self.default_book_depth,
+            full_depth: self.full_depth_books,
+            conflation: Duration::from_micros(self.default_conflation_us),
+        }
+    }
+
+    // Book settings indexed by the SHM instrument index
+    pub fn book_settings(&self, instrument_index: &HashMap<String, usize>, nb_instruments: usize) -> Vec<BookSettings> {
+        let mut settings = vec![self.default_book_settings(); nb_instruments];
+        for (instrument, &idx) in instrument_index {
+            if idx < nb_instruments {
+                settings[idx] = self.book_settings_for(instrument);
+            }
+        }
+        settings
+    }
+
+    // Returns the instrument of market data channels, which must have a SHM slot
+    fn check_channel(channel: &str) -> Result<Option<&str>, ConfigError> {
+        let invalid = |reason: &str| ConfigError::InvalidChannel {
+            channel: channel.to_string(),
+            reason: reason.to_string(),
+        };
+        let parts: Vec<&str> = channel.split('.').collect();
+        if parts.iter().any(|p| p.is_empty() || !p.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')) {
+            return Err(invalid("unexpected character or empty part"));
+        }
+
+        match parts.as_slice() {
+            ["book" | "trades", instrument, "raw"] => Ok(Some(*instrument)),
+            ["book" | "trades", _, _] => Err(invalid("only raw market data channels are supported")),
+            ["user", "orders" | "trades", _, "raw" | "100ms" | "agg2"] => Ok(None),
+            ["user", "orders" | "trades", _, _, "raw" | "100ms" | "agg2"] => Ok(None),
+            _ => Err(invalid("expected book.<instrument>.raw, trades.<instrument>.raw or user.orders|trades.<...>.<interval>")),
+        }
+    }
+
+    fn check_log_path(path: &str) -> Result<(), ConfigError> {
+        let error = |reason: String| ConfigError::LogPath { path: path.to_string(), reason };
+        std::fs::create_dir_all(path).map_err(|e| error(e.to_string()))?;
+        let probe = Path::new(path).join(".haiku_fh_write_test");
+        std::fs::write(&probe, b"").map_err(|e| error(e.to_string()))?;
+        let _ = std::fs::remove_file(&probe);
+        Ok(())
+    }
+}
+
+pub fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_debug_redacts_secrets() {
+        let json_data = r#"{"url":"wss://test.deribit.com/ws/api/v2","key":"my_key","secret":{"command":"echo my_secret"},
+            "channels":["book.BTC-PERPETUAL.raw"],"log_path":"/tmp/","meta_data_path":"/tmp/meta.json"}"#;
+        let cfg: Config = serde_json::from_str(json_data).unwrap();
+        assert_eq!(cfg.secret.expose(), "my_secret");
+
+        let printed = format!("{:?} {:?}", cfg, cfg.key);
+        assert!(!printed.contains("my_key"));
+        assert!(!printed.contains("my_secret"));
+    }
+
+    #[test]
+    fn test_channel_validation() {
+        assert_eq!(Config::check_channel("book.BTC-PERPETUAL.raw").unwrap(), Some("BTC-PERPETUAL"));
+        assert_eq!(Config::check_channel("trades.ETH_USDC-PERPETUAL.raw").unwrap(), Some("ETH_USDC-PERPETUAL"));
+        assert_eq!(Config::check_channel("user.orders.future.BTC.raw").unwrap(), None);
+        assert!(Config::check_channel("book.BTC-PERPETUAL.100ms").is_err());
+        assert!(Config::check_channel("book..raw").is_err());
+        assert!(Config::check_channel("ticker.BTC-PERPETUAL.raw").is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_secret_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("haiku_fh_secret_{}", std::process::id()));
+        std::fs::write(&path, "file_secret\n").unwrap();
+        let source = SecretSource::File { file: path.to_string_lossy().to_string() };
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(source.resolve(), Err(ConfigError::InsecurePermissions { .. })));
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(source.resolve().unwrap(), "file_secret");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_secret_command_runs_once() {
+        let runs = std::env::temp_dir().join(format!("haiku_fh_secret_runs_{}", std::process::id()));
+        let command = format!("echo run >> {}; echo command_secret", runs.display());
+        let source = SecretSource::Command { command };
+
+        assert_eq!(source.resolve().unwrap(), "command_secret");
+        assert_eq!(source.resolve().unwrap(), "command_secret");
+        assert_eq!(std::fs::read_to_string(&runs).unwrap().lines().count(), 1);
+        std::fs::remove_file(&runs).unwrap();
+    }
+
+    #[test]
+    fn test_depth_validation() {
+        let json_data = r#"{"url":"wss://test.deribit.com/ws/api/v2","key":"my_key","secret":"my_secret",
+            "channels":["book.BTC-PERPETUAL.raw"],"book_depth":[{"instrument":"BTC-PERPETUAL","depth":25}],
+            "log_path":"/tmp/","meta_data_path":"/tmp/meta.json"}"#;
+        let mut cfg: Config = serde_json::from_str(json_data).unwrap();
+        let instrument_index = HashMap::from([("BTC-PERPETUAL".to_string(), 0)]);
+        assert!(cfg.validate(&instrument_index).is_ok());
+
+        cfg.book_depth[0].depth = 0;
+        assert!(matches!(cfg.validate(&instrument_index), Err(ConfigError::InvalidDepth { depth: 0, .. })));
+    }
+
+    #[test]
+    fn test_file_env_cli_precedence() {
+        let path = std::env::temp_dir().join(format!("haiku_fh_config_{}.yaml", std::process::id()));
+        std::fs::write(
+            &path,
+            "url: wss://file.deribit.com/ws/api/v2\nkey: my_key\nsecret: my_secret\nchannels: [book.BTC-PERPETUAL.raw]\n\
+             log_path: /tmp/file/\nmeta_data_path: /tmp/file_meta.json\n",
+        )
+        .unwrap();
+        let path = path.to_string_lossy().to_string();
+        // the only test reading HAIKU_FH_ variables
+        unsafe { std::env::set_var("HAIKU_FH_LOG_PATH", "/tmp/env/") };
+
+        // the environment wins over the file
+        let cfg = Config::load(&path, &ConfigOverrides::default()).unwrap();
+        assert_eq!(cfg.url, "wss://file.deribit.com/ws/api/v2");
+        assert_eq!(cfg.log_path, "/tmp/env/");
+        assert_eq!(cfg.meta_data_path, "/tmp/file_meta.json");
+
+        // the command line wins over both
+        let overrides = ConfigOverrides { log_path: Some("/tmp/cli/".to_string()), ..Default::default() };
+        let cfg = Config::load(&path, &overrides).unwrap();
+        assert_eq!(cfg.url, "wss://file.deribit.com/ws/api/v2");
+        assert_eq!(cfg.log_path, "/tmp/cli/");
+        assert_eq!(cfg.meta_data_path, "/tmp/file_meta.json");
+
+        unsafe { std::env::remove_var("HAIKU_FH_LOG_PATH") };
+        std::fs::remove_file(&path).unwrap();
+    }
 }
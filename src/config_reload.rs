@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+use crate::config_global::{Config, ConfigError, ConfigOverrides};
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ChannelDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl ChannelDiff {
+    pub fn between(current: &[String], wanted: &[String]) -> Self {
+        Self {
+            added: wanted.iter().filter(|c| !current.contains(*c)).cloned().collect(),
+            removed: current.iter().filter(|c| !wanted.contains(*c)).cloned().collect(),
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+
+    // Instruments whose book channel goes away, their books have to be rebuilt if they come back
+    pub fn removed_books(&self) -> impl Iterator<Item = &str> {
+        self.removed.iter().filter_map(|c| book_instrument(c))
+    }
+}
+
+// book.BTC-PERPETUAL.raw or book.BTC-PERPETUAL.100ms -> BTC-PERPETUAL
+pub fn book_instrument(channel: &str) -> Option<&str> {
+    channel.strip_prefix("book.").and_then(|c| c.split('.').next())
+}
+
+// Reloads the config file when it is modified (polled) or on demand (SIGHUP)
+pub struct ConfigWatcher {
+    path: String,
+    overrides: ConfigOverrides,
+    last_modified: Option<SystemTime>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &str, overrides: ConfigOverrides) -> Self {
+        let last_modified = Self::modified(path);
+        Self { path: path.to_string(), overrides, last_modified }
+    }
+
+    fn modified(path: &str) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    pub fn has_changed(&mut self) -> bool {
+        let modified = Self::modified(&self.path);
+        if modified.is_some() && modified != self.last_modified {
+            self.last_modified = modified;
+            return true;
+        }
+        false
+    }
+
+    // Same layering and validation as at startup, a broken file leaves the running config in place
+    pub fn reload(&self, instrument_index: &HashMap<String, usize>) -> Result<Config, ConfigError> {
+        let cfg = Config::load(&self.path, &self.overrides)?;
+        cfg.validate(instrument_index)?;
+        Ok(cfg)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_diff() {
+        let current = vec!["book.BTC-PERPETUAL.raw".to_string(), "trades.BTC-PERPETUAL.raw".to_string()];
+        let wanted = vec!["trades.BTC-PERPETUAL.raw".to_string(), "book.ETH-PERPETUAL.raw".to_string()];
+        let diff = ChannelDiff::between(&current, &wanted);
+
+        assert_eq!(diff.added, vec!["book.ETH-PERPETUAL.raw"]);
+        assert_eq!(diff.removed, vec!["book.BTC-PERPETUAL.raw"]);
+        assert_eq!(diff.removed_books().collect::<Vec<_>>(), vec!["BTC-PERPETUAL"]);
+        assert!(ChannelDiff::between(&current, &current).is_empty());
+    }
+}
@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+// Record of the book change slot, one per instrument index, written each time the book is written to SHM.
+// The slot sequence (ShmSlots::version) is the "last changed" counter: a reader compares it with the
+// one it saw last instead of reading the book.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[repr(C)]
+pub struct BookChange {
+    pub timestamp: u64,
+    pub change_id: u64,
+}
+
+// What a SHM book write needs besides the levels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DuePublish {
+    pub timestamp: u64,
+    pub flag: u8,
+}
+
+// Publishes a book at most once per interval: the first update after a quiet period goes out
+// right away, the following ones are merged and the latest state is written when the interval is over.
+#[derive(Debug)]
+pub struct Conflator {
+    interval: Duration,
+    last_published: Option<Instant>,
+    pending: Option<DuePublish>,
+}
+
+impl Conflator {
+    pub fn new(interval: Duration) -> Self {
+        Self { interval, last_published: None, pending: None }
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        !self.interval.is_zero()
+    }
+
+    // For each applied update, Some when the book has to be written now
+    #[inline]
+    pub fn on_update(&mut self, now: Instant, timestamp: u64, flag: u8) -> Option<DuePublish> {
+        if !self.is_enabled() {
+            return Some(DuePublish { timestamp, flag });
+        }
+        // the sides touched since the last write
+        let flag = flag | self.pending.map_or(0, |pending| pending.flag);
+        self.pending = Some(DuePublish { timestamp, flag });
+        self.poll(now)
+    }
+
+    // The merged updates once the interval since the last write is over
+    #[inline]
+    pub fn poll(&mut self, now: Instant) -> Option<DuePublish> {
+        match self.last_published {
+            Some(last) if now.duration_since(last) < self.interval => None,
+            _ => {
+                let due = self.pending.take()?;
+                self.last_published = Some(now);
+                Some(due)
+            }
+        }
+    }
+
+    // Whatever is still merged, regardless of the interval
+    #[inline]
+    pub fn flush(&mut self) -> Option<DuePublish> {
+        self.pending.take()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conflation() {
+        let start = Instant::now();
+        let mut conflator = Conflator::new(Duration::from_micros(500));
+        assert_eq!(conflator.on_update(start, 1, 0b01), Some(DuePublish { timestamp: 1, flag: 0b01 }));
+
+        let soon = start + Duration::from_micros(100);
+        assert_eq!(conflator.on_update(soon, 2, 0b10), None);
+        assert_eq!(conflator.on_update(soon, 3, 0b10), None);
+        assert_eq!(conflator.poll(soon), None);
+
+        let later = start + Duration::from_micros(600);
+        assert_eq!(conflator.poll(later), Some(DuePublish { timestamp: 3, flag: 0b10 }));
+        assert_eq!(conflator.poll(later + Duration::from_millis(1)), None);
+        assert_eq!(conflator.on_update(later, 4, 0b01), None);
+        assert_eq!(conflator.flush(), Some(DuePublish { timestamp: 4, flag: 0b01 }));
+
+        let mut every_update = Conflator::new(Duration::ZERO);
+        assert!(every_update.on_update(start, 1, 0).is_some());
+        assert!(every_update.on_update(start, 2, 0).is_some());
+    }
+}
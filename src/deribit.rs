@@ -1,14 +1,23 @@
-use crate::deribit_helper::{AuthResult, DeribitError, SubscriptionResult};
-use crate::parsing::MessageParser;
+use crate::deribit_helper::{AuthResult, DeribitError, SubscriptionResult, auth_nonce, client_signature, request_id};
+use crate::journal::{JournalHandle, now_ns};
+use crate::health::HEALTH;
+use crate::metrics::{METRICS, Queue};
+use crate::parsing::{MessageParser, ParseError};
 use crate::parsing::exchange_message_type::DeribitMessage;
+use crate::parsing::parsing_admin::InstrumentInfo;
 use crate::parsing::parsing_fast::{FastMarketData, StreamingParser};
-use crate::parsing::parsing_fast_orderbook::OrderbookResult;
+use crate::parsing::parsing_fast_orderbook::{OrderbookResult, OrderbookUpdateDataRaw};
+use crate::parsing::parsing_orderbook::{OrderbookLevel, OrderbookRecoveryMessage};
+use crate::parsing::parsing_user::{UserOrder, UserPosition, UserTrade};
+use crate::price::Decimal;
 use futures::{SinkExt, StreamExt};
 use haiku_common::latency_tracker::LatencyTracker;
 use haiku_common::monitoring::message_monitor::WebsocketMessageMonitor;
 use haiku_common::shm_accessor::market_data_type::TradeEvent;
 use serde_json::{Value, json};
+use smallvec::SmallVec;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
 use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio::task::JoinHandle;
 use tokio::time::{Duration, Instant};
@@ -25,9 +34,26 @@ pub enum ControlMessage {
         id: u64,
         result: Result<SubscriptionResult, DeribitError>,
     },
+    UserOrders(Vec<UserOrder>),
+    UserTrades(Vec<UserTrade>),
+    OpenOrders {
+        id: u64,
+        orders: Vec<UserOrder>,
+    },
+    Positions {
+        id: u64,
+        positions: Vec<UserPosition>,
+    },
+    Instruments {
+        id: u64,
+        instruments: Vec<InstrumentInfo>,
+    },
     Error(DeribitError),
 }
 
+// identifies the connection of a frame in the journal
+static NEXT_CONNECTION_ID: AtomicU32 = AtomicU32::new(1);
+
 #[derive(Debug)]
 struct ClientCommand {
     msg: String,
@@ -57,7 +83,25 @@ impl DeribitConnection {
     pub async fn connect(
         url: &str,
         instrument_map: HashMap<String, usize>,
+        snapshot_depths: Vec<usize>,
         shutdown_tx: broadcast::Sender<()>,
+        journal: Option<JournalHandle>,
+    ) -> Result<Self, DeribitError> {
+        Self::open(url, instrument_map, snapshot_depths, shutdown_tx, journal, true).await
+    }
+
+    // A connection for the user.* channels of a subaccount: no books, no journal, not tracked by /health
+    pub async fn connect_private(url: &str, shutdown_tx: broadcast::Sender<()>) -> Result<Self, DeribitError> {
+        Self::open(url, HashMap::new(), Vec::new(), shutdown_tx, None, false).await
+    }
+
+    async fn open(
+        url: &str,
+        instrument_map: HashMap<String, usize>,
+        snapshot_depths: Vec<usize>,
+        shutdown_tx: broadcast::Sender<()>,
+        journal: Option<JournalHandle>,
+        market_data: bool,
     ) -> Result<Self, DeribitError> {
         let (ws_stream, _) = connect_async(url)
             .await
@@ -72,12 +116,19 @@ impl DeribitConnection {
         let (fast_orderbook_tx, fast_orderbook_rx) = mpsc::channel(1000);
 
         let mut task_handles = Vec::new();
-        let streaming_parser = StreamingParser::new(instrument_map);
+        let streaming_parser = StreamingParser::new(instrument_map).with_snapshot_depths(snapshot_depths);
 
         let parsed_tx_clone = parsed_tx.clone();
+        // get_order_book replies reach the writer like subscription snapshots
+        let recovery_tx = fast_orderbook_tx.clone();
         let mut shutdown_rx_ws = shutdown_tx.subscribe();
+        let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        if market_data {
+            METRICS.connections.fetch_add(1, Ordering::Relaxed);
+            HEALTH.set_connected(true);
+        }
         let ws_handle = tokio::spawn(async move {
-            Self::websocket_task(
+            let result = Self::websocket_task(
                 read,
                 write,
                 command_rx,
@@ -86,8 +137,14 @@ impl DeribitConnection {
                 fast_trade_tx,
                 fast_orderbook_tx,
                 streaming_parser,
+                journal,
+                connection_id,
             )
-            .await
+            .await;
+            if market_data {
+                HEALTH.set_connected(false);
+            }
+            result
         });
         task_handles.push(ws_handle);
 
@@ -95,7 +152,7 @@ impl DeribitConnection {
 
         let router_handle =
             tokio::spawn(
-                async move { router_task(parsed_rx, control_tx, shutdown_rx_router).await },
+                async move { router_task(parsed_rx, control_tx, recovery_tx, shutdown_rx_router).await },
             );
         task_handles.push(router_handle);
 
@@ -139,6 +196,8 @@ impl DeribitConnection {
         fast_trade_tx: mpsc::Sender<TradeEvent>,
         fast_orderbook_tx: mpsc::Sender<OrderbookResult>,
         streaming_parser: StreamingParser,
+        journal: Option<JournalHandle>,
+        connection_id: u32,
     ) -> Result<(), DeribitError> {
         let mut parse_buffer = Vec::with_capacity(4096);
         let mut parse_tracker = LatencyTracker::new(1000);
@@ -151,43 +210,42 @@ impl DeribitConnection {
                 ws_msg = read.next() => {
                     match ws_msg {
                         Some(Ok(Message::Text(text))) => {
+                            let recv_ts_ns = if journal.is_some() { now_ns() } else { 0 };
                             if parse_buffer.capacity() > 8192 {
                                 warn!("websocket_task: shrink of parse_buffer");
                                 parse_buffer.shrink_to(4096);
                             }
                             message_monitor.record_message();
-                            parse_buffer.clear();
-                            parse_buffer.extend_from_slice(text.as_bytes());
+                            METRICS.on_message();
                             let parse_start = Instant::now();
-                            match streaming_parser.parse_fast_new(&mut parse_buffer) {
-                                Ok(Some(FastMarketData::Trade(trades))) => {
+                            match parse_frame(&streaming_parser, &mut parse_buffer, &text) {
+                                Ok(ParsedFrame::Trades(trades)) => {
                                     parse_tracker.record(parse_start.elapsed());
+                                    METRICS.parse_latency.observe(parse_start.elapsed());
                                     for trade in trades {
-                                        let _ = fast_trade_tx.try_send(trade);
+                                        METRICS.on_send(Queue::Trades, fast_trade_tx.try_send(trade));
                                     }
                                 }
-                                Ok(Some(FastMarketData::OrderbookUpdate(orderbook))) => {
+                                Ok(ParsedFrame::Orderbook(orderbook)) => {
                                     parse_tracker.record(parse_start.elapsed());
-                                    let _ = fast_orderbook_tx.try_send(orderbook);
+                                    METRICS.parse_latency.observe(parse_start.elapsed());
+                                    METRICS.on_send(Queue::Orderbooks, fast_orderbook_tx.try_send(orderbook));
                                 }
-                                Ok(None) => {
-                                    // slow path for auth/subscription messages
-                                    match MessageParser::parse_bytes(&mut parse_buffer) {
-                                        Ok(parsed_msg) => {
-                                            parse_tracker.record(parse_start.elapsed());
-                                            let _ = parsed_tx.try_send(parsed_msg);
-                                        }
-                                        Err(e) => {
-                                            error!("MessageParser error: {:?}", e.to_string());
-                                            error!("MessageParser error: {:?}", text);
-                                            message_monitor.record_error();}
-                                    }
+                                Ok(ParsedFrame::Other(parsed_msg)) => {
+                                    parse_tracker.record(parse_start.elapsed());
+                                    METRICS.parse_latency.observe(parse_start.elapsed());
+                                    METRICS.on_send(Queue::Parsed, parsed_tx.try_send(parsed_msg));
+                                }
+                                Err((parser, e)) => {
+                                    error!("{} error: {:?}", parser, e.to_string());
+                                    error!("{} error: {:?}", parser, text);
+                                    message_monitor.record_error();
+                                    METRICS.parse_errors.fetch_add(1, Ordering::Relaxed);
                                 }
-
-                                Err(e) => {
-                                    error!("streaming_parser error: {:?}", e.to_string());
-                                    error!("streaming_parser error: {:?}", text);
-                                    message_monitor.record_error(); }
+                            }
+                            // after the parsing, the frame is moved rather than copied
+                            if let Some(journal) = &journal {
+                                journal.record(connection_id, recv_ts_ns, text);
                             }
                         }
                         Some(Err(e)) => {
@@ -231,6 +289,9 @@ impl DeribitConnection {
                     let messages_stat = message_monitor.get_stats();
                     info!("websocket_task: websocket Message Stats: msg_rates {} | errors {} | last_message_age {}",
                         messages_stat.msg_rate, messages_stat.error_rate, messages_stat.last_message_age);
+                    if let Some(dropped) = journal.as_ref().map(|journal| journal.take_dropped()).filter(|&d| d > 0) {
+                        warn!("websocket_task: {} frames not journaled, the journal queue was full", dropped);
+                    }
                 }
 
                 _ = tokio::time::sleep(Duration::from_secs(30)) => {
@@ -269,28 +330,79 @@ impl DeribitConnection {
     }
 }
 
-async fn router_task(
+// What a text frame turns into
+pub(crate) enum ParsedFrame {
+    Trades(SmallVec<[TradeEvent; 4]>),
+    Orderbook(OrderbookResult),
+    Other(DeribitMessage),
+}
+
+// Fast path for market data, slow path for auth/subscription messages. The error tells which parser failed.
+pub(crate) fn parse_frame(
+    streaming_parser: &StreamingParser,
+    parse_buffer: &mut Vec<u8>,
+    text: &str,
+) -> Result<ParsedFrame, (&'static str, ParseError)> {
+    parse_buffer.clear();
+    parse_buffer.extend_from_slice(text.as_bytes());
+    match streaming_parser.parse_fast_new(parse_buffer) {
+        Ok(Some(FastMarketData::Trade(trades))) => Ok(ParsedFrame::Trades(trades)),
+        Ok(Some(FastMarketData::OrderbookUpdate(orderbook))) => Ok(ParsedFrame::Orderbook(orderbook)),
+        Ok(None) => MessageParser::parse_bytes(parse_buffer)
+            .map(ParsedFrame::Other)
+            .map_err(|e| ("MessageParser", e)),
+        Err(e) => Err(("streaming_parser", e)),
+    }
+}
+
+// Stops when the websocket task is gone (or at the end of a replay), nothing can be routed anymore
+pub(crate) async fn router_task(
     mut parsed_rx: mpsc::Receiver<DeribitMessage>,
     control_tx: mpsc::Sender<ControlMessage>,
+    recovery_tx: mpsc::Sender<OrderbookResult>,
     mut shutdown_rx: broadcast::Receiver<()>,
 ) -> Result<(), DeribitError> {
     loop {
         tokio::select! {
-            Some(msg) = parsed_rx.recv() => {
+            msg = parsed_rx.recv() => {
+                let Some(msg) = msg else {
+                    return Ok(());
+                };
                 match msg {
                     DeribitMessage::Auth(auth) => {
-                        let result = AuthResult {access_token: auth.result.access_token.clone(), expires_in: auth.result.expires_in};
+                        let result = AuthResult {access_token: auth.result.access_token.clone(), refresh_token: auth.result.refresh_token.clone(), expires_in: auth.result.expires_in};
                         let control_msg = ControlMessage::AuthResult {id: auth.id, result: Ok(result)};
-                        let _ = control_tx.try_send(control_msg);
+                        METRICS.on_send(Queue::Control, control_tx.try_send(control_msg));
                         }
                     DeribitMessage::Subscription(sub) => {
                         let result = SubscriptionResult {channels: sub.result.clone(),success: true};
                         let control_msg = ControlMessage::SubscriptionResult {id: sub.id,result: Ok(result)};
-                        let _ = control_tx.try_send(control_msg);
+                        METRICS.on_send(Queue::Control, control_tx.try_send(control_msg));
                     }
                     DeribitMessage::Pong(pong) => {
                         info!("router_task: received pong usDiff {} usIn {} usOut {}", pong.us_diff, pong.us_in, pong.us_out);
                     }
+                    DeribitMessage::UserOrders(msg) => {
+                        METRICS.on_send(Queue::Control, control_tx.try_send(ControlMessage::UserOrders(msg.orders)));
+                    }
+                    DeribitMessage::UserTrades(msg) => {
+                        METRICS.on_send(Queue::Control, control_tx.try_send(ControlMessage::UserTrades(msg.trades)));
+                    }
+                    DeribitMessage::OpenOrders(msg) => {
+                        METRICS.on_send(Queue::Control, control_tx.try_send(ControlMessage::OpenOrders {id: msg.id, orders: msg.orders}));
+                    }
+                    DeribitMessage::Positions(msg) => {
+                        METRICS.on_send(Queue::Control, control_tx.try_send(ControlMessage::Positions {id: msg.id, positions: msg.positions}));
+                    }
+                    DeribitMessage::Instruments(msg) => {
+                        METRICS.on_send(Queue::Control, control_tx.try_send(ControlMessage::Instruments {id: msg.id, instruments: msg.instruments}));
+                    }
+                    DeribitMessage::OrderbookRecovery(msg) => {
+                        info!("router_task: recovery snapshot for {} at change_id {}", msg.instrument_name, msg.change_id);
+                        if let Err(e) = recovery_tx.send(recovery_snapshot(msg)).await {
+                            error!("router_task: recovery snapshot not delivered: {}", e);
+                        }
+                    }
                     _ => {}
                     }
             }
@@ -299,6 +411,26 @@ async fn router_task(
     }
 }
 
+// The book the reply belongs to is encoded in the request id
+fn recovery_snapshot(msg: OrderbookRecoveryMessage) -> OrderbookResult {
+    let mut data = OrderbookUpdateDataRaw::new();
+    let levels = |side: &[(f64, f64)]| -> Vec<OrderbookLevel> {
+        side.iter()
+            .filter_map(|&(price, amount)| {
+                let price = Decimal::from_f64(price).ok()?;
+                OrderbookLevel::from_action_number(0, price, amount as f32)
+            })
+            .collect()
+    };
+    for level in levels(&msg.bids) {
+        data.add_bid(level);
+    }
+    for level in levels(&msg.asks) {
+        data.add_ask(level);
+    }
+    let instrument_idx = (msg.id - request_id::ORDER_BOOK.start) as usize;
+    OrderbookResult::new(msg.change_id, msg.timestamp, instrument_idx, data, true)
+}
 
 impl DeribitReceiver {
     pub fn into_control_rx(self) -> mpsc::Receiver<ControlMessage> {
@@ -345,11 +477,31 @@ impl DeribitReceiver {
         .await
         .map_err(|_| DeribitError::Timeout)?
     }
+
+    pub async fn wait_for_instruments_response(
+        &mut self,
+        expected_id: u64,
+    ) -> Result<Vec<InstrumentInfo>, DeribitError> {
+        let timeout_duration = Duration::from_secs(30);
+
+        tokio::time::timeout(timeout_duration, async {
+            while let Some(msg) = self.control_rx.recv().await {
+                if let ControlMessage::Instruments { id, instruments } = msg {
+                    if id == expected_id {
+                        return Ok(instruments);
+                    }
+                }
+            }
+            Err(DeribitError::ChannelClosed)
+        })
+        .await
+        .map_err(|_| DeribitError::Timeout)?
+    }
 }
 
 impl DeribitClient {
     pub async fn authenticate(&self, api_key: &str, api_secret: &str) -> Result<u64, DeribitError> {
-        let uuid = 1;
+        let uuid = request_id::AUTH;
         let msg = json!({
             "jsonrpc": "2.0",
             "id": uuid,
@@ -361,21 +513,155 @@ impl DeribitClient {
             }
         });
         self.send_command(msg.to_string()).await?;
-        Ok(1)
+        Ok(uuid)
+    }
+
+    // Same reply as authenticate, but the secret never goes over the socket
+    pub async fn authenticate_with_signature(&self, api_key: &str, api_secret: &str) -> Result<u64, DeribitError> {
+        let id = request_id::AUTH;
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_err(|e| DeribitError::AuthError(e.to_string()))?
+            .as_millis() as u64;
+        let nonce = auth_nonce();
+        let data = "";
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "public/auth",
+            "params": {
+                "grant_type": "client_signature",
+                "client_id": api_key,
+                "timestamp": timestamp,
+                "signature": client_signature(api_secret, timestamp, &nonce, data),
+                "nonce": nonce,
+                "data": data
+            }
+        });
+        self.send_command(msg.to_string()).await?;
+        Ok(id)
+    }
+
+    // New access token before the current one expires, the reply is a new auth result
+    pub async fn refresh_auth(&self, refresh_token: &str) -> Result<u64, DeribitError> {
+        let id = request_id::REFRESH_TOKEN;
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "public/auth",
+            "params": {
+                "grant_type": "refresh_token",
+                "refresh_token": refresh_token
+            }
+        });
+        self.send_command(msg.to_string()).await?;
+        Ok(id)
+    }
+
+    // Scopes the session to a subaccount, the reply is a new auth result
+    pub async fn exchange_token(&self, refresh_token: &str, subject_id: u64) -> Result<u64, DeribitError> {
+        let id = request_id::EXCHANGE_TOKEN;
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "public/exchange_token",
+            "params": {
+                "refresh_token": refresh_token,
+                "subject_id": subject_id
+            }
+        });
+        self.send_command(msg.to_string()).await?;
+        Ok(id)
     }
 
     pub async fn subscribe(&self, channels: &[String]) -> Result<u64, DeribitError> {
-        let id = 2;
+        let id = request_id::SUBSCRIBE;
         let msg = json!({
             "jsonrpc": "2.0",
             "id": id,
-            "method": "public/subscribe",
+            "method": Self::subscription_method(channels, "subscribe"),
             "params": { "channels": channels },
         });
         self.send_command(msg.to_string()).await?;
         Ok(id)
     }
 
+    // The reply lists the channels still subscribed to, like a subscription
+    pub async fn unsubscribe(&self, channels: &[String]) -> Result<u64, DeribitError> {
+        let id = request_id::UNSUBSCRIBE;
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": Self::subscription_method(channels, "unsubscribe"),
+            "params": { "channels": channels },
+        });
+        self.send_command(msg.to_string()).await?;
+        Ok(id)
+    }
+
+    // user.* channels are only served through the private endpoints
+    fn subscription_method(channels: &[String], action: &str) -> String {
+        if channels.iter().any(|c| c.starts_with("user.")) {
+            format!("private/{}", action)
+        } else {
+            format!("public/{}", action)
+        }
+    }
+
+    // Every active instrument, used for the tick sizes of the books
+    pub async fn get_instruments(&self) -> Result<u64, DeribitError> {
+        let id = request_id::INSTRUMENTS;
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "public/get_instruments",
+            "params": { "currency": "any", "expired": false },
+        });
+        self.send_command(msg.to_string()).await?;
+        Ok(id)
+    }
+
+    // `instrument_idx` routes the reply to its book, see recovery_snapshot
+    pub async fn get_order_book(&self, instrument_name: &str, depth: usize, instrument_idx: usize) -> Result<u64, DeribitError> {
+        let id = request_id::ORDER_BOOK.start + instrument_idx as u64;
+        if !request_id::ORDER_BOOK.contains(&id) {
+            return Err(DeribitError::InvalidFormat(format!("instrument index {} out of the order book id range", instrument_idx)));
+        }
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "public/get_order_book",
+            "params": { "instrument_name": instrument_name, "depth": depth },
+        });
+        self.send_command(msg.to_string()).await?;
+        Ok(id)
+    }
+
+    // `slot` tells the replies of several requests apart, see OrderTracker::request_slot
+    pub async fn get_open_orders_by_currency(&self, currency: &str, slot: u64) -> Result<u64, DeribitError> {
+        let id = request_id::OPEN_ORDERS.start + slot;
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "private/get_open_orders_by_currency",
+            "params": { "currency": currency },
+        });
+        self.send_command(msg.to_string()).await?;
+        Ok(id)
+    }
+
+    pub async fn get_positions(&self, currency: &str, slot: u64) -> Result<u64, DeribitError> {
+        let id = request_id::POSITIONS.start + slot;
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "private/get_positions",
+            "params": { "currency": currency },
+        });
+        self.send_command(msg.to_string()).await?;
+        Ok(id)
+    }
+
     async fn send_command(&self, msg: String) -> Result<(), DeribitError> {
         let (response_tx, _response_rx) = oneshot::channel();
         let command = ClientCommand { msg, response_tx };
@@ -1,7 +1,28 @@
+use hmac::{Hmac, Mac};
 use serde::Deserialize;
 use serde_json::Value;
+use sha2::Sha256;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use thiserror::Error;
 
+// JSON-RPC ids of the requests we send, the slow parser relies on them to route replies
+pub mod request_id {
+    use std::ops::Range;
+
+    pub const AUTH: u64 = 1;
+    pub const SUBSCRIBE: u64 = 2;
+    pub const EXCHANGE_TOKEN: u64 = 3;
+    pub const UNSUBSCRIBE: u64 = 4;
+    pub const INSTRUMENTS: u64 = 5;
+    pub const REFRESH_TOKEN: u64 = 6;
+    // one id per reconciliation request: start + slot, see OrderTracker::request_slot
+    pub const OPEN_ORDERS: Range<u64> = 100..200;
+    pub const POSITIONS: Range<u64> = 200..300;
+    // one id per book: start + instrument index, a range of its own far above the others so no
+    // instrument index can reach another kind of request
+    pub const ORDER_BOOK: Range<u64> = 1 << 32..2 << 32;
+}
 
 #[derive(Debug, Error, Clone)]
 pub enum DeribitError {
@@ -71,9 +92,40 @@ pub enum DeribitResponse {
 #[derive(Debug, Clone)]
 pub struct AuthResult {
     pub access_token: String,
+    pub refresh_token: String,
     pub expires_in: u64,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    // client_secret is sent as is
+    #[default]
+    ClientCredentials,
+    // only an HMAC-SHA256 of timestamp, nonce and data leaves the process
+    ClientSignature,
+}
+
+// Deribit client_signature: hex(HMAC-SHA256(secret, "{timestamp}\n{nonce}\n{data}"))
+pub fn client_signature(api_secret: &str, timestamp: u64, nonce: &str, data: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(api_secret.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(format!("{}\n{}\n{}", timestamp, nonce, data).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// Only needs to be unique per auth request, RandomState is seeded randomly per process and per call
+pub fn auth_nonce() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    format!("{:016x}", hasher.finish())
+}
+
 #[derive(Debug, Clone)]
 pub struct OrderBookData {
     pub instrument: String,
@@ -91,3 +143,16 @@ pub struct Trade {
 }
 
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_signature() {
+        // example of the Deribit API documentation, empty data
+        let signature = client_signature("AMANDASECRECT", 1554883365000, "fdbmmz79", "");
+        assert_eq!(signature, "438f21e59fce07c7e646e21a547e503546ad132f48e49aec6a7f771d981520a9");
+    }
+}
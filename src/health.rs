@@ -0,0 +1,219 @@
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering::Relaxed};
+use std::sync::{Mutex, OnceLock};
+use crate::journal::now_ns;
+use crate::metrics::METRICS;
+
+// State behind /health and /ready, set by the connection, main and the writer.
+//   /health: 503 when the connection is down, the writer stopped or a book is stale
+//   /ready:  also 503 until authenticated (and once the access token expired) and every configured channel is subscribed
+// Both answer the same JSON report.
+pub static HEALTH: Health = Health::new();
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HealthConfig {
+    // a subscribed book without an update applied for longer is stale
+    #[serde(default = "default_stale_book_ms")]
+    pub stale_book_ms: u64,
+    // the writer checks in every 100ms, it is stopped or stuck after this
+    #[serde(default = "default_writer_timeout_ms")]
+    pub writer_timeout_ms: u64,
+}
+
+fn default_stale_book_ms() -> u64 {
+    30_000
+}
+
+fn default_writer_timeout_ms() -> u64 {
+    5_000
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self { stale_book_ms: default_stale_book_ms(), writer_timeout_ms: default_writer_timeout_ms() }
+    }
+}
+
+#[derive(Default)]
+struct Channels {
+    expected: Vec<String>,
+    confirmed: Vec<String>,
+    // instrument and index of the book channels among them
+    books: Vec<(String, usize)>,
+    // when the expected channels last changed, a book is not stale before it had time to arrive
+    since_ns: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct HealthReport {
+    pub connected: bool,
+    pub authenticated: bool,
+    pub writer_alive: bool,
+    pub missing_channels: Vec<String>,
+    // instrument and seconds since its last update
+    pub stale_books: Vec<(String, f64)>,
+}
+
+impl HealthReport {
+    pub fn is_healthy(&self) -> bool {
+        self.connected && self.writer_alive && self.stale_books.is_empty()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.is_healthy() && self.authenticated && self.missing_channels.is_empty()
+    }
+
+    pub fn to_json(&self) -> String {
+        json!({
+            "healthy": self.is_healthy(),
+            "ready": self.is_ready(),
+            "connected": self.connected,
+            "authenticated": self.authenticated,
+            "writer_alive": self.writer_alive,
+            "missing_channels": self.missing_channels,
+            "stale_books": self.stale_books.iter().map(|(instrument, age)| json!({"instrument": instrument, "age_s": age})).collect::<Vec<_>>(),
+        })
+        .to_string()
+    }
+}
+
+pub struct Health {
+    connected: AtomicBool,
+    // wall clock when the access token expires, 0 when not authenticated
+    authenticated_until_ns: AtomicU64,
+    // wall clock of the last writer check in, 0 before it started
+    writer_heartbeat_ns: AtomicU64,
+    channels: Mutex<Channels>,
+    config: OnceLock<HealthConfig>,
+}
+
+impl Health {
+    const fn new() -> Self {
+        Self {
+            connected: AtomicBool::new(false),
+            authenticated_until_ns: AtomicU64::new(0),
+            writer_heartbeat_ns: AtomicU64::new(0),
+            channels: Mutex::new(Channels { expected: Vec::new(), confirmed: Vec::new(), books: Vec::new(), since_ns: 0 }),
+            config: OnceLock::new(),
+        }
+    }
+
+    pub fn configure(&self, config: HealthConfig) {
+        let _ = self.config.set(config);
+    }
+
+    fn channels(&self) -> std::sync::MutexGuard<'_, Channels> {
+        self.channels.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // The session and its authentication end with the connection
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Relaxed);
+        if !connected {
+            self.authenticated_until_ns.store(0, Relaxed);
+            self.channels().confirmed.clear();
+        }
+    }
+
+    // `expires_in_s` of the auth reply, every token refresh sets it again
+    pub fn set_authenticated(&self, expires_in_s: u64) {
+        self.authenticated_until_ns.store(now_ns().saturating_add(expires_in_s.saturating_mul(1_000_000_000)), Relaxed);
+    }
+
+    #[inline]
+    pub fn writer_heartbeat(&self) {
+        self.writer_heartbeat_ns.store(now_ns(), Relaxed);
+    }
+
+    pub fn set_expected_channels(&self, channels: &[String], books: Vec<(String, usize)>) {
+        let mut state = self.channels();
+        state.expected = channels.to_vec();
+        state.books = books;
+        state.since_ns = now_ns();
+    }
+
+    pub fn on_subscribed(&self, channels: &[String]) {
+        let mut state = self.channels();
+        for channel in channels {
+            if !state.confirmed.contains(channel) {
+                state.confirmed.push(channel.clone());
+            }
+        }
+    }
+
+    // Removed before the request goes out, a later subscription to them has to be confirmed again
+    pub fn on_unsubscribed(&self, channels: &[String]) {
+        self.channels().confirmed.retain(|channel| !channels.contains(channel));
+    }
+
+    pub fn report(&self) -> HealthReport {
+        self.report_at(now_ns(), |instrument_idx| METRICS.last_book_applied_ns(instrument_idx))
+    }
+
+    // `last_update_ns` gives the wall clock of the last update applied to a book, 0 when none
+    fn report_at(&self, now_ns: u64, last_update_ns: impl Fn(usize) -> u64) -> HealthReport {
+        let default_config = HealthConfig::default();
+        let config = self.config.get().unwrap_or(&default_config);
+        let age_s = |since_ns: u64| now_ns.saturating_sub(since_ns) as f64 / 1e9;
+        let writer_heartbeat_ns = self.writer_heartbeat_ns.load(Relaxed);
+        let writer_alive = writer_heartbeat_ns > 0 && age_s(writer_heartbeat_ns) * 1e3 <= config.writer_timeout_ms as f64;
+
+        let state = self.channels();
+        let missing_channels = state.expected.iter().filter(|channel| !state.confirmed.contains(channel)).cloned().collect();
+        let stale_books = state
+            .books
+            .iter()
+            .map(|(instrument, idx)| (instrument.clone(), age_s(last_update_ns(*idx).max(state.since_ns))))
+            .filter(|(_, age)| age * 1e3 > config.stale_book_ms as f64)
+            .collect();
+        HealthReport {
+            connected: self.connected.load(Relaxed),
+            authenticated: now_ns < self.authenticated_until_ns.load(Relaxed),
+            writer_alive,
+            missing_channels,
+            stale_books,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let health = Health::new();
+        health.configure(HealthConfig { stale_book_ms: 1_000, writer_timeout_ms: 5_000 });
+        let channels = ["book.BTC-PERPETUAL.raw".to_string(), "trades.ETH-PERPETUAL.raw".to_string()];
+        health.set_expected_channels(&channels, vec![("BTC-PERPETUAL".to_string(), 0)]);
+        let start = health.channels().since_ns;
+        let last_updates = |idx: usize| if idx == 0 { start + 2_000_000_000 } else { 0 };
+
+        let report = health.report_at(start, last_updates);
+        assert!(!report.is_healthy() && !report.is_ready());
+        assert_eq!(report.missing_channels.len(), 2);
+
+        health.set_connected(true);
+        health.set_authenticated(900);
+        health.writer_heartbeat();
+        health.on_subscribed(&channels);
+        let now = health.writer_heartbeat_ns.load(Relaxed).max(start);
+        let report = health.report_at(now, last_updates);
+        assert!(report.is_healthy() && report.is_ready(), "{:?}", report);
+
+        // 1.5 s after the last update of the book
+        let report = health.report_at(start + 3_500_000_000, |_| start + 2_000_000_000);
+        assert_eq!(report.stale_books, vec![("BTC-PERPETUAL".to_string(), 1.5)]);
+        assert!(!report.is_healthy());
+
+        // the token expired
+        let report = health.report_at(now + 901_000_000_000, last_updates);
+        assert!(!report.authenticated && !report.is_ready());
+
+        health.set_connected(false);
+        let report = health.report_at(now, last_updates);
+        assert!(!report.connected && !report.authenticated && report.missing_channels.len() == 2);
+    }
+}
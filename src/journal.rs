@@ -0,0 +1,272 @@
+use memmap2::{Mmap, MmapMut};
+use serde::Deserialize;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+// Raw capture of the websocket text frames, exactly as Deribit sent them.
+//
+// File layout, little endian:
+//   header: magic (8 bytes), creation time in ns since the epoch (u64)
+//   records: payload length (u32), connection id (u32), local receive time in ns since the epoch (u64),
+//            payload, zero padding to 8 bytes
+// A length of 0 ends the file, what follows is unused space of a file that was not closed.
+
+const MAGIC: &[u8; 8] = b"HKFHJRN1";
+const FILE_HEADER_SIZE: usize = 16;
+const RECORD_HEADER_SIZE: usize = 16;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct JournalConfig {
+    pub dir: String,
+    // a new file is started when the current one is full or older than rotate_secs
+    #[serde(default = "default_max_file_mb")]
+    pub max_file_mb: u64,
+    #[serde(default = "default_rotate_secs")]
+    pub rotate_secs: u64,
+    // frames waiting for the journal thread, beyond that they are dropped and counted
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+}
+
+fn default_max_file_mb() -> u64 {
+    1024
+}
+
+fn default_rotate_secs() -> u64 {
+    3600
+}
+
+fn default_queue_size() -> usize {
+    65536
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalFrame {
+    pub connection_id: u32,
+    pub recv_ts_ns: u64,
+    pub payload: String,
+}
+
+#[inline]
+pub fn now_ns() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+}
+
+#[inline]
+fn padded(len: usize) -> usize {
+    len.next_multiple_of(8)
+}
+
+// Given to the websocket tasks, the frame is moved to the journal thread and never copied on the hot path
+#[derive(Clone)]
+pub struct JournalHandle {
+    tx: mpsc::Sender<JournalFrame>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl JournalHandle {
+    #[inline]
+    pub fn record(&self, connection_id: u32, recv_ts_ns: u64, payload: String) {
+        if self.tx.try_send(JournalFrame { connection_id, recv_ts_ns, payload }).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Frames dropped since the last call
+    pub fn take_dropped(&self) -> u64 {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+}
+
+// Starts the journal thread, it stops once every handle is dropped
+pub fn spawn_journal(config: &JournalConfig) -> std::io::Result<JournalHandle> {
+    std::fs::create_dir_all(&config.dir)?;
+    let (tx, mut rx) = mpsc::channel(config.queue_size.max(1));
+    let mut writer = JournalWriter::new(
+        PathBuf::from(&config.dir),
+        (config.max_file_mb * 1024 * 1024) as usize,
+        Duration::from_secs(config.rotate_secs),
+    );
+    std::thread::Builder::new().name("fh-journal".to_string()).spawn(move || {
+        while let Some(frame) = rx.blocking_recv() {
+            if let Err(e) = writer.append(&frame) {
+                error!("journal: frame lost: {}", e);
+            }
+        }
+        if let Err(e) = writer.close() {
+            error!("journal: cannot close {:?}: {}", writer.current_path(), e);
+        }
+    })?;
+    Ok(JournalHandle { tx, dropped: Arc::new(AtomicU64::new(0)) })
+}
+
+struct JournalFile {
+    file: File,
+    mmap: MmapMut,
+    path: PathBuf,
+    len: usize,
+    opened_at: Instant,
+}
+
+pub struct JournalWriter {
+    dir: PathBuf,
+    max_file_bytes: usize,
+    rotate_every: Duration,
+    current: Option<JournalFile>,
+    files_opened: u64,
+}
+
+impl JournalWriter {
+    pub fn new(dir: PathBuf, max_file_bytes: usize, rotate_every: Duration) -> Self {
+        Self { dir, max_file_bytes, rotate_every, current: None, files_opened: 0 }
+    }
+
+    pub fn current_path(&self) -> Option<&Path> {
+        self.current.as_ref().map(|current| current.path.as_path())
+    }
+
+    pub fn append(&mut self, frame: &JournalFrame) -> std::io::Result<()> {
+        let payload = frame.payload.as_bytes();
+        let needed = RECORD_HEADER_SIZE + padded(payload.len());
+        let rotate = match &self.current {
+            None => true,
+            Some(current) => current.len + needed > current.mmap.len() || current.opened_at.elapsed() >= self.rotate_every,
+        };
+        if rotate {
+            self.rotate(needed)?;
+        }
+        let Some(current) = self.current.as_mut() else {
+            return Ok(());
+        };
+
+        let start = current.len;
+        let record = &mut current.mmap[start..start + needed];
+        record[0..4].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        record[4..8].copy_from_slice(&frame.connection_id.to_le_bytes());
+        record[8..16].copy_from_slice(&frame.recv_ts_ns.to_le_bytes());
+        record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + payload.len()].copy_from_slice(payload);
+        current.len += needed;
+        Ok(())
+    }
+
+    // Cuts the unused end of the current file
+    pub fn close(&mut self) -> std::io::Result<()> {
+        let Some(current) = self.current.take() else {
+            return Ok(());
+        };
+        current.mmap.flush()?;
+        drop(current.mmap);
+        current.file.set_len(current.len as u64)?;
+        info!("journal: closed {} ({} bytes)", current.path.display(), current.len);
+        Ok(())
+    }
+
+    fn rotate(&mut self, needed: usize) -> std::io::Result<()> {
+        self.close()?;
+        let created_ns = now_ns();
+        self.files_opened += 1;
+        let path = self.dir.join(format!("journal_{}_{}.bin", created_ns / 1_000_000, self.files_opened));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        // a frame larger than a file gets a file of its own
+        let size = self.max_file_bytes.max(FILE_HEADER_SIZE + needed);
+        file.set_len(size as u64)?;
+        let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+        mmap[0..8].copy_from_slice(MAGIC);
+        mmap[8..16].copy_from_slice(&created_ns.to_le_bytes());
+        info!("journal: writing to {}", path.display());
+        self.current = Some(JournalFile { file, mmap, path, len: FILE_HEADER_SIZE, opened_at: Instant::now() });
+        Ok(())
+    }
+}
+
+// Frames of a journal file in the order they were received
+pub struct JournalReader {
+    mmap: Mmap,
+    pos: usize,
+}
+
+impl JournalReader {
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        if mmap.len() < FILE_HEADER_SIZE || &mmap[0..8] != MAGIC {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "not a journal file"));
+        }
+        Ok(Self { mmap, pos: FILE_HEADER_SIZE })
+    }
+}
+
+impl Iterator for JournalReader {
+    type Item = std::io::Result<JournalFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = &self.mmap[self.pos..];
+        if data.len() < RECORD_HEADER_SIZE {
+            return None;
+        }
+        let len = u32::from_le_bytes(data[0..4].try_into().ok()?) as usize;
+        if len == 0 {
+            return None;
+        }
+        let needed = RECORD_HEADER_SIZE + padded(len);
+        if data.len() < RECORD_HEADER_SIZE + len {
+            return Some(Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "truncated record")));
+        }
+        let connection_id = u32::from_le_bytes(data[4..8].try_into().ok()?);
+        let recv_ts_ns = u64::from_le_bytes(data[8..16].try_into().ok()?);
+        let payload = String::from_utf8(data[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len].to_vec())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+        self.pos = (self.pos + needed).min(self.mmap.len());
+        Some(payload.map(|payload| JournalFrame { connection_id, recv_ts_ns, payload }))
+    }
+}
+
+// Journal files of a directory, oldest first
+pub fn journal_files(dir: impl AsRef<Path>) -> std::io::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with("journal_") && n.ends_with(".bin")))
+        .collect();
+    // journal_<ms>_<n>.bin, by time then by file number
+    files.sort_by_key(|path| {
+        let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+        let mut parts = stem.trim_start_matches("journal_").split('_').map(|p| p.parse::<u64>().unwrap_or(0));
+        (parts.next().unwrap_or(0), parts.next().unwrap_or(0))
+    });
+    Ok(files)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_rotate_read() {
+        let dir = std::env::temp_dir().join(format!("haiku_fh_journal_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let frame = |i: u64| JournalFrame { connection_id: 1, recv_ts_ns: 1_000 + i, payload: format!("{{\"n\":{}}}", i) };
+
+        // room for 3 of these frames per file
+        let mut writer = JournalWriter::new(dir.clone(), FILE_HEADER_SIZE + 3 * 24, Duration::from_secs(3600));
+        for i in 0..5 {
+            writer.append(&frame(i)).unwrap();
+        }
+        writer.close().unwrap();
+
+        let files = journal_files(&dir).unwrap();
+        assert_eq!(files.len(), 2);
+        let frames: Vec<JournalFrame> = files
+            .iter()
+            .flat_map(|path| JournalReader::open(path).unwrap())
+            .map(|frame| frame.unwrap())
+            .collect();
+        assert_eq!(frames, (0..5).map(frame).collect::<Vec<_>>());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -4,3 +4,17 @@ mod config_global;
 pub mod parsing;
 pub mod shm_writer;
 pub mod orderbook_management;
+pub mod order_tracker;
+pub mod price;
+pub mod shm_slots;
+pub mod book_analytics;
+pub mod order_flow;
+pub mod conflation;
+pub mod book_dump;
+pub mod trade_checks;
+pub mod sinks;
+pub mod journal;
+pub mod bars;
+pub mod wire;
+pub mod metrics;
+pub mod health;
@@ -1,82 +1,326 @@
 mod config_global;
+mod config_reload;
 mod deribit;
 mod deribit_helper;
 mod parsing;
 mod shm_writer;
 mod orderbook_management;
+mod order_tracker;
+mod price;
+mod shm_slots;
+mod book_analytics;
+mod order_flow;
+mod conflation;
+mod book_dump;
+mod trade_checks;
+mod sinks;
+mod journal;
+mod bars;
+mod wire;
+mod replay;
+mod metrics;
+mod health;
 
-use config_global::Config;
-use deribit::DeribitConnection;
+use config_global::{Config, ConfigError, ConfigOverrides, split_list};
+use config_reload::{ChannelDiff, ConfigWatcher, book_instrument};
+use deribit::{ControlMessage, DeribitClient, DeribitConnection, DeribitReceiver};
+use deribit_helper::{AuthMethod, AuthResult, DeribitError, request_id};
+use order_tracker::{OrderTracker, currencies_from_channels};
+use parsing::parsing_admin::InstrumentInfo;
+use price::{ContractType, TickSize};
+use book_analytics::AnalyticsPublisher;
+use order_flow::OrderFlowPublisher;
+use bars::BarPublisher;
+use shm_slots::ShmSlots;
+use book_dump::{BookDumpFormat, write_dump};
+use trade_checks::TradeChecker;
+use sinks::FanOutSink;
+use sinks::sink_export::{ExportFormat, ExportSinkConfig};
+use journal::spawn_journal;
+use replay::{Pacing, ReplayOptions};
+use metrics::{METRICS, serve_http};
+use health::HEALTH;
+use parsing::parsing_fast_orderbook::OrderbookResult;
+use haiku_common::shm_accessor::market_data_type::TradeEvent;
 use haiku_common::metadata::ShmMetadata;
-use haiku_common::shm_accessor::SHMAccessor;
-use haiku_common::shm_accessor::trade_ring_buffer::TradeRingBuffer;
 use tokio::signal;
-use tokio::sync::broadcast;
+use std::collections::HashMap;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
 use tracing::{info, warn, error};
 use haiku_common::monitoring::logger::StdoutLogger;
-use shm_writer::shm_writer_task;
+use shm_writer::{WriterClock, WriterCommand, WriterEvent, WriterSetup, shm_writer_task};
+use orderbook_management::SHM_BOOK_LEVELS;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+// The exchange can change the tick size of a listed instrument
+const TICK_SIZE_REFRESH: Duration = Duration::from_secs(3600);
+// How often the access tokens are checked, they are refreshed after 4/5 of their lifetime
+const TOKEN_CHECK: Duration = Duration::from_secs(10);
+// Wait before asking again when a refresh got no reply
+const TOKEN_RETRY: Duration = Duration::from_secs(30);
 
 #[derive(Parser, Debug)]
 #[command(name = "haiku_fh", about = "Small FH for Deribit")]
 struct Args {
-
+    /// Feed handler config (.json, .yaml or .yml), every key can be overridden by HAIKU_FH_<KEY> then by the flags below
     #[arg(long, value_name = "config-fh")]
     config_fh: String,
 
+    /// SHM metadata file, defaults to meta_data_path from the config
     #[arg(long, value_name = "config-shm")]
-    config_shm: String,
+    config_shm: Option<String>,
+
+    /// Websocket URL of the exchange, replaces url from the config
+    #[arg(long)]
+    url: Option<String>,
+
+    /// Comma separated channels, replace the channels of the config
+    #[arg(long)]
+    channels: Option<String>,
+
+    /// Log directory, replaces log_path from the config
+    #[arg(long)]
+    log_path: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Feed a recorded journal through the parsers, the books and the sinks instead of connecting
+    Replay {
+        /// Journal file, or a directory replayed file after file
+        journal: String,
+
+        /// Replay speed, 2.0 replays twice as fast as recorded
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+
+        /// No pacing at all, as fast as the pipeline goes
+        #[arg(long)]
+        asap: bool,
+    },
+    /// Replay a journal as fast as possible into per instrument trade and book tables
+    Export {
+        /// Journal file, or a directory replayed file after file
+        journal: String,
+
+        /// Output directory, one sub-directory per instrument
+        #[arg(long)]
+        out: String,
+
+        /// Table formats: csv, parquet or both
+        #[arg(long, value_delimiter = ',', default_value = "csv,parquet")]
+        formats: Vec<ExportFormat>,
+
+        /// Book levels per side
+        #[arg(long, default_value_t = SHM_BOOK_LEVELS)]
+        levels: usize,
+    },
+}
+
+impl Args {
+    fn overrides(&self) -> ConfigOverrides {
+        ConfigOverrides {
+            url: self.url.clone(),
+            channels: self.channels.as_deref().map(split_list),
+            log_path: self.log_path.clone(),
+            meta_data_path: self.config_shm.clone(),
+        }
+    }
+}
+
+// Configuration mistakes are reported plainly instead of through the Debug output of main's error
+fn exit_on_config_error<T>(result: Result<T, ConfigError>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("haiku_fh: invalid configuration: {}", e);
+        std::process::exit(2);
+    })
 }
 
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    let cfg = Config::load(&args.config_fh)?;
+    let cfg = exit_on_config_error(Config::load(&args.config_fh, &args.overrides()));
+    let metadata = ShmMetadata::load_from_file(&cfg.meta_data_path)?;
+    exit_on_config_error(cfg.validate(&metadata.clone_instrument_index()));
     let logger = StdoutLogger::new(&cfg.log_path, "fh", &["haiku_fh", "haiku_common"]);
-    let metadata = ShmMetadata::load_from_file(&args.config_shm)?;
+    info!("configuration: {:?}", cfg);
+    if let Some(Command::Replay { journal, speed, asap }) = &args.command {
+        let pacing = if *asap { Pacing::Asap } else { Pacing::Speed(*speed) };
+        if !pacing.is_valid() {
+            eprintln!("haiku_fh: --speed must be a positive number, got {}", speed);
+            std::process::exit(2);
+        }
+        replay::run(&cfg, &metadata, ReplayOptions { journal: journal.into(), pacing }).await?;
+        return Ok(());
+    }
+    if let Some(Command::Export { journal, out, formats, levels }) = &args.command {
+        let export = ExportSinkConfig { formats: formats.clone(), levels: *levels, ..ExportSinkConfig::new(out.clone()) };
+        let cfg = replay::export_config(cfg, export);
+        replay::run(&cfg, &metadata, ReplayOptions { journal: journal.into(), pacing: Pacing::Asap }).await?;
+        return Ok(());
+    }
     let nb_instruments = metadata.max_instruments;
+    let book_settings = cfg.book_settings(&metadata.clone_instrument_index(), nb_instruments);
+    let snapshot_depths = book_settings.iter().map(|settings| settings.snapshot_levels()).collect();
+    let recovery_depths: Vec<usize> = book_settings.iter().map(|settings| settings.recovery_depth()).collect();
+
+    let mut instrument_names = vec![String::new(); nb_instruments];
+    for (instrument, &idx) in &metadata.clone_instrument_index() {
+        if idx < nb_instruments {
+            instrument_names[idx] = instrument.clone();
+        }
+    }
+    METRICS.set_instruments(&instrument_names);
+    HEALTH.configure(cfg.health.clone());
+    HEALTH.set_expected_channels(&cfg.channels, expected_books(&cfg.channels, &metadata.clone_instrument_index()));
+    if let Some(addr) = &cfg.http_addr {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        tokio::spawn(serve_http(listener));
+    }
 
     let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
-    let mut connection = DeribitConnection::connect(&cfg.url, metadata.clone_instrument_index(), shutdown_tx).await?;
+    let journal = cfg.journal.as_ref().map(spawn_journal).transpose()?;
+    let mut connection = DeribitConnection::connect(&cfg.url, metadata.clone_instrument_index(), snapshot_depths, shutdown_tx.clone(), journal).await?;
     let client = connection.client();
     let mut receiver = connection.take_receiver().expect("Failed to get receiver");
     let (fast_trade_rx, fast_orderbook_rx) = connection.take_fast_channels();
 
-    let auth_id = client
-        .authenticate(&cfg.key, &cfg.secret)
-        .await?;
-    let _auth_result = receiver.wait_for_auth_response(auth_id).await?;
-    info!("authentication successful to {}", cfg.url);
+    let auth = authenticate(&client, &mut receiver, &cfg, cfg.subaccount_ids.first().copied()).await?;
+    HEALTH.set_authenticated(auth.expires_in);
+    let mut sessions = vec![Session::new(&auth)];
+
+    let instruments_id = client.get_instruments().await?;
+    let instruments = receiver.wait_for_instruments_response(instruments_id).await?;
+    let mut tick_sizes = tick_sizes_by_index(&instruments, &metadata.clone_instrument_index(), nb_instruments);
+    let contract_types = contract_types_by_index(&instruments, &metadata.clone_instrument_index(), nb_instruments);
+    let missing = books_without_tick_size(&cfg.channels, &metadata.clone_instrument_index(), &tick_sizes);
+    if !missing.is_empty() {
+        eprintln!("haiku_fh: no tick size from the exchange for books {:?}, is the instrument still listed?", missing);
+        std::process::exit(2);
+    }
 
-    let channels = cfg.channels;
+    let mut channels = cfg.channels.clone();
     let sub_id = client.subscribe(&channels).await?;
     let _sub_result = receiver.wait_for_subscription_response(sub_id).await?;
     info!("subscribed to channels: {:?}", _sub_result.channels);
+    HEALTH.on_subscribed(&_sub_result.channels);
 
     let _connection_handle = connection;
-    let mut control_rx = receiver.into_control_rx();
+    let (control_tx, mut control_rx) = mpsc::channel(100);
+    forward_control(0, receiver, control_tx.clone());
 
-    let shm_writer = SHMAccessor::new("rust_integration", &metadata)
-        .expect("failed to open shared memory writer");
-    let trade_buffer = TradeRingBuffer::new("rust_integration_trades_buffer", 1000)?;
+    // the main connection carries the first subaccount, the other ones get a connection for their user.* channels
+    let accounts = account_names(&cfg.subaccount_ids);
+    let mut clients = vec![client.clone()];
+    let mut subaccount_connections = Vec::new();
+    for &subaccount_id in cfg.subaccount_ids.iter().skip(1) {
+        let (connection, receiver, auth) = connect_subaccount(&cfg, subaccount_id, &channels, shutdown_tx.clone()).await?;
+        forward_control(clients.len(), receiver, control_tx.clone());
+        clients.push(connection.client());
+        sessions.push(Session::new(&auth));
+        subaccount_connections.push(connection);
+    }
+
+    let currencies = currencies_from_channels(&channels);
+    let mut order_trackers: Vec<OrderTracker> = accounts.iter().map(|_| OrderTracker::new()).collect();
+    for (account_client, order_tracker) in clients.iter().zip(&mut order_trackers) {
+        request_reconciliation(account_client, order_tracker, &currencies).await?;
+    }
 
     println!("spawning shm writer"); // just to know in the terminal all good
-    tokio::spawn(shm_writer_task(
-        fast_trade_rx,
-        fast_orderbook_rx,
-        shutdown_rx,
-        shm_writer,
-        trade_buffer,
-        nb_instruments,
-    ));
+    let setup = writer_setup(&cfg, &metadata, &tick_sizes, &contract_types, WriterClock::Wall)?;
+    let (writer_cmd_tx, mut writer_event_rx, writer_handle) = spawn_writer(setup, fast_trade_rx, fast_orderbook_rx, shutdown_rx);
+
 
+    let instrument_index = metadata.clone_instrument_index();
+    let mut config_watcher = ConfigWatcher::new(&args.config_fh, args.overrides());
+    let mut reload_timer = tokio::time::interval(Duration::from_secs(2));
+    let mut tick_size_timer = tokio::time::interval_at(tokio::time::Instant::now() + TICK_SIZE_REFRESH, TICK_SIZE_REFRESH);
+    let mut token_timer = tokio::time::interval(TOKEN_CHECK);
+    let mut sighup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+    let mut sigusr1 = signal::unix::signal(signal::unix::SignalKind::user_defined1())?;
+    let mut sigusr2 = signal::unix::signal(signal::unix::SignalKind::user_defined2())?;
 
     loop {
         tokio::select! {
-            Some(control_msg) = control_rx.recv() => {
-                info!("Control message: {:?}", control_msg);
+            Some((account, control_msg)) = control_rx.recv() => {
+                match control_msg {
+                    ControlMessage::Instruments { instruments, .. } => {
+                        refresh_tick_sizes(&instruments, &instrument_index, &mut tick_sizes, &writer_cmd_tx).await;
+                    }
+                    ControlMessage::AuthResult { id: request_id::REFRESH_TOKEN, result: Ok(auth) } => {
+                        info!("access token refreshed for {}, expires in {}s", accounts[account], auth.expires_in);
+                        if account == 0 {
+                            HEALTH.set_authenticated(auth.expires_in);
+                        }
+                        sessions[account] = Session::new(&auth);
+                    }
+                    control_msg => handle_control_message(control_msg, account, &accounts[account], &mut order_trackers[account]),
+                }
+            }
+
+            _ = token_timer.tick() => {
+                for ((account_client, session), account_name) in clients.iter().zip(&mut sessions).zip(&accounts) {
+                    if tokio::time::Instant::now() < session.refresh_at {
+                        continue;
+                    }
+                    session.refresh_at = tokio::time::Instant::now() + TOKEN_RETRY;
+                    if let Err(e) = account_client.refresh_auth(&session.refresh_token).await {
+                        error!("access token refresh failed for {}: {}", account_name, e);
+                    }
+                }
+            }
+
+            _ = tick_size_timer.tick() => {
+                if let Err(e) = client.get_instruments().await {
+                    error!("tick size refresh failed: {}", e);
+                }
+            }
+
+            Some(event) = writer_event_rx.recv() => {
+                match event {
+                    WriterEvent::ResyncBook(instrument_idx) => {
+                        if let Err(e) = resync_book(&client, &channels, &instrument_index, instrument_idx).await {
+                            error!("book resync failed: {}", e);
+                        }
+                    }
+                    WriterEvent::RecoverBook(instrument_idx) => {
+                        if let Err(e) = recover_book(&client, &channels, &instrument_index, &recovery_depths, instrument_idx).await {
+                            error!("book recovery failed: {}", e);
+                        }
+                    }
+                }
+            }
+
+            _ = reload_timer.tick() => {
+                if config_watcher.has_changed() {
+                    info!("{} modified, reloading channels", args.config_fh);
+                    if let Err(e) = reload_channels(&config_watcher, &clients, &mut order_trackers, &mut channels, &instrument_index, &tick_sizes, &writer_cmd_tx).await {
+                        error!("channel reload failed: {}", e);
+                    }
+                }
+            }
+
+            _ = sighup.recv() => {
+                info!("SIGHUP received, reloading channels from {}", args.config_fh);
+                if let Err(e) = reload_channels(&config_watcher, &clients, &mut order_trackers, &mut channels, &instrument_index, &tick_sizes, &writer_cmd_tx).await {
+                    error!("channel reload failed: {}", e);
+                }
+            }
+
+            _ = sigusr1.recv() => {
+                dump_books(&writer_cmd_tx, &instrument_names, BookDumpFormat::Text, cfg.book_dump_path.clone()).await;
+            }
+
+            _ = sigusr2.recv() => {
+                dump_books(&writer_cmd_tx, &instrument_names, BookDumpFormat::Json, cfg.book_dump_path.clone()).await;
             }
 
             _ = signal::ctrl_c() => {
@@ -86,5 +330,428 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
     _connection_handle.shutdown().await?;
+    for connection in subaccount_connections {
+        connection.shutdown().await?;
+    }
+    // the sinks close their files on the way out
+    match writer_handle.await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => error!("shm writer error during shutdown: {}", e),
+        Err(e) => error!("shm writer join error: {}", e),
+    }
+    Ok(())
+}
+
+type WriterHandles = (mpsc::Sender<WriterCommand>, mpsc::Receiver<WriterEvent>, JoinHandle<Result<(), DeribitError>>);
+
+// Opens the sinks and the SHM segments of the config for the writer, live or replayed (then on the recorded clock)
+fn writer_setup(
+    cfg: &Config,
+    metadata: &ShmMetadata,
+    tick_sizes: &[Option<TickSize>],
+    contract_types: &[ContractType],
+    clock: WriterClock,
+) -> Result<WriterSetup, Box<dyn std::error::Error>> {
+    let nb_instruments = metadata.max_instruments;
+    let book_settings = cfg.book_settings(&metadata.clone_instrument_index(), nb_instruments);
+    let sink = FanOutSink::from_config(&cfg.sinks, metadata)?;
+    let analytics = match &cfg.analytics {
+        Some(analytics_cfg) => Some(AnalyticsPublisher::new(analytics_cfg.clone(), contract_types.to_vec())?),
+        None => None,
+    };
+    let order_flow = match &cfg.order_flow {
+        Some(order_flow_cfg) => Some(OrderFlowPublisher::new(order_flow_cfg.clone(), nb_instruments)?),
+        None => None,
+    };
+    let book_changes = ShmSlots::create(&cfg.book_change_shm, nb_instruments)?;
+    let trade_checks = cfg.trade_checks.clone().map(|checks_cfg| TradeChecker::new(checks_cfg, nb_instruments));
+    let bars = match &cfg.bars {
+        Some(bars_cfg) => Some(BarPublisher::new(bars_cfg.clone(), contract_types.to_vec())?),
+        None => None,
+    };
+
+    Ok(WriterSetup {
+        sink,
+        analytics,
+        order_flow,
+        book_changes,
+        trade_checks,
+        bars,
+        book_settings,
+        tick_sizes: tick_sizes.to_vec(),
+        max_crossed: Duration::from_millis(cfg.max_crossed_ms),
+        clock,
+    })
+}
+
+// Starts the writer on the fast channels
+fn spawn_writer(
+    setup: WriterSetup,
+    fast_trade_rx: mpsc::Receiver<TradeEvent>,
+    fast_orderbook_rx: mpsc::Receiver<OrderbookResult>,
+    shutdown_rx: broadcast::Receiver<()>,
+) -> WriterHandles {
+    let (writer_cmd_tx, writer_cmd_rx) = mpsc::channel(16);
+    let (writer_event_tx, writer_event_rx) = mpsc::channel(16);
+    let handle = tokio::spawn(shm_writer_task(fast_trade_rx, fast_orderbook_rx, shutdown_rx, writer_cmd_rx, writer_event_tx, setup));
+    (writer_cmd_tx, writer_event_rx, handle)
+}
+
+// Asks the writer for a copy of its books, formatting and writing happen in a separate task
+async fn dump_books(
+    writer_cmd_tx: &mpsc::Sender<WriterCommand>,
+    instrument_names: &[String],
+    format: BookDumpFormat,
+    dir: Option<String>,
+) {
+    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+    if writer_cmd_tx.send(WriterCommand::DumpBooks(reply_tx)).await.is_err() {
+        error!("book dump failed: shm writer is gone");
+        return;
+    }
+    let instrument_names = instrument_names.to_vec();
+    tokio::spawn(async move {
+        let Ok(mut dumps) = reply_rx.await else {
+            error!("book dump failed: no reply from the shm writer");
+            return;
+        };
+        for dump in &mut dumps {
+            dump.instrument = instrument_names.get(dump.instrument_idx).cloned().unwrap_or_default();
+        }
+        match tokio::task::spawn_blocking(move || write_dump(&dumps, format, dir.as_deref())).await {
+            Ok(Ok(Some(path))) => info!("books dumped to {}", path.display()),
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => error!("book dump failed: {}", e),
+            Err(e) => error!("book dump failed: {}", e),
+        }
+    });
+}
+
+// Subscribes/unsubscribes the channel delta on the live connection, books of untouched channels are kept
+// `clients` has the main connection first, then one per other subaccount
+async fn reload_channels(
+    config_watcher: &ConfigWatcher,
+    clients: &[DeribitClient],
+    order_trackers: &mut [OrderTracker],
+    channels: &mut Vec<String>,
+    instrument_index: &HashMap<String, usize>,
+    tick_sizes: &[Option<TickSize>],
+    writer_cmd_tx: &mpsc::Sender<WriterCommand>,
+) -> Result<(), DeribitError> {
+    let new_cfg = match config_watcher.reload(instrument_index) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("config reload rejected, keeping the running channels: {}", e);
+            return Ok(());
+        }
+    };
+
+    let diff = ChannelDiff::between(channels, &new_cfg.channels);
+    if diff.is_empty() {
+        info!("config reloaded, channels unchanged (only channels are reloaded, other settings need a restart)");
+        return Ok(());
+    }
+    let missing = books_without_tick_size(&diff.added, instrument_index, tick_sizes);
+    if !missing.is_empty() {
+        error!("config reload rejected, no tick size for books {:?}", missing);
+        return Ok(());
+    }
+    info!("config reloaded: subscribing {:?}, unsubscribing {:?}", diff.added, diff.removed);
+    let client = &clients[0];
+
+    HEALTH.set_expected_channels(&new_cfg.channels, expected_books(&new_cfg.channels, instrument_index));
+    HEALTH.on_unsubscribed(&diff.removed);
+    HEALTH.on_unsubscribed(&diff.added);
+    if !diff.removed.is_empty() {
+        client.unsubscribe(&diff.removed).await?;
+        for instrument in diff.removed_books() {
+            if let Some(&instrument_idx) = instrument_index.get(instrument) {
+                let _ = writer_cmd_tx.send(WriterCommand::ResetBook(instrument_idx)).await;
+            }
+        }
+    }
+    if !diff.added.is_empty() {
+        client.subscribe(&diff.added).await?;
+    }
+    let (private_removed, private_added) = (private_channels(&diff.removed), private_channels(&diff.added));
+    for subaccount_client in &clients[1..] {
+        if !private_removed.is_empty() {
+            subaccount_client.unsubscribe(&private_removed).await?;
+        }
+        if !private_added.is_empty() {
+            subaccount_client.subscribe(&private_added).await?;
+        }
+    }
+    let currencies = currencies_from_channels(channels);
+    *channels = new_cfg.channels;
+
+    let new_currencies = currencies_from_channels(channels);
+    if new_currencies != currencies {
+        for (account_client, order_tracker) in clients.iter().zip(order_trackers.iter_mut()) {
+            request_reconciliation(account_client, order_tracker, &new_currencies).await?;
+        }
+    }
     Ok(())
 }
+
+// Instrument and index of the book channels, the ones /health checks for staleness
+fn expected_books(channels: &[String], instrument_index: &HashMap<String, usize>) -> Vec<(String, usize)> {
+    channels
+        .iter()
+        .filter_map(|channel| book_instrument(channel))
+        .filter_map(|instrument| instrument_index.get(instrument).map(|&idx| (instrument.to_string(), idx)))
+        .collect()
+}
+
+// Tick sizes indexed by the SHM instrument index, None for instruments the exchange did not list
+fn tick_sizes_by_index(
+    instruments: &[InstrumentInfo],
+    instrument_index: &HashMap<String, usize>,
+    nb_instruments: usize,
+) -> Vec<Option<TickSize>> {
+    let mut tick_sizes = vec![None; nb_instruments];
+    for instrument in instruments {
+        let Some(&idx) = instrument_index.get(&instrument.instrument_name) else {
+            continue;
+        };
+        match TickSize::new(instrument.tick_size) {
+            Ok(tick_size) if idx < nb_instruments => tick_sizes[idx] = Some(tick_size),
+            Ok(_) => {}
+            Err(e) => warn!("{}: {}", instrument.instrument_name, e),
+        }
+    }
+    tick_sizes
+}
+
+// Linear for instruments the exchange did not list
+fn contract_types_by_index(
+    instruments: &[InstrumentInfo],
+    instrument_index: &HashMap<String, usize>,
+    nb_instruments: usize,
+) -> Vec<ContractType> {
+    let mut contract_types = vec![ContractType::default(); nb_instruments];
+    for instrument in instruments {
+        if let Some(&idx) = instrument_index.get(&instrument.instrument_name).filter(|&&idx| idx < nb_instruments) {
+            contract_types[idx] = instrument.contract_type;
+        }
+    }
+    contract_types
+}
+
+// A changed tick size rebuilds the book in the new ticks, an instrument no longer listed keeps its last one
+async fn refresh_tick_sizes(
+    instruments: &[InstrumentInfo],
+    instrument_index: &HashMap<String, usize>,
+    tick_sizes: &mut [Option<TickSize>],
+    writer_cmd_tx: &mpsc::Sender<WriterCommand>,
+) {
+    let refreshed = tick_sizes_by_index(instruments, instrument_index, tick_sizes.len());
+    for (instrument_idx, (current, refreshed)) in tick_sizes.iter_mut().zip(refreshed).enumerate() {
+        let Some(refreshed) = refreshed else { continue };
+        if *current != Some(refreshed) {
+            *current = Some(refreshed);
+            let _ = writer_cmd_tx.send(WriterCommand::SetTickSize(instrument_idx, refreshed)).await;
+        }
+    }
+}
+
+fn books_without_tick_size(
+    channels: &[String],
+    instrument_index: &HashMap<String, usize>,
+    tick_sizes: &[Option<TickSize>],
+) -> Vec<String> {
+    channels
+        .iter()
+        .filter_map(|c| book_instrument(c))
+        .filter(|instrument| {
+            instrument_index
+                .get(*instrument)
+                .and_then(|&idx| tick_sizes.get(idx).copied().flatten())
+                .is_none()
+        })
+        .map(|instrument| instrument.to_string())
+        .collect()
+}
+
+// Resubscribing the book channels of an instrument makes the exchange send a new snapshot
+async fn resync_book(
+    client: &DeribitClient,
+    channels: &[String],
+    instrument_index: &HashMap<String, usize>,
+    instrument_idx: usize,
+) -> Result<(), DeribitError> {
+    let book_channels: Vec<String> = channels
+        .iter()
+        .filter(|c| book_instrument(c).and_then(|i| instrument_index.get(i)) == Some(&instrument_idx))
+        .cloned()
+        .collect();
+    if book_channels.is_empty() {
+        return Ok(());
+    }
+    info!("resubscribing {:?} for a new snapshot", book_channels);
+    client.unsubscribe(&book_channels).await?;
+    client.subscribe(&book_channels).await?;
+    Ok(())
+}
+
+// The snapshot comes back through the router to the writer, which applies it in place of the book
+async fn recover_book(
+    client: &DeribitClient,
+    channels: &[String],
+    instrument_index: &HashMap<String, usize>,
+    recovery_depths: &[usize],
+    instrument_idx: usize,
+) -> Result<(), DeribitError> {
+    let subscribed = channels
+        .iter()
+        .filter_map(|c| book_instrument(c))
+        .find(|i| instrument_index.get(*i) == Some(&instrument_idx));
+    let Some(instrument) = subscribed else {
+        return Ok(());
+    };
+    info!("requesting a snapshot of {} after a sequence gap", instrument);
+    client.get_order_book(instrument, recovery_depths[instrument_idx], instrument_idx).await?;
+    Ok(())
+}
+
+// Authenticates with the account key, then scopes the session to `subaccount_id` when given. Returns the
+// auth result of the session, the scoped one for a subaccount
+async fn authenticate(
+    client: &DeribitClient,
+    receiver: &mut DeribitReceiver,
+    cfg: &Config,
+    subaccount_id: Option<u64>,
+) -> Result<AuthResult, DeribitError> {
+    let auth_id = match cfg.auth_method {
+        AuthMethod::ClientCredentials => client.authenticate(cfg.key.expose(), cfg.secret.expose()).await?,
+        AuthMethod::ClientSignature => client.authenticate_with_signature(cfg.key.expose(), cfg.secret.expose()).await?,
+    };
+    let auth_result = receiver.wait_for_auth_response(auth_id).await?;
+    info!("authentication successful to {} ({:?})", cfg.url, cfg.auth_method);
+
+    if let Some(subject_id) = subaccount_id {
+        let exchange_id = client.exchange_token(&auth_result.refresh_token, subject_id).await?;
+        let scoped_auth = receiver.wait_for_auth_response(exchange_id).await?;
+        info!("session scoped to subaccount {}", subject_id);
+        return Ok(scoped_auth);
+    }
+    Ok(auth_result)
+}
+
+// Refresh token of a connection, the access token is renewed before it expires
+struct Session {
+    refresh_token: String,
+    refresh_at: tokio::time::Instant,
+}
+
+impl Session {
+    fn new(auth: &AuthResult) -> Self {
+        Self {
+            refresh_token: auth.refresh_token.clone(),
+            refresh_at: tokio::time::Instant::now() + Duration::from_secs(auth.expires_in * 4 / 5),
+        }
+    }
+}
+
+// Connection of a subaccount other than the first one, subscribed to the user.* channels only
+async fn connect_subaccount(
+    cfg: &Config,
+    subaccount_id: u64,
+    channels: &[String],
+    shutdown_tx: broadcast::Sender<()>,
+) -> Result<(DeribitConnection, DeribitReceiver, AuthResult), DeribitError> {
+    let mut connection = DeribitConnection::connect_private(&cfg.url, shutdown_tx).await?;
+    let client = connection.client();
+    let mut receiver = connection.take_receiver().expect("Failed to get receiver");
+    let auth = authenticate(&client, &mut receiver, cfg, Some(subaccount_id)).await?;
+
+    let private = private_channels(channels);
+    if !private.is_empty() {
+        let sub_id = client.subscribe(&private).await?;
+        let sub_result = receiver.wait_for_subscription_response(sub_id).await?;
+        info!("subaccount {} subscribed to channels: {:?}", subaccount_id, sub_result.channels);
+    }
+    Ok((connection, receiver, auth))
+}
+
+fn private_channels(channels: &[String]) -> Vec<String> {
+    channels.iter().filter(|channel| channel.starts_with("user.")).cloned().collect()
+}
+
+// Name of the account of each connection in the logs, the main connection first
+fn account_names(subaccount_ids: &[u64]) -> Vec<String> {
+    if subaccount_ids.is_empty() {
+        return vec!["main account".to_string()];
+    }
+    subaccount_ids.iter().map(|id| format!("subaccount {}", id)).collect()
+}
+
+// Tags the control messages of a connection with its account, one loop serves them all
+fn forward_control(account: usize, receiver: DeribitReceiver, tx: mpsc::Sender<(usize, ControlMessage)>) {
+    let mut control_rx = receiver.into_control_rx();
+    tokio::spawn(async move {
+        while let Some(control_msg) = control_rx.recv().await {
+            if tx.send((account, control_msg)).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+// Asks for the exchange view of our orders and positions, the replies come back as control messages.
+// Runs when the session starts and when a reload changes the currencies. The feed handler does not reconnect:
+// a new connection is a new process, whose empty tracker is seeded by the first replies.
+async fn request_reconciliation(client: &DeribitClient, order_tracker: &mut OrderTracker, currencies: &[String]) -> Result<(), DeribitError> {
+    for currency in currencies {
+        let slot = order_tracker.request_slot(currency);
+        client.get_open_orders_by_currency(currency, slot).await?;
+        client.get_positions(currency, slot).await?;
+    }
+    Ok(())
+}
+
+// `account` indexes the connections, 0 being the main one
+fn handle_control_message(control_msg: ControlMessage, account: usize, account_name: &str, order_tracker: &mut OrderTracker) {
+    match control_msg {
+        ControlMessage::UserOrders(orders) => {
+            for order in &orders {
+                order_tracker.on_order(order);
+            }
+        }
+        ControlMessage::UserTrades(trades) => {
+            for trade in &trades {
+                order_tracker.on_trade(trade);
+            }
+        }
+        ControlMessage::OpenOrders { id, orders } => {
+            let Some(currency) = order_tracker.requested_currency(id - request_id::OPEN_ORDERS.start).map(str::to_string) else {
+                warn!("open orders reply with unexpected id {}", id);
+                return;
+            };
+            let mismatches = order_tracker.reconcile_open_orders(&currency, &orders);
+            for mismatch in &mismatches {
+                warn!("order reconciliation {} {}: {:?}", account_name, currency, mismatch);
+            }
+            info!("orders reconciled for {} {}: {} open, {} mismatches", account_name, currency, orders.len(), mismatches.len());
+        }
+        ControlMessage::Positions { id, positions } => {
+            let Some(currency) = order_tracker.requested_currency(id - request_id::POSITIONS.start).map(str::to_string) else {
+                warn!("positions reply with unexpected id {}", id);
+                return;
+            };
+            let mismatches = order_tracker.reconcile_positions(&currency, &positions);
+            for mismatch in &mismatches {
+                warn!("position reconciliation {} {}: {:?}", account_name, currency, mismatch);
+            }
+            info!("positions reconciled for {} {}: {} positions, {} mismatches", account_name, currency, positions.len(), mismatches.len());
+        }
+        // confirmations of the channels added by a reload
+        ControlMessage::SubscriptionResult { id: request_id::SUBSCRIBE, result: Ok(result) } => {
+            info!("{} subscribed to channels: {:?}", account_name, result.channels);
+            if account == 0 {
+                HEALTH.on_subscribed(&result.channels);
+            }
+        }
+        other => info!("Control message ({}): {:?}", account_name, other),
+    }
+}
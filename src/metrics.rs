@@ -0,0 +1,300 @@
+use std::fmt::Write as _;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::{info, warn};
+use crate::health::HEALTH;
+use crate::journal::now_ns;
+
+// Counters of the feed handler, written with relaxed atomics from the hot path and read by the
+// HTTP endpoint in the Prometheus text format. Rates are left to Prometheus, rate() of the totals.
+pub static METRICS: Metrics = Metrics::new();
+
+// Upper bounds of the latency buckets, in ns
+const LATENCY_BUCKETS_NS: [u64; 12] = [500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 10_000_000];
+
+pub struct Histogram {
+    // not cumulative, the last one is +Inf
+    buckets: [AtomicU64; LATENCY_BUCKETS_NS.len() + 1],
+    sum_ns: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self { buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS_NS.len() + 1], sum_ns: AtomicU64::new(0) }
+    }
+
+    #[inline]
+    pub fn observe(&self, elapsed: Duration) {
+        let ns = elapsed.as_nanos() as u64;
+        let bucket = LATENCY_BUCKETS_NS.partition_point(|&bound| bound < ns);
+        self.buckets[bucket].fetch_add(1, Relaxed);
+        self.sum_ns.fetch_add(ns, Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        let mut count = 0;
+        for (n, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Relaxed);
+            let bound = LATENCY_BUCKETS_NS.get(n).map_or("+Inf".to_string(), |bound| (*bound as f64 / 1e9).to_string());
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_sum {}\n{}_count {}", name, self.sum_ns.load(Relaxed) as f64 / 1e9, name, count);
+    }
+}
+
+// Queues whose try_send drops what does not fit
+#[derive(Debug, Clone, Copy)]
+pub enum Queue {
+    Trades,
+    Orderbooks,
+    Parsed,
+    Control,
+    WriterEvents,
+}
+
+const QUEUES: [(Queue, &str); 5] = [
+    (Queue::Trades, "trades"),
+    (Queue::Orderbooks, "orderbooks"),
+    (Queue::Parsed, "parsed"),
+    (Queue::Control, "control"),
+    (Queue::WriterEvents, "writer_events"),
+];
+
+#[derive(Default)]
+struct InstrumentCounters {
+    book_updates: AtomicU64,
+    trades: AtomicU64,
+    gaps: AtomicU64,
+    // wall clock of the last update applied to the book, for the staleness of /health
+    last_applied_ns: AtomicU64,
+}
+
+// One of the counters of an instrument, to render them all the same way
+type InstrumentCounter = fn(&InstrumentCounters) -> &AtomicU64;
+
+pub struct Metrics {
+    pub messages: AtomicU64,
+    pub parse_errors: AtomicU64,
+    pub connections: AtomicU64,
+    // errors returned by the sinks, most of them are not logged
+    pub sink_errors: AtomicU64,
+    // wall clock of the last websocket frame, 0 before the first one
+    last_message_ns: AtomicU64,
+    pub parse_latency: Histogram,
+    pub write_latency: Histogram,
+    dropped: [AtomicU64; QUEUES.len()],
+    // by instrument index, set once the instruments are known
+    instruments: OnceLock<Vec<(String, InstrumentCounters)>>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            messages: AtomicU64::new(0),
+            parse_errors: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            sink_errors: AtomicU64::new(0),
+            last_message_ns: AtomicU64::new(0),
+            parse_latency: Histogram::new(),
+            write_latency: Histogram::new(),
+            dropped: [const { AtomicU64::new(0) }; QUEUES.len()],
+            instruments: OnceLock::new(),
+        }
+    }
+
+    // `names` by instrument index, the per instrument counters are not kept before
+    pub fn set_instruments(&self, names: &[String]) {
+        let _ = self.instruments.set(names.iter().map(|name| (name.clone(), InstrumentCounters::default())).collect());
+    }
+
+    #[inline]
+    fn instrument(&self, instrument_idx: usize) -> Option<&InstrumentCounters> {
+        self.instruments.get().and_then(|instruments| instruments.get(instrument_idx)).map(|(_, counters)| counters)
+    }
+
+    #[inline]
+    pub fn on_message(&self) {
+        self.messages.fetch_add(1, Relaxed);
+        self.last_message_ns.store(now_ns(), Relaxed);
+    }
+
+    #[inline]
+    pub fn on_book_update(&self, instrument_idx: usize) {
+        if let Some(counters) = self.instrument(instrument_idx) {
+            counters.book_updates.fetch_add(1, Relaxed);
+        }
+    }
+
+    // Received is not enough: a book stuck on a gap or dropping every update is stale
+    #[inline]
+    pub fn on_book_applied(&self, instrument_idx: usize) {
+        if let Some(counters) = self.instrument(instrument_idx) {
+            counters.last_applied_ns.store(now_ns(), Relaxed);
+        }
+    }
+
+    #[inline]
+    pub fn on_trade(&self, instrument_idx: usize) {
+        if let Some(counters) = self.instrument(instrument_idx) {
+            counters.trades.fetch_add(1, Relaxed);
+        }
+    }
+
+    pub fn on_gap(&self, instrument_idx: usize) {
+        if let Some(counters) = self.instrument(instrument_idx) {
+            counters.gaps.fetch_add(1, Relaxed);
+        }
+    }
+
+    // 0 before the first update, or when the instruments are not set
+    pub fn last_book_applied_ns(&self, instrument_idx: usize) -> u64 {
+        self.instrument(instrument_idx).map_or(0, |counters| counters.last_applied_ns.load(Relaxed))
+    }
+
+    // Counts what try_send dropped, a closed queue is not a drop
+    #[inline]
+    pub fn on_send<T>(&self, queue: Queue, result: Result<(), TrySendError<T>>) {
+        if let Err(TrySendError::Full(_)) = result {
+            self.dropped[queue as usize].fetch_add(1, Relaxed);
+        }
+    }
+
+    // Seconds since the last websocket frame, None before the first one
+    pub fn last_message_age(&self) -> Option<f64> {
+        let last = self.last_message_ns.load(Relaxed);
+        (last > 0).then(|| now_ns().saturating_sub(last) as f64 / 1e9)
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::with_capacity(4096);
+        let counters = [
+            ("haiku_fh_messages_total", "Websocket frames received", &self.messages),
+            ("haiku_fh_parse_errors_total", "Websocket frames that could not be parsed", &self.parse_errors),
+            ("haiku_fh_connections_total", "Market data connections opened to the exchange, the feed handler does not reconnect", &self.connections),
+            ("haiku_fh_sink_errors_total", "Books, trades and statuses a sink failed to write", &self.sink_errors),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, counter.load(Relaxed));
+        }
+        if let Some(age) = self.last_message_age() {
+            let name = "haiku_fh_last_message_age_seconds";
+            let _ = writeln!(out, "# HELP {} Time since the last websocket frame\n# TYPE {} gauge\n{} {}", name, name, name, age);
+        }
+        self.parse_latency.render(&mut out, "haiku_fh_parse_latency_seconds", "Parsing time of a websocket frame");
+        self.write_latency.render(&mut out, "haiku_fh_write_latency_seconds", "Time to apply and publish a book update");
+
+        let name = "haiku_fh_dropped_total";
+        let _ = writeln!(out, "# HELP {} Messages dropped because the queue was full\n# TYPE {} counter", name, name);
+        for (queue, label) in QUEUES {
+            let _ = writeln!(out, "{}{{queue=\"{}\"}} {}", name, label, self.dropped[queue as usize].load(Relaxed));
+        }
+
+        let instruments = self.instruments.get().map_or(&[][..], |instruments| instruments.as_slice());
+        let by_instrument: [(&str, &str, InstrumentCounter); 3] = [
+            ("haiku_fh_book_updates_total", "Book updates received", |counters| &counters.book_updates),
+            ("haiku_fh_trades_total", "Trades received", |counters| &counters.trades),
+            ("haiku_fh_sequence_gaps_total", "Sequence gaps that reset the book", |counters| &counters.gaps),
+        ];
+        for (name, help, counter) in by_instrument {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+            for (instrument, counters) in instruments.iter().filter(|(instrument, _)| !instrument.is_empty()) {
+                let _ = writeln!(out, "{}{{instrument=\"{}\"}} {}", name, instrument, counter(counters).load(Relaxed));
+            }
+        }
+        out
+    }
+}
+
+// (status, content type, body) of a GET
+fn route(path: &str) -> (&'static str, &'static str, String) {
+    match path {
+        "/metrics" => ("200 OK", "text/plain; version=0.0.4", METRICS.render()),
+        "/health" | "/ready" => {
+            let report = HEALTH.report();
+            let ok = if path == "/health" { report.is_healthy() } else { report.is_ready() };
+            (if ok { "200 OK" } else { "503 Service Unavailable" }, "application/json", report.to_json())
+        }
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    }
+}
+
+// A client that does not send its request or read the answer in time is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Only what a scraper needs: one GET per connection, the answer closes it
+async fn serve_request(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut request = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() > 16 * 1024 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut words = request.split_whitespace();
+    let (status, content_type, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some(path)) => route(path.split('?').next().unwrap_or(path)),
+        _ => ("405 Method Not Allowed", "text/plain", "only GET\n".to_string()),
+    };
+    let head = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, content_type, body.len());
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+pub async fn serve_http(listener: TcpListener) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("http endpoint on {}", addr);
+    }
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    match tokio::time::timeout(REQUEST_TIMEOUT, serve_request(stream)).await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => warn!("http: request failed: {}", e),
+                        Err(_) => warn!("http: request timed out after {:?}", REQUEST_TIMEOUT),
+                    }
+                });
+            }
+            Err(e) => warn!("http: connection not accepted: {}", e),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.set_instruments(&["BTC-PERPETUAL".to_string(), String::new()]);
+        metrics.on_message();
+        metrics.on_book_update(0);
+        metrics.on_book_update(0);
+        metrics.on_book_update(7);
+        metrics.parse_latency.observe(Duration::from_nanos(3_000));
+        metrics.parse_latency.observe(Duration::from_millis(20));
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        metrics.on_send(Queue::Orderbooks, tx.try_send(1));
+        metrics.on_send(Queue::Orderbooks, tx.try_send(2));
+
+        let text = metrics.render();
+        assert!(text.contains("haiku_fh_messages_total 1\n"));
+        assert!(text.contains("haiku_fh_book_updates_total{instrument=\"BTC-PERPETUAL\"} 2\n"));
+        assert!(text.contains("haiku_fh_parse_latency_seconds_bucket{le=\"0.0000025\"} 0\n"));
+        assert!(text.contains("haiku_fh_parse_latency_seconds_bucket{le=\"0.000005\"} 1\n"));
+        assert!(text.contains("haiku_fh_parse_latency_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("haiku_fh_dropped_total{queue=\"orderbooks\"} 1\n"));
+        assert!(text.contains("haiku_fh_last_message_age_seconds "));
+    }
+}
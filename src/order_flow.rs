@@ -0,0 +1,355 @@
+use serde::Deserialize;
+use crate::orderbook_management::OrderbookManagerV2;
+use crate::parsing::parsing_fast_orderbook::OrderbookUpdateDataRaw;
+use crate::parsing::parsing_orderbook::OrderbookAction;
+use crate::shm_slots::ShmSlots;
+
+// windows of a slot record, the config can use fewer
+pub const MAX_WINDOWS: usize = 4;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OrderFlowConfig {
+    // rolling windows, multiples of bucket_ms
+    #[serde(default = "default_windows_ms")]
+    pub windows_ms: Vec<u64>,
+    // granularity of the windows, a window covers the current bucket and the previous ones
+    #[serde(default = "default_bucket_ms")]
+    pub bucket_ms: u64,
+    #[serde(default = "default_shm_name")]
+    pub shm_name: String,
+}
+
+fn default_windows_ms() -> Vec<u64> {
+    vec![1_000, 10_000, 60_000]
+}
+
+fn default_bucket_ms() -> u64 {
+    100
+}
+
+fn default_shm_name() -> String {
+    "haiku_fh_order_flow".to_string()
+}
+
+// Order flow of one delta, or summed over a window. Sizes are in the book units.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FlowEvent {
+    // size added/removed at or through the touch of the book before the delta
+    pub bid_added: f64,
+    pub bid_removed: f64,
+    pub ask_added: f64,
+    pub ask_removed: f64,
+    // new/change/delete entries, at any depth
+    pub level_changes: u64,
+    // change of the best bid queue minus change of the best ask queue (Cont, Kukanov, Stoikov)
+    pub ofi: f64,
+}
+
+impl FlowEvent {
+    #[inline]
+    fn add(&mut self, other: &FlowEvent) {
+        self.bid_added += other.bid_added;
+        self.bid_removed += other.bid_removed;
+        self.ask_added += other.ask_added;
+        self.ask_removed += other.ask_removed;
+        self.level_changes += other.level_changes;
+        self.ofi += other.ofi;
+    }
+
+    #[inline]
+    fn sub(&mut self, other: &FlowEvent) {
+        self.bid_added -= other.bid_added;
+        self.bid_removed -= other.bid_removed;
+        self.ask_added -= other.ask_added;
+        self.ask_removed -= other.ask_removed;
+        self.level_changes -= other.level_changes;
+        self.ofi -= other.ofi;
+    }
+}
+
+// What a delta does to the book, read before it is applied as the book keeps no history
+pub struct PendingFlow {
+    best_bid: Option<(i64, f32)>,
+    best_ask: Option<(i64, f32)>,
+    event: FlowEvent,
+}
+
+impl PendingFlow {
+    pub fn observe(book: &OrderbookManagerV2, update: &OrderbookUpdateDataRaw) -> Self {
+        let best_bid = book.bid_levels().next();
+        let best_ask = book.ask_levels().next();
+        let mut event = FlowEvent {
+            level_changes: (update.bid_updates.len() + update.ask_updates.len()) as u64,
+            ..FlowEvent::default()
+        };
+
+        let tick_size = book.tick_size();
+        for (levels, is_bid) in [(&update.bid_updates, true), (&update.ask_updates, false)] {
+            let touch = if is_bid { best_bid } else { best_ask };
+            for level in levels.iter() {
+                // the book refuses the whole update
+                let Ok(price) = tick_size.to_ticks(level.price) else { continue };
+                let ticks = price.ticks;
+                let at_touch = match touch {
+                    Some((best, _)) if is_bid => ticks >= best,
+                    Some((best, _)) => ticks <= best,
+                    None => true,
+                };
+                if !at_touch {
+                    continue;
+                }
+                let size = match level.action {
+                    OrderbookAction::Delete => 0.0,
+                    _ => level.size.max(0.0),
+                };
+                let change = (size - book.level_size(ticks, is_bid)) as f64;
+                let (added, removed) = if is_bid {
+                    (&mut event.bid_added, &mut event.bid_removed)
+                } else {
+                    (&mut event.ask_added, &mut event.ask_removed)
+                };
+                if change > 0.0 {
+                    *added += change;
+                } else {
+                    *removed -= change;
+                }
+            }
+        }
+
+        Self { best_bid, best_ask, event }
+    }
+
+    // Once the delta is applied, the OFI needs the new touch
+    pub fn complete(self, book: &OrderbookManagerV2) -> FlowEvent {
+        let mut event = self.event;
+        if let (Some((old, old_size)), Some((new, new_size))) = (self.best_bid, book.bid_levels().next()) {
+            if new >= old {
+                event.ofi += new_size as f64;
+            }
+            if new <= old {
+                event.ofi -= old_size as f64;
+            }
+        }
+        if let (Some((old, old_size)), Some((new, new_size))) = (self.best_ask, book.ask_levels().next()) {
+            if new <= old {
+                event.ofi -= new_size as f64;
+            }
+            if new >= old {
+                event.ofi += old_size as f64;
+            }
+        }
+        event
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[repr(C)]
+pub struct WindowFlow {
+    pub window_ms: u64,
+    pub level_changes: u64,
+    pub bid_added: f64,
+    pub bid_removed: f64,
+    pub ask_added: f64,
+    pub ask_removed: f64,
+    pub ofi: f64,
+}
+
+// Record of the order flow slot, one per instrument index, as of the exchange timestamp of the last delta
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[repr(C)]
+pub struct OrderFlowStats {
+    pub timestamp: u64,
+    pub change_id: u64,
+    pub nb_windows: u64,
+    pub windows: [WindowFlow; MAX_WINDOWS],
+}
+
+// Ring of buckets covering the largest window, with a running sum per window.
+// The f64 sums drift as buckets are added and taken off, they are recomputed from the ring once per turn of it.
+struct RollingFlow {
+    buckets: Vec<FlowEvent>,
+    window_buckets: Vec<usize>,
+    sums: Vec<FlowEvent>,
+    current: Option<u64>,
+    // buckets moved through since the sums were last recomputed
+    since_resum: u64,
+}
+
+impl RollingFlow {
+    fn new(window_buckets: Vec<usize>) -> Self {
+        let nb_buckets = window_buckets.iter().copied().max().unwrap_or(1);
+        Self {
+            buckets: vec![FlowEvent::default(); nb_buckets],
+            sums: vec![FlowEvent::default(); window_buckets.len()],
+            window_buckets,
+            current: None,
+            since_resum: 0,
+        }
+    }
+
+    // Moves to `bucket`, what falls out of each window is taken off its sum.
+    // Late timestamps are counted in the current bucket.
+    fn advance(&mut self, bucket: u64) {
+        let current = match self.current {
+            Some(current) if bucket <= current => return,
+            Some(current) => current,
+            None => {
+                self.current = Some(bucket);
+                return;
+            }
+        };
+        let nb_buckets = self.buckets.len() as u64;
+        if bucket - current >= nb_buckets {
+            self.buckets.fill(FlowEvent::default());
+            self.sums.fill(FlowEvent::default());
+            self.since_resum = 0;
+        } else {
+            for b in current + 1..=bucket {
+                for (sum, &len) in self.sums.iter_mut().zip(&self.window_buckets) {
+                    if let Some(leaving) = b.checked_sub(len as u64) {
+                        sum.sub(&self.buckets[(leaving % nb_buckets) as usize]);
+                    }
+                }
+                self.buckets[(b % nb_buckets) as usize] = FlowEvent::default();
+            }
+            self.since_resum += bucket - current;
+        }
+        self.current = Some(bucket);
+        if self.since_resum >= nb_buckets {
+            self.resum(bucket);
+            self.since_resum = 0;
+        }
+    }
+
+    // Sums of the last buckets of each window ending at `current`, buckets before the first one are empty
+    fn resum(&mut self, current: u64) {
+        let nb_buckets = self.buckets.len() as u64;
+        for (sum, &len) in self.sums.iter_mut().zip(&self.window_buckets) {
+            *sum = FlowEvent::default();
+            for back in 0..len as u64 {
+                sum.add(&self.buckets[((current + nb_buckets - back) % nb_buckets) as usize]);
+            }
+        }
+    }
+
+    fn record(&mut self, bucket: u64, event: &FlowEvent) {
+        self.advance(bucket);
+        let nb_buckets = self.buckets.len() as u64;
+        let current = self.current.unwrap_or(bucket);
+        self.buckets[(current % nb_buckets) as usize].add(event);
+        for sum in &mut self.sums {
+            sum.add(event);
+        }
+    }
+}
+
+// Rolls the flow of every delta into the windows of its instrument and writes them to its slot
+pub struct OrderFlowPublisher {
+    config: OrderFlowConfig,
+    flows: Vec<RollingFlow>,
+    slots: ShmSlots<OrderFlowStats>,
+}
+
+impl OrderFlowPublisher {
+    pub fn new(config: OrderFlowConfig, nb_instruments: usize) -> std::io::Result<Self> {
+        let slots = ShmSlots::create(&config.shm_name, nb_instruments)?;
+        let window_buckets: Vec<usize> = config.windows_ms.iter().map(|w| (w / config.bucket_ms) as usize).collect();
+        let flows = (0..nb_instruments).map(|_| RollingFlow::new(window_buckets.clone())).collect();
+        Ok(Self { config, flows, slots })
+    }
+
+    // `timestamp` is the exchange one in ms, so windows do not depend on when we process the delta
+    pub fn record(&mut self, instrument_idx: usize, timestamp: u64, change_id: u64, event: &FlowEvent) {
+        let Some(flow) = self.flows.get_mut(instrument_idx) else {
+            return;
+        };
+        flow.record(timestamp / self.config.bucket_ms, event);
+
+        let mut stats = OrderFlowStats {
+            timestamp,
+            change_id,
+            nb_windows: flow.sums.len() as u64,
+            ..OrderFlowStats::default()
+        };
+        for ((window, sum), &window_ms) in stats.windows.iter_mut().zip(&flow.sums).zip(&self.config.windows_ms) {
+            *window = WindowFlow {
+                window_ms,
+                level_changes: sum.level_changes,
+                bid_added: sum.bid_added,
+                bid_removed: sum.bid_removed,
+                ask_added: sum.ask_added,
+                ask_removed: sum.ask_removed,
+                ofi: sum.ofi,
+            };
+        }
+        self.slots.write(instrument_idx, &stats);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::parsing::parsing_orderbook::OrderbookLevel;
+    use crate::price::{Decimal, TickSize};
+
+    fn level(action: OrderbookAction, price: f64, size: f32) -> OrderbookLevel {
+        OrderbookLevel { action, price: Decimal::from_f64(price).unwrap(), size }
+    }
+
+    #[test]
+    fn test_delta_flow() {
+        let mut book = OrderbookManagerV2::new(10, TickSize::new(0.5).unwrap());
+        let mut snapshot = OrderbookUpdateDataRaw::new();
+        snapshot.add_bid(level(OrderbookAction::New, 100.0, 3.0));
+        snapshot.add_bid(level(OrderbookAction::New, 99.5, 1.0));
+        snapshot.add_ask(level(OrderbookAction::New, 101.0, 2.0));
+        book.apply_snapshot(snapshot, 1, Instant::now()).unwrap();
+
+        // bid touch grows 3 -> 5, a deeper bid is ignored, the ask touch is taken out
+        let mut delta = OrderbookUpdateDataRaw::new();
+        delta.set_prev_change_id(1);
+        delta.add_bid(level(OrderbookAction::Change, 100.0, 5.0));
+        delta.add_bid(level(OrderbookAction::Change, 99.5, 4.0));
+        delta.add_ask(level(OrderbookAction::Delete, 101.0, 0.0));
+        delta.add_ask(level(OrderbookAction::New, 101.5, 1.0));
+        let pending = PendingFlow::observe(&book, &delta);
+        book.apply_update(delta, 2, Instant::now()).unwrap();
+        let event = pending.complete(&book);
+
+        assert_eq!(event.level_changes, 4);
+        assert_eq!((event.bid_added, event.bid_removed), (2.0, 0.0));
+        assert_eq!((event.ask_added, event.ask_removed), (0.0, 2.0));
+        // +2 on the bid queue, the ask moved away: +2 for the queue that left
+        assert_eq!(event.ofi, 4.0);
+    }
+
+    #[test]
+    fn test_rolling_windows() {
+        let mut flow = RollingFlow::new(vec![1, 3]);
+        let event = FlowEvent { level_changes: 1, ofi: 1.0, ..FlowEvent::default() };
+        flow.record(10, &event);
+        flow.record(11, &event);
+        flow.record(11, &event);
+        assert_eq!((flow.sums[0].level_changes, flow.sums[1].level_changes), (2, 3));
+
+        // bucket 10 leaves the 3 buckets window at 13
+        flow.record(13, &event);
+        assert_eq!((flow.sums[0].level_changes, flow.sums[1].level_changes), (1, 3));
+        assert_eq!(flow.sums[1].ofi, 3.0);
+
+        flow.record(100, &event);
+        assert_eq!((flow.sums[0].level_changes, flow.sums[1].level_changes), (1, 1));
+    }
+
+    #[test]
+    fn test_sums_do_not_drift() {
+        let mut flow = RollingFlow::new(vec![2]);
+        flow.record(0, &FlowEvent { ofi: 1e16, ..FlowEvent::default() });
+        // lost in the running sum: 1e16 + 1 == 1e16
+        flow.record(1, &FlowEvent { ofi: 1.0, ..FlowEvent::default() });
+        flow.record(2, &FlowEvent::default());
+        assert_eq!(flow.sums[0].ofi, 1.0);
+    }
+}
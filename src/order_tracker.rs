@@ -0,0 +1,447 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::str::FromStr;
+use crate::deribit_helper::request_id;
+use crate::parsing::parsing_user::{UserOrder, UserPosition, UserTrade};
+
+// Reconciliation requests in flight at most, the ids cycle through their range
+const REQUEST_SLOTS: u64 = request_id::OPEN_ORDERS.end - request_id::OPEN_ORDERS.start;
+
+// amounts are exchanged as decimals, anything below this is considered equal
+const AMOUNT_EPSILON: f64 = 1e-9;
+
+// trade ids remembered to drop duplicated user.trades notifications, the oldest are forgotten first
+const SEEN_TRADES: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderStatus {
+    Open,
+    Untriggered,
+    Filled,
+    Cancelled,
+    Rejected,
+}
+
+impl FromStr for OrderStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(Self::Open),
+            "untriggered" => Ok(Self::Untriggered),
+            "filled" => Ok(Self::Filled),
+            "cancelled" => Ok(Self::Cancelled),
+            "rejected" => Ok(Self::Rejected),
+            other => Err(format!("unknown order state {}", other)),
+        }
+    }
+}
+
+impl OrderStatus {
+    #[inline]
+    pub fn is_live(&self) -> bool {
+        matches!(self, Self::Open | Self::Untriggered)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackedOrder {
+    pub order_id: String,
+    pub instrument_name: String,
+    pub direction: i8,
+    pub status: OrderStatus,
+    pub price: f64,
+    pub amount: f64,
+    pub filled_amount: f64,
+    pub average_price: f64,
+    pub last_update_timestamp: u64,
+}
+
+impl TrackedOrder {
+    fn from_user_order(order: &UserOrder) -> Option<Self> {
+        Some(Self {
+            order_id: order.order_id.clone(),
+            instrument_name: order.instrument_name.clone(),
+            direction: order.direction,
+            status: order.order_state.parse().ok()?,
+            price: order.price,
+            amount: order.amount,
+            filled_amount: order.filled_amount,
+            average_price: order.average_price,
+            last_update_timestamp: order.last_update_timestamp,
+        })
+    }
+
+    #[inline]
+    pub fn remaining(&self) -> f64 {
+        self.amount - self.filled_amount
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Position {
+    pub size: f64, // signed, negative when short
+    pub average_price: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mismatch {
+    // we believe the order is live, the exchange does not list it
+    MissingOnExchange { order_id: String },
+    // the exchange lists a live order we never saw
+    UnknownOrder { order_id: String },
+    OrderDiffers { order_id: String, field: &'static str, local: f64, exchange: f64 },
+    // the exchange updated the order after the last notification we applied, some were missed
+    StaleOrder { order_id: String, local_timestamp: u64, exchange_timestamp: u64 },
+    PositionDiffers { instrument_name: String, local: f64, exchange: f64 },
+}
+
+// Our own orders and positions, built from user.orders / user.trades.
+// The exchange is the source of truth: reconciliation reports the differences then adopts its state.
+// The first reconciliation of a currency only seeds the tracker, which knows nothing from before the session.
+#[derive(Debug, Default)]
+pub struct OrderTracker {
+    orders: HashMap<String, TrackedOrder>,
+    positions: HashMap<String, Position>,
+    seen_trades: HashSet<String>,
+    // same ids by arrival, to forget the oldest past SEEN_TRADES
+    seen_order: VecDeque<String>,
+    // currencies whose orders / positions were seeded from the exchange
+    seeded_orders: HashSet<String>,
+    seeded_positions: HashSet<String>,
+    // currency of the reconciliation requests by slot, replies are matched by name so a reload
+    // changing the currencies while requests are in flight does not misroute them
+    requests: HashMap<u64, String>,
+    next_request: u64,
+}
+
+// Currency used by the private/get_*_by_currency endpoints, e.g. BTC-PERPETUAL -> BTC, ETH_USDC-PERPETUAL -> USDC
+pub fn instrument_currency(instrument_name: &str) -> &str {
+    let base = instrument_name.split('-').next().unwrap_or(instrument_name);
+    match base.split_once('_') {
+        Some((_, settlement)) => settlement,
+        None => base,
+    }
+}
+
+// Currencies to reconcile for the private channels we subscribe to,
+// user.orders.BTC-PERPETUAL.raw as well as user.orders.future.BTC.raw
+pub fn currencies_from_channels(channels: &[String]) -> Vec<String> {
+    let mut currencies: Vec<String> = Vec::new();
+    for channel in channels {
+        let parts: Vec<&str> = channel.split('.').collect();
+        let currency = match parts.as_slice() {
+            ["user", "orders" | "trades", instrument, _interval] => instrument_currency(instrument),
+            ["user", "orders" | "trades", _kind, currency, _interval] => *currency,
+            _ => continue,
+        };
+        if currency != "any" && !currencies.iter().any(|c| c == currency) {
+            currencies.push(currency.to_string());
+        }
+    }
+    currencies
+}
+
+impl OrderTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Slot of the request ids (start of OPEN_ORDERS / POSITIONS + slot) to reconcile `currency`
+    pub fn request_slot(&mut self, currency: &str) -> u64 {
+        let slot = self.next_request;
+        self.next_request = (self.next_request + 1) % REQUEST_SLOTS;
+        self.requests.insert(slot, currency.to_string());
+        slot
+    }
+
+    pub fn requested_currency(&self, slot: u64) -> Option<&str> {
+        self.requests.get(&slot).map(String::as_str)
+    }
+
+    pub fn order(&self, order_id: &str) -> Option<&TrackedOrder> {
+        self.orders.get(order_id)
+    }
+
+    pub fn open_orders(&self) -> impl Iterator<Item = &TrackedOrder> {
+        self.orders.values().filter(|o| o.status.is_live())
+    }
+
+    pub fn position(&self, instrument_name: &str) -> Option<&Position> {
+        self.positions.get(instrument_name)
+    }
+
+    pub fn on_order(&mut self, order: &UserOrder) {
+        let Some(tracked) = TrackedOrder::from_user_order(order) else {
+            return;
+        };
+        match self.orders.get_mut(&order.order_id) {
+            // notifications can be reordered with the reconciliation replies, keep the latest
+            Some(existing) if existing.last_update_timestamp > tracked.last_update_timestamp => {}
+            Some(existing) => *existing = tracked,
+            None => {
+                self.orders.insert(order.order_id.clone(), tracked);
+            }
+        }
+    }
+
+    pub fn on_trade(&mut self, trade: &UserTrade) {
+        if !self.seen_trades.insert(trade.trade_id.clone()) {
+            return;
+        }
+        self.seen_order.push_back(trade.trade_id.clone());
+        if self.seen_order.len() > SEEN_TRADES
+            && let Some(oldest) = self.seen_order.pop_front()
+        {
+            self.seen_trades.remove(&oldest);
+        }
+        let position = self.positions.entry(trade.instrument_name.clone()).or_default();
+        let signed_amount = trade.direction as f64 * trade.amount;
+        let new_size = position.size + signed_amount;
+
+        if position.size == 0.0 || position.size.signum() == signed_amount.signum() {
+            // increasing the position
+            let total = position.size.abs() + trade.amount;
+            position.average_price =
+                (position.size.abs() * position.average_price + trade.amount * trade.price) / total;
+        } else if new_size.abs() > AMOUNT_EPSILON && new_size.signum() != position.size.signum() {
+            // flipped side, the remainder was opened at the trade price
+            position.average_price = trade.price;
+        } else if new_size.abs() <= AMOUNT_EPSILON {
+            position.average_price = 0.0;
+        }
+        position.size = if new_size.abs() <= AMOUNT_EPSILON { 0.0 } else { new_size };
+    }
+
+    // `orders` is the get_open_orders_by_currency reply for `currency`
+    pub fn reconcile_open_orders(&mut self, currency: &str, orders: &[UserOrder]) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        // orders placed before the session are only known from this reply
+        let seeding = self.seeded_orders.insert(currency.to_string());
+        let exchange_ids: HashSet<&str> = orders.iter().map(|o| o.order_id.as_str()).collect();
+
+        let missing: Vec<String> = self
+            .orders
+            .values()
+            .filter(|o| o.status.is_live())
+            .filter(|o| instrument_currency(&o.instrument_name) == currency)
+            .filter(|o| !exchange_ids.contains(o.order_id.as_str()))
+            .map(|o| o.order_id.clone())
+            .collect();
+        for order_id in missing {
+            // we don't know how it ended, forget about it
+            self.orders.remove(&order_id);
+            mismatches.push(Mismatch::MissingOnExchange { order_id });
+        }
+
+        for order in orders {
+            let Some(exchange) = TrackedOrder::from_user_order(order) else {
+                continue;
+            };
+            match self.orders.get(&order.order_id) {
+                None if seeding => {}
+                None => mismatches.push(Mismatch::UnknownOrder { order_id: order.order_id.clone() }),
+                // a notification newer than the reply, the reply is the stale one
+                Some(local) if local.last_update_timestamp > exchange.last_update_timestamp => continue,
+                Some(local) => {
+                    if local.last_update_timestamp < exchange.last_update_timestamp {
+                        mismatches.push(Mismatch::StaleOrder {
+                            order_id: order.order_id.clone(),
+                            local_timestamp: local.last_update_timestamp,
+                            exchange_timestamp: exchange.last_update_timestamp,
+                        });
+                    }
+                    if (local.amount - exchange.amount).abs() > AMOUNT_EPSILON {
+                        mismatches.push(Mismatch::OrderDiffers {
+                            order_id: order.order_id.clone(),
+                            field: "amount",
+                            local: local.amount,
+                            exchange: exchange.amount,
+                        });
+                    }
+                    if (local.filled_amount - exchange.filled_amount).abs() > AMOUNT_EPSILON {
+                        mismatches.push(Mismatch::OrderDiffers {
+                            order_id: order.order_id.clone(),
+                            field: "filled_amount",
+                            local: local.filled_amount,
+                            exchange: exchange.filled_amount,
+                        });
+                    }
+                }
+            }
+            self.orders.insert(order.order_id.clone(), exchange);
+        }
+
+        // closed orders have been reported already, no need to keep them around forever
+        self.orders.retain(|_, o| o.status.is_live());
+        mismatches
+    }
+
+    // `positions` is the get_positions reply for `currency`
+    pub fn reconcile_positions(&mut self, currency: &str, positions: &[UserPosition]) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        // the local positions only hold the trades of the session until then
+        let seeding = self.seeded_positions.insert(currency.to_string());
+
+        for (instrument_name, local) in self.positions.iter() {
+            if seeding || instrument_currency(instrument_name) != currency || local.size == 0.0 {
+                continue;
+            }
+            if !positions.iter().any(|p| &p.instrument_name == instrument_name) {
+                mismatches.push(Mismatch::PositionDiffers {
+                    instrument_name: instrument_name.clone(),
+                    local: local.size,
+                    exchange: 0.0,
+                });
+            }
+        }
+        self.positions
+            .retain(|name, _| instrument_currency(name) != currency || positions.iter().any(|p| &p.instrument_name == name));
+
+        for exchange in positions {
+            let local_size = self.positions.get(&exchange.instrument_name).map_or(0.0, |p| p.size);
+            if !seeding && (local_size - exchange.size).abs() > AMOUNT_EPSILON {
+                mismatches.push(Mismatch::PositionDiffers {
+                    instrument_name: exchange.instrument_name.clone(),
+                    local: local_size,
+                    exchange: exchange.size,
+                });
+            }
+            self.positions.insert(
+                exchange.instrument_name.clone(),
+                Position { size: exchange.size, average_price: exchange.average_price },
+            );
+        }
+        mismatches
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(order_id: &str, state: &str, amount: f64, filled: f64, ts: u64) -> UserOrder {
+        UserOrder {
+            order_id: order_id.to_string(),
+            instrument_name: "BTC-PERPETUAL".to_string(),
+            direction: 1,
+            order_state: state.to_string(),
+            price: 100_000.0,
+            amount,
+            filled_amount: filled,
+            average_price: 0.0,
+            last_update_timestamp: ts,
+        }
+    }
+
+    fn trade(trade_id: &str, direction: i8, price: f64, amount: f64) -> UserTrade {
+        UserTrade {
+            trade_id: trade_id.to_string(),
+            order_id: "1".to_string(),
+            instrument_name: "BTC-PERPETUAL".to_string(),
+            direction,
+            price,
+            amount,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn test_currency_from_channels() {
+        let channels = vec![
+            "user.orders.BTC-PERPETUAL.raw".to_string(),
+            "user.trades.ETH_USDC-PERPETUAL.raw".to_string(),
+            "user.orders.future.BTC.raw".to_string(),
+            "book.BTC-PERPETUAL.raw".to_string(),
+        ];
+        assert_eq!(currencies_from_channels(&channels), vec!["BTC", "USDC"]);
+    }
+
+    #[test]
+    fn test_position_from_trades() {
+        let mut tracker = OrderTracker::new();
+        tracker.on_trade(&trade("t1", 1, 100.0, 10.0));
+        tracker.on_trade(&trade("t2", 1, 110.0, 10.0));
+        tracker.on_trade(&trade("t2", 1, 110.0, 10.0)); // duplicate
+        let position = tracker.position("BTC-PERPETUAL").unwrap();
+        assert_eq!(position.size, 20.0);
+        assert_eq!(position.average_price, 105.0);
+
+        tracker.on_trade(&trade("t3", -1, 120.0, 30.0));
+        let position = tracker.position("BTC-PERPETUAL").unwrap();
+        assert_eq!(position.size, -10.0);
+        assert_eq!(position.average_price, 120.0);
+    }
+
+    #[test]
+    fn test_seen_trades_are_bounded() {
+        let mut tracker = OrderTracker::new();
+        for i in 0..=SEEN_TRADES {
+            tracker.on_trade(&trade(&i.to_string(), 1, 100.0, 1.0));
+        }
+        assert_eq!(tracker.seen_trades.len(), SEEN_TRADES);
+        // the oldest id has been forgotten, the latest ones are still dropped
+        tracker.on_trade(&trade("0", 1, 100.0, 1.0));
+        tracker.on_trade(&trade(&SEEN_TRADES.to_string(), 1, 100.0, 1.0));
+        assert_eq!(tracker.position("BTC-PERPETUAL").unwrap().size, (SEEN_TRADES + 2) as f64);
+    }
+
+    #[test]
+    fn test_reconcile_open_orders() {
+        let mut tracker = OrderTracker::new();
+        // the first reply seeds the orders from before the session
+        assert!(tracker.reconcile_open_orders("BTC", &[order("z", "open", 1.0, 0.0, 1)]).is_empty());
+        tracker.on_order(&order("z", "cancelled", 1.0, 0.0, 2));
+        tracker.on_order(&order("a", "open", 10.0, 0.0, 1));
+        tracker.on_order(&order("b", "open", 10.0, 0.0, 1));
+        tracker.on_order(&order("d", "open", 3.0, 1.0, 5));
+
+        let exchange = vec![
+            order("b", "open", 10.0, 5.0, 2),
+            order("c", "open", 1.0, 0.0, 2),
+            // older than the notification, left as is
+            order("d", "open", 3.0, 0.0, 4),
+        ];
+        let mismatches = tracker.reconcile_open_orders("BTC", &exchange);
+
+        assert!(mismatches.contains(&Mismatch::MissingOnExchange { order_id: "a".to_string() }));
+        assert!(mismatches.contains(&Mismatch::UnknownOrder { order_id: "c".to_string() }));
+        assert!(mismatches.contains(&Mismatch::StaleOrder { order_id: "b".to_string(), local_timestamp: 1, exchange_timestamp: 2 }));
+        assert!(mismatches.contains(&Mismatch::OrderDiffers {
+            order_id: "b".to_string(),
+            field: "filled_amount",
+            local: 0.0,
+            exchange: 5.0,
+        }));
+        assert_eq!(mismatches.len(), 4);
+        assert_eq!(tracker.open_orders().count(), 3);
+        assert_eq!(tracker.order("b").unwrap().remaining(), 5.0);
+        assert_eq!(tracker.order("d").unwrap().remaining(), 2.0);
+    }
+
+    #[test]
+    fn test_reconcile_positions() {
+        let mut tracker = OrderTracker::new();
+        tracker.on_trade(&trade("t1", 1, 100.0, 10.0));
+        let exchange = |size: f64| vec![UserPosition { instrument_name: "BTC-PERPETUAL".to_string(), size, average_price: 100.0 }];
+        // the session trades are part of the position held before it
+        assert!(tracker.reconcile_positions("BTC", &exchange(50.0)).is_empty());
+        tracker.on_trade(&trade("t2", 1, 100.0, 10.0));
+        assert!(tracker.reconcile_positions("BTC", &exchange(60.0)).is_empty());
+        let mismatches = tracker.reconcile_positions("BTC", &exchange(70.0));
+        assert_eq!(mismatches, vec![Mismatch::PositionDiffers { instrument_name: "BTC-PERPETUAL".to_string(), local: 60.0, exchange: 70.0 }]);
+    }
+
+    #[test]
+    fn test_request_slots() {
+        let mut tracker = OrderTracker::new();
+        let btc = tracker.request_slot("BTC");
+        let eth = tracker.request_slot("ETH");
+        // a reload asking again for BTC alone does not reuse the slot of ETH
+        let btc_again = tracker.request_slot("BTC");
+        assert_eq!(tracker.requested_currency(btc), Some("BTC"));
+        assert_eq!(tracker.requested_currency(eth), Some("ETH"));
+        assert_eq!(tracker.requested_currency(btc_again), Some("BTC"));
+        assert_eq!(tracker.requested_currency(REQUEST_SLOTS), None);
+    }
+}
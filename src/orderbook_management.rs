@@ -1,7 +1,48 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use serde::Serialize;
 use thiserror::Error;
 use haiku_common::shm_accessor::market_data_type::OrderbookData;
 use crate::parsing::parsing_fast_orderbook::OrderbookUpdateDataRaw;
 use crate::parsing::parsing_orderbook::{OrderbookAction, OrderbookLevel};
+use crate::price::TickSize;
+
+// levels per side of OrderbookData in the SHM layout
+pub const SHM_BOOK_LEVELS: usize = 10;
+// small buffer if we need to delete some quote which are in our orderbook
+const DEPTH_BUFFER: usize = 5;
+// initial allocation of a full depth side, a liquid perpetual has a few hundred levels per side
+const FULL_DEPTH_RESERVE: usize = 1024;
+// deltas kept while a recovery snapshot is pending, the oldest are dropped first
+const MAX_RECOVERY_DELTAS: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BookSettings {
+    pub depth: usize,
+    // keep every level from the snapshot and the deltas, only truncate to depth when publishing
+    pub full_depth: bool,
+    // minimum time between two SHM writes of the book, zero to write every update
+    pub conflation: Duration,
+}
+
+impl BookSettings {
+    // Snapshot levels worth parsing for this book
+    #[inline]
+    pub fn snapshot_levels(&self) -> usize {
+        if self.full_depth {
+            usize::MAX
+        } else {
+            OrderbookManagerV2::capacity_for(self.depth)
+        }
+    }
+
+    // public/get_order_book only accepts a few depths, the smallest one covering what we keep
+    pub fn recovery_depth(&self) -> usize {
+        const DEPTHS: [usize; 8] = [1, 5, 10, 20, 50, 100, 1000, 10000];
+        let levels = self.snapshot_levels();
+        DEPTHS.iter().copied().find(|&d| d >= levels).unwrap_or(DEPTHS[DEPTHS.len() - 1])
+    }
+}
 
 #[derive(Debug, Error)]
 pub enum OrderbookError {
@@ -13,81 +54,298 @@ pub enum OrderbookError {
 
     #[error("Not initialized with snapshot")]
     NotInitialized,
+
+    // delta older than the snapshot the book was rebuilt from, expected right after a recovery
+    #[error("Stale update: change_id {change_id} already covered by {last_change_id}")]
+    StaleUpdate { change_id: u64, last_change_id: u64 },
+}
+
+// Counted per book each time an update leaves it in that state, and per level for the prices
+// that were not on the tick grid and had to be rounded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ViolationCounts {
+    pub unordered_levels: u64,
+    pub crossed: u64,
+    pub locked: u64,
+    pub invalid_size: u64,
+    pub off_grid_prices: u64,
+}
+
+impl ViolationCounts {
+    #[inline]
+    pub fn total(&self) -> u64 {
+        self.unordered_levels + self.crossed + self.locked + self.invalid_size + self.off_grid_prices
+    }
+}
+
+// A delta received while the book waits for its recovery snapshot
+#[derive(Debug, Clone)]
+pub struct BufferedDelta {
+    pub update: OrderbookUpdateDataRaw,
+    pub change_id: u64,
+    pub timestamp: u64,
 }
 
+// Prices are kept as integer ticks, f32 only appears when writing to SHM
 #[derive(Debug, Clone, Copy)]
 struct PriceLevel {
-    price: f32,
+    ticks: i64,
     size: f32,
 }
 
 #[derive(Debug, Clone)]
 pub struct OrderbookManagerV2 {
-    bids: [PriceLevel; 15], // small buffer if we need to delete some quote which are in our orderbook
-    bid_count: u8,
-    asks: [PriceLevel; 15],
-    ask_count: u8,
+    bids: Vec<PriceLevel>, // never grows past capacity, allocated once
+    asks: Vec<PriceLevel>,
+    depth: usize,
+    capacity: usize,
+    tick_size: TickSize,
     last_change_id: u64,
+    violations: ViolationCounts,
+    // set when an update leaves best bid >= best ask, cleared by the first update that uncrosses it
+    crossed_since: Option<Instant>,
+    resync_requested: bool,
+    // set after a sequence gap until the get_order_book snapshot arrives
+    recovery: Option<VecDeque<BufferedDelta>>,
+    // since startup, across resets
+    snapshot_count: u64,
+    update_count: u64,
 }
 
 impl OrderbookManagerV2 {
-    pub fn new(_max_depth: usize) -> Self {
+    pub fn new(max_depth: usize, tick_size: TickSize) -> Self {
+        let capacity = Self::capacity_for(max_depth);
         Self {
-            bids: [PriceLevel { price: 0.0, size: 0.0 }; 15],
-            bid_count: 0,
-            asks: [PriceLevel { price: 0.0, size: 0.0 }; 15],
-            ask_count: 0,
+            bids: Vec::with_capacity(capacity),
+            asks: Vec::with_capacity(capacity),
+            depth: max_depth,
+            capacity,
+            tick_size,
             last_change_id: 0,
+            violations: ViolationCounts::default(),
+            crossed_since: None,
+            resync_requested: false,
+            recovery: None,
+            snapshot_count: 0,
+            update_count: 0,
+        }
+    }
+
+    // Without the truncation, deletes near the top never leave the published book shorter than
+    // reality and levels coming back in range are known. Costs a memmove of the side per insert/delete.
+    pub fn with_full_depth(max_depth: usize, tick_size: TickSize) -> Self {
+        Self {
+            bids: Vec::with_capacity(FULL_DEPTH_RESERVE),
+            asks: Vec::with_capacity(FULL_DEPTH_RESERVE),
+            depth: max_depth,
+            capacity: usize::MAX,
+            tick_size,
+            last_change_id: 0,
+            violations: ViolationCounts::default(),
+            crossed_since: None,
+            resync_requested: false,
+            recovery: None,
+            snapshot_count: 0,
+            update_count: 0,
+        }
+    }
+
+    pub fn from_settings(settings: &BookSettings, tick_size: TickSize) -> Self {
+        if settings.full_depth {
+            Self::with_full_depth(settings.depth, tick_size)
+        } else {
+            Self::new(settings.depth, tick_size)
+        }
+    }
+
+    #[inline]
+    pub fn is_full_depth(&self) -> bool {
+        self.capacity == usize::MAX
+    }
+
+    // Levels kept for a given depth
+    #[inline]
+    pub fn capacity_for(depth: usize) -> usize {
+        depth + DEPTH_BUFFER
+    }
+
+    #[inline]
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    // Back to the state before the first snapshot, the next one is accepted whatever its change_id
+    pub fn reset(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+        self.last_change_id = 0;
+        self.crossed_since = None;
+        self.resync_requested = false;
+        self.recovery = None;
+    }
+
+    // After a reset on a sequence gap: the deltas are kept until the recovery snapshot
+    pub fn start_recovery(&mut self) {
+        self.recovery = Some(VecDeque::new());
+    }
+
+    #[inline]
+    pub fn is_recovering(&self) -> bool {
+        self.recovery.is_some()
+    }
+
+    // Losing the oldest delta breaks the change_id chain, the replay then starts another recovery
+    pub fn buffer_delta(&mut self, update: OrderbookUpdateDataRaw, change_id: u64, timestamp: u64) {
+        if let Some(deltas) = self.recovery.as_mut() {
+            if deltas.len() >= MAX_RECOVERY_DELTAS {
+                deltas.pop_front();
+            }
+            deltas.push_back(BufferedDelta { update, change_id, timestamp });
+        }
+    }
+
+    // Ends the recovery with the snapshot at `change_id`: the buffered deltas it does not cover, in arrival order
+    pub fn end_recovery(&mut self, change_id: u64) -> Vec<BufferedDelta> {
+        self.recovery
+            .take()
+            .map(|deltas| deltas.into_iter().filter(|delta| delta.change_id > change_id).collect())
+            .unwrap_or_default()
+    }
+
+    #[inline]
+    pub fn last_change_id(&self) -> u64 {
+        self.last_change_id
+    }
+
+    // (ticks, size) from the touch outwards
+    #[inline]
+    pub fn bid_levels(&self) -> impl Iterator<Item = (i64, f32)> + '_ {
+        self.bids.iter().map(|level| (level.ticks, level.size))
+    }
+
+    #[inline]
+    pub fn ask_levels(&self) -> impl Iterator<Item = (i64, f32)> + '_ {
+        self.asks.iter().map(|level| (level.ticks, level.size))
+    }
+
+    // Size kept at a price, 0 when there is no such level (or it is beyond what we keep)
+    #[inline]
+    pub fn level_size(&self, ticks: i64, is_bid: bool) -> f32 {
+        let levels = if is_bid { &self.bids } else { &self.asks };
+        match Self::find_price_index_binary(levels, ticks, is_bid) {
+            Ok(idx) => levels[idx].size,
+            Err(_) => 0.0,
+        }
+    }
+
+    #[inline]
+    pub fn snapshot_count(&self) -> u64 {
+        self.snapshot_count
+    }
+
+    // deltas applied
+    #[inline]
+    pub fn update_count(&self) -> u64 {
+        self.update_count
+    }
+
+    #[inline]
+    pub fn violations(&self) -> ViolationCounts {
+        self.violations
+    }
+
+    // Only the top of the book, on every update: the first two levels strictly ordered, sizes at the touch
+    // finite and positive, best bid < best ask. Deeper levels are kept ordered by the insertion.
+    #[inline]
+    fn check_invariants(&mut self, now: Instant) {
+        let ordered = |levels: &[PriceLevel], is_bid: bool| match levels {
+            [first, second, ..] if is_bid => first.ticks > second.ticks,
+            [first, second, ..] => first.ticks < second.ticks,
+            _ => true,
+        };
+        if !ordered(&self.bids, true) || !ordered(&self.asks, false) {
+            self.violations.unordered_levels += 1;
+        }
+        let valid_size = |level: Option<&PriceLevel>| level.is_none_or(|level| level.size.is_finite() && level.size > 0.0);
+        if !valid_size(self.bids.first()) || !valid_size(self.asks.first()) {
+            self.violations.invalid_size += 1;
+        }
+
+        let crossed = match (self.bids.first(), self.asks.first()) {
+            (Some(bid), Some(ask)) if bid.ticks > ask.ticks => {
+                self.violations.crossed += 1;
+                true
+            }
+            (Some(bid), Some(ask)) if bid.ticks == ask.ticks => {
+                self.violations.locked += 1;
+                true
+            }
+            _ => false,
+        };
+        if !crossed {
+            self.crossed_since = None;
+        } else if self.crossed_since.is_none() {
+            self.crossed_since = Some(now);
+        }
+    }
+
+    // True once per episode when the book has been crossed or locked for longer than `max_crossed`,
+    // the caller is expected to resnapshot it, which resets the book
+    pub fn needs_resync(&mut self, now: Instant, max_crossed: Duration) -> bool {
+        match self.crossed_since {
+            Some(since) if !self.resync_requested && now.duration_since(since) > max_crossed => {
+                self.resync_requested = true;
+                true
+            }
+            _ => false,
         }
     }
 
+    #[inline]
+    pub fn tick_size(&self) -> TickSize {
+        self.tick_size
+    }
+
     #[inline(always)]
-    fn find_price_index_binary(levels: &[PriceLevel], count: u8, price: f32, is_bid: bool) -> Result<usize, usize> {
-        if count == 0 {
+    fn find_price_index_binary(levels: &[PriceLevel], ticks: i64, is_bid: bool) -> Result<usize, usize> {
+        if levels.is_empty() {
             return Err(0);
         }
 
-        if is_bid && price > levels[0].price {
+        if is_bid && ticks > levels[0].ticks {
             return Err(0);
         }
-        if !is_bid && price < levels[0].price {
+        if !is_bid && ticks < levels[0].ticks {
             return Err(0);
         }
 
-        let slice = &levels[..count as usize];
         if is_bid {
-            match slice.binary_search_by(|level| price.partial_cmp(&level.price).unwrap()) {
-                Ok(i) => Ok(i),
-                Err(i) => Err(i),
-            }
+            levels.binary_search_by(|level| ticks.cmp(&level.ticks))
         } else {
-            match slice.binary_search_by(|level| level.price.partial_cmp(&price).unwrap()) {
-                Ok(i) => Ok(i),
-                Err(i) => Err(i),
-            }
+            levels.binary_search_by(|level| level.ticks.cmp(&ticks))
         }
     }
 
     // Non binary way, to check which one is the best as the array are small
     #[inline(always)]
-    fn find_price_index(levels: &[PriceLevel], count: u8, price: f32, is_bid: bool) -> Result<usize, usize> {
-        if count == 0 {
+    fn find_price_index(levels: &[PriceLevel], ticks: i64, is_bid: bool) -> Result<usize, usize> {
+        if levels.is_empty() {
             return Err(0);
         }
-        if is_bid && price > levels[0].price {
+        if is_bid && ticks > levels[0].ticks {
             return Err(0);
         }
-        if !is_bid && price < levels[0].price {
+        if !is_bid && ticks < levels[0].ticks {
             return Err(0);
         }
-        if price == levels[0].price {
+        if ticks == levels[0].ticks {
             return Ok(0);
         }
 
-        let len = count as usize;
+        let len = levels.len();
         if is_bid {
             for i in 1..len {
-                match price.partial_cmp(&levels[i].price).unwrap() {
+                match ticks.cmp(&levels[i].ticks) {
                     std::cmp::Ordering::Equal => return Ok(i),
                     std::cmp::Ordering::Greater => return Err(i),
                     std::cmp::Ordering::Less => continue,
@@ -96,7 +354,7 @@ impl OrderbookManagerV2 {
             Err(len)
         } else {
             for i in 1..len {
-                match price.partial_cmp(&levels[i].price).unwrap() {
+                match ticks.cmp(&levels[i].ticks) {
                     std::cmp::Ordering::Equal => return Ok(i),
                     std::cmp::Ordering::Less => return Err(i),
                     std::cmp::Ordering::Greater => continue,
@@ -106,22 +364,64 @@ impl OrderbookManagerV2 {
         }
     }
 
+    // Until the first snapshot (and after a reset) there is nothing to apply deltas to
+    #[inline]
+    pub fn is_initialized(&self) -> bool {
+        self.last_change_id != 0
+    }
+
+    // Replaces both sides, whatever the previous state: initial snapshot, resubscription or get_order_book recovery.
+    // `now` is the time the caller took for the update, it starts the crossed book timeout.
+    pub fn apply_snapshot(
+        &mut self,
+        snapshot: OrderbookUpdateDataRaw,
+        change_id: u64,
+        now: Instant,
+    ) -> Result<OrderbookData, OrderbookError> {
+        self.bids.clear();
+        self.asks.clear();
+        self.crossed_since = None;
+        self.resync_requested = false;
+        let off_grid = &mut self.violations.off_grid_prices;
+        for level in snapshot.bid_updates {
+            Self::apply_level_update(&mut self.bids, self.capacity, self.tick_size, off_grid, level, true)?;
+        }
+        for level in snapshot.ask_updates {
+            Self::apply_level_update(&mut self.asks, self.capacity, self.tick_size, off_grid, level, false)?;
+        }
+
+        self.last_change_id = change_id;
+        self.snapshot_count += 1;
+        self.check_invariants(now);
+        Ok(self.get_orderbook())
+    }
+
     pub fn apply_update(
         &mut self,
         update: OrderbookUpdateDataRaw,
         change_id: u64,
+        now: Instant,
     ) -> Result<OrderbookData, OrderbookError> {
+        if !self.is_initialized() {
+            return Err(OrderbookError::NotInitialized);
+        }
+        if change_id <= self.last_change_id {
+            return Err(OrderbookError::StaleUpdate { change_id, last_change_id: self.last_change_id });
+        }
         if update.prev_change_id != self.last_change_id {
             return Err(OrderbookError::SequenceGap {
                     expected: self.last_change_id,
                     received: update.prev_change_id,
                 });
             }
+        let off_grid = &mut self.violations.off_grid_prices;
         for action in update.bid_updates {
 
                 Self::apply_level_update(
                     &mut self.bids,
-                    &mut self.bid_count,
+                    self.capacity,
+                    self.tick_size,
+                    off_grid,
                     action,
                     true,
                 )?;
@@ -131,7 +431,9 @@ impl OrderbookManagerV2 {
 
                 Self::apply_level_update(
                     &mut self.asks,
-                    &mut self.ask_count,
+                    self.capacity,
+                    self.tick_size,
+                    off_grid,
                     action,
                     false,
                 )?;
@@ -139,42 +441,32 @@ impl OrderbookManagerV2 {
         }
 
         self.last_change_id = change_id;
+        self.update_count += 1;
+        self.check_invariants(now);
         Ok(self.get_orderbook())
     }
 
     #[inline(always)]
     fn insert_level(
-        levels: &mut [PriceLevel; 15],
-        count: &mut u8,
-        price: f32,
+        levels: &mut Vec<PriceLevel>,
+        capacity: usize,
+        ticks: i64,
         size: f32,
         is_bid: bool,
     ) -> Result<(), OrderbookError> {
-        if *count >= 15 {
-            let worst_idx = 14;
-            if is_bid {
-                if price <= levels[worst_idx].price {
-                    return Ok(());
-                }
-            } else {
-                if price >= levels[worst_idx].price {
-                    return Ok(());
-                }
-            }
-            *count = 14;
-        }
-
-        match Self::find_price_index_binary(levels, *count, price, is_bid) {
+        match Self::find_price_index_binary(levels, ticks, is_bid) {
             Ok(idx) => {
                 levels[idx].size = size;
             }
             Err(idx) => {
-                let current_count = *count as usize;
-                if idx < current_count {
-                    levels.copy_within(idx..current_count, idx + 1);
+                // worse than every level we keep
+                if idx >= capacity {
+                    return Ok(());
                 }
-                levels[idx] = PriceLevel { price, size };
-                *count += 1;
+                if levels.len() >= capacity {
+                    levels.pop();
+                }
+                levels.insert(idx, PriceLevel { ticks, size });
             }
         }
         Ok(())
@@ -182,65 +474,247 @@ impl OrderbookManagerV2 {
 
     #[inline(always)]
     fn remove_level(
-        levels: &mut [PriceLevel; 15],
-        count: &mut u8,
-        price: f32,
+        levels: &mut Vec<PriceLevel>,
+        ticks: i64,
         is_bid: bool,
     ) -> Result<(), OrderbookError> {
-        if let Ok(idx) = Self::find_price_index_binary(levels, *count, price, is_bid) {
-            let current_count = *count as usize;
-            if idx + 1 < current_count {
-                levels.copy_within(idx + 1..current_count, idx);
-            }
-            *count -= 1;
+        if let Ok(idx) = Self::find_price_index_binary(levels, ticks, is_bid) {
+            levels.remove(idx);
         }
         Ok(())
     }
 
     #[inline(always)]
     fn apply_level_update(
-        levels: &mut [PriceLevel; 15],
-        count: &mut u8,
+        levels: &mut Vec<PriceLevel>,
+        capacity: usize,
+        tick_size: TickSize,
+        off_grid: &mut u64,
         level: OrderbookLevel,
         is_bid: bool,
     ) -> Result<(), OrderbookError> {
+        let price = tick_size.to_ticks(level.price).map_err(|e| OrderbookError::InvalidLevel(e.to_string()))?;
+        if price.off_grid {
+            *off_grid += 1;
+        }
+        let ticks = price.ticks;
         match level.action {
             OrderbookAction::New | OrderbookAction::Change => {
                 if level.size > 0.0 {
-                    Self::insert_level(levels, count, level.price, level.size, is_bid)?;
+                    Self::insert_level(levels, capacity, ticks, level.size, is_bid)?;
                 } else {
-                    Self::remove_level(levels, count, level.price, is_bid)?;
+                    Self::remove_level(levels, ticks, is_bid)?;
                 }
             }
             OrderbookAction::Delete => {
-                Self::remove_level(levels, count, level.price, is_bid)?;
+                Self::remove_level(levels, ticks, is_bid)?;
             }
         }
         Ok(())
     }
 
+    // Writes at most `depth` levels, bounded by what the SHM layout holds
     #[inline(always)]
     pub fn get_orderbook(&self) -> OrderbookData {
         let mut ob_data = OrderbookData {
-            bid_prices: [0.0; 10],
-            ask_prices: [0.0; 10],
-            bid_sizes: [0.0; 10],
-            ask_sizes: [0.0; 10],
+            bid_prices: [0.0; SHM_BOOK_LEVELS],
+            ask_prices: [0.0; SHM_BOOK_LEVELS],
+            bid_sizes: [0.0; SHM_BOOK_LEVELS],
+            ask_sizes: [0.0; SHM_BOOK_LEVELS],
         };
+        let max_levels = std::cmp::min(self.depth, SHM_BOOK_LEVELS);
 
-        let bid_levels = std::cmp::min(10, self.bid_count as usize);
+        let bid_levels = std::cmp::min(max_levels, self.bids.len());
         for i in 0..bid_levels {
-            ob_data.bid_prices[i] = self.bids[i].price;
+            ob_data.bid_prices[i] = self.tick_size.to_price(self.bids[i].ticks) as f32;
             ob_data.bid_sizes[i] = self.bids[i].size;
         }
 
-        let ask_levels = std::cmp::min(10, self.ask_count as usize);
+        let ask_levels = std::cmp::min(max_levels, self.asks.len());
         for i in 0..ask_levels {
-            ob_data.ask_prices[i] = self.asks[i].price;
+            ob_data.ask_prices[i] = self.tick_size.to_price(self.asks[i].ticks) as f32;
             ob_data.ask_sizes[i] = self.asks[i].size;
         }
 
         ob_data
     }
 
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::price::Decimal;
+
+    fn level(action: OrderbookAction, price: f64, size: f32) -> OrderbookLevel {
+        OrderbookLevel { action, price: Decimal::from_f64(price).unwrap(), size }
+    }
+
+    fn tick() -> TickSize {
+        TickSize::new(0.5).unwrap()
+    }
+
+    fn update(prev_change_id: u64, bids: &[(f64, f32)], asks: &[(f64, f32)]) -> OrderbookUpdateDataRaw {
+        let mut data = OrderbookUpdateDataRaw::new();
+        data.set_prev_change_id(prev_change_id);
+        for &(price, size) in bids {
+            data.add_bid(level(OrderbookAction::New, price, size));
+        }
+        for &(price, size) in asks {
+            data.add_ask(level(OrderbookAction::New, price, size));
+        }
+        data
+    }
+
+    #[test]
+    fn test_depth_bounds_storage_and_output() {
+        let mut book = OrderbookManagerV2::new(1, tick());
+        let bids: Vec<(f64, f32)> = (0..10).map(|i| (100.0 - i as f64, 1.0)).collect();
+        let ob_data = book.apply_snapshot(update(0, &bids, &[(101.0, 2.0)]), 1, Instant::now()).unwrap();
+
+        assert_eq!(book.bids.len(), OrderbookManagerV2::capacity_for(1));
+        assert_eq!(ob_data.bid_prices[0], 100.0);
+        assert_eq!(ob_data.bid_prices[1], 0.0);
+        assert_eq!(ob_data.ask_prices[0], 101.0);
+
+        // a delete at the top brings the next kept level up
+        let mut delete = OrderbookUpdateDataRaw::new();
+        delete.set_prev_change_id(1);
+        delete.add_bid(level(OrderbookAction::Delete, 100.0, 0.0));
+        let ob_data = book.apply_update(delete, 2, Instant::now()).unwrap();
+        assert_eq!(ob_data.bid_prices[0], 99.0);
+
+        // deeper books are kept whole, only the SHM output is cut to its levels
+        let mut book = OrderbookManagerV2::new(25, tick());
+        let bids: Vec<(f64, f32)> = (0..30).map(|i| (100.0 - i as f64, 1.0)).collect();
+        let ob_data = book.apply_snapshot(update(0, &bids, &[(101.0, 2.0)]), 1, Instant::now()).unwrap();
+        assert_eq!(book.bid_levels().count(), 30);
+        assert_eq!(ob_data.bid_prices[SHM_BOOK_LEVELS - 1], 100.0 - (SHM_BOOK_LEVELS - 1) as f32);
+    }
+
+    #[test]
+    fn test_sequence_gap() {
+        let mut book = OrderbookManagerV2::new(10, tick());
+        book.apply_snapshot(update(0, &[(100.0, 1.0)], &[]), 5, Instant::now()).unwrap();
+        assert!(matches!(
+            book.apply_update(update(4, &[(99.0, 1.0)], &[]), 6, Instant::now()),
+            Err(OrderbookError::SequenceGap { expected: 5, received: 4 })
+        ));
+    }
+
+    #[test]
+    fn test_full_depth_keeps_every_level() {
+        let mut book = OrderbookManagerV2::with_full_depth(2, tick());
+        let bids: Vec<(f64, f32)> = (0..50).map(|i| (100.0 - i as f64, 1.0)).collect();
+        book.apply_snapshot(update(0, &bids, &[]), 1, Instant::now()).unwrap();
+        assert_eq!(book.bids.len(), 50);
+
+        // the whole top is deleted, deeper levels come into range
+        let mut delete = OrderbookUpdateDataRaw::new();
+        delete.set_prev_change_id(1);
+        for i in 0..45 {
+            delete.add_bid(level(OrderbookAction::Delete, 100.0 - i as f64, 0.0));
+        }
+        let ob_data = book.apply_update(delete, 2, Instant::now()).unwrap();
+        assert_eq!(ob_data.bid_prices[0], 55.0);
+        assert_eq!(ob_data.bid_prices[1], 54.0);
+        assert_eq!(ob_data.bid_prices[2], 0.0);
+    }
+
+    #[test]
+    fn test_half_tick_levels_stay_apart() {
+        // 100000.002 and 100000.001 are the same f32
+        let mut book = OrderbookManagerV2::new(10, TickSize::new(0.001).unwrap());
+        book.apply_snapshot(update(0, &[(100000.002, 1.0), (100000.001, 2.0)], &[]), 1, Instant::now()).unwrap();
+        assert_eq!(book.bids.len(), 2);
+
+        let mut delete = OrderbookUpdateDataRaw::new();
+        delete.set_prev_change_id(1);
+        delete.add_bid(level(OrderbookAction::Delete, 100000.002, 0.0));
+        book.apply_update(delete, 2, Instant::now()).unwrap();
+        assert_eq!(book.bids.len(), 1);
+        assert_eq!(book.bids[0].ticks, 100000001);
+        assert_eq!(book.bids[0].size, 2.0);
+        assert_eq!(book.violations().off_grid_prices, 0);
+    }
+
+    #[test]
+    fn test_off_grid_prices_are_counted() {
+        let mut book = OrderbookManagerV2::new(10, tick());
+        book.apply_snapshot(update(0, &[(100.0, 1.0), (99.7, 1.0)], &[(101.0, 1.0)]), 1, Instant::now()).unwrap();
+        assert_eq!(book.violations().off_grid_prices, 1);
+        assert_eq!(book.bids[1].ticks, 199);
+
+        // 10^39 does not fit the tick arithmetic
+        let mut out_of_range = OrderbookUpdateDataRaw::new();
+        out_of_range.set_prev_change_id(1);
+        out_of_range.add_ask(level(OrderbookAction::New, 1e-40, 1.0));
+        assert!(matches!(book.apply_update(out_of_range, 2, Instant::now()), Err(OrderbookError::InvalidLevel(_))));
+    }
+
+    #[test]
+    fn test_crossed_book_requests_resync() {
+        let mut book = OrderbookManagerV2::new(10, tick());
+        let now = Instant::now();
+        book.apply_snapshot(update(0, &[(100.0, 1.0)], &[(101.0, 1.0)]), 1, now).unwrap();
+        assert_eq!(book.violations().total(), 0);
+
+        // a bid above the ask that the exchange never removed
+        book.apply_update(update(1, &[(102.0, 1.0)], &[]), 2, now).unwrap();
+        book.apply_update(update(2, &[(101.0, 1.0)], &[]), 3, now + Duration::from_secs(1)).unwrap();
+        assert_eq!(book.violations().crossed, 2);
+
+        assert!(!book.needs_resync(now + Duration::from_secs(60), Duration::from_secs(60)));
+        assert!(book.needs_resync(now + Duration::from_secs(61), Duration::from_secs(60)));
+        // only requested once until the book is reset
+        assert!(!book.needs_resync(now + Duration::from_secs(62), Duration::from_secs(60)));
+
+        book.reset();
+        book.apply_snapshot(update(0, &[(100.0, 1.0)], &[(100.0, 1.0)]), 1, now).unwrap();
+        assert_eq!(book.violations().locked, 1);
+    }
+
+    #[test]
+    fn test_recovery_buffers_deltas() {
+        let mut book = OrderbookManagerV2::new(10, tick());
+        book.start_recovery();
+        book.buffer_delta(update(8, &[(99.0, 1.0)], &[]), 9, 1);
+        book.buffer_delta(update(9, &[(98.0, 1.0)], &[]), 10, 2);
+        book.buffer_delta(update(10, &[(97.0, 1.0)], &[]), 11, 3);
+
+        book.apply_snapshot(update(0, &[(100.0, 1.0)], &[]), 9, Instant::now()).unwrap();
+        let deltas = book.end_recovery(9);
+        assert!(!book.is_recovering());
+        assert_eq!(deltas.iter().map(|delta| delta.change_id).collect::<Vec<_>>(), vec![10, 11]);
+        for delta in deltas {
+            book.apply_update(delta.update, delta.change_id, Instant::now()).unwrap();
+        }
+        assert_eq!(book.last_change_id(), 11);
+        assert_eq!(book.bids.len(), 3);
+    }
+
+    #[test]
+    fn test_snapshot_replaces_book() {
+        let mut book = OrderbookManagerV2::new(10, tick());
+        assert!(matches!(
+            book.apply_update(update(0, &[(100.0, 1.0)], &[]), 1, Instant::now()),
+            Err(OrderbookError::NotInitialized)
+        ));
+
+        book.apply_snapshot(update(0, &[(100.0, 1.0), (99.0, 1.0)], &[(101.0, 1.0)]), 10, Instant::now()).unwrap();
+        // a recovery snapshot does not merge with what was there
+        let ob_data = book.apply_snapshot(update(0, &[(98.0, 3.0)], &[(102.0, 1.0)]), 20, Instant::now()).unwrap();
+        assert_eq!(book.bids.len(), 1);
+        assert_eq!(ob_data.bid_prices[0], 98.0);
+        assert_eq!(ob_data.ask_prices[0], 102.0);
+
+        // deltas already covered by the snapshot are skipped, the next one chains on its change_id
+        assert!(matches!(
+            book.apply_update(update(15, &[(97.0, 1.0)], &[]), 18, Instant::now()),
+            Err(OrderbookError::StaleUpdate { change_id: 18, last_change_id: 20 })
+        ));
+        book.apply_update(update(20, &[(97.0, 1.0)], &[]), 21, Instant::now()).unwrap();
+        assert_eq!(book.bids.len(), 2);
+    }
+}
@@ -1,6 +1,7 @@
-use crate::parsing::parsing_admin::{AuthMessage, PongMessage, SubscriptionMessage};
-use crate::parsing::parsing_orderbook::{OrderbookSnapshotMessage, OrderbookUpdateMessage};
+use crate::parsing::parsing_admin::{AuthMessage, InstrumentsMessage, PongMessage, SubscriptionMessage};
+use crate::parsing::parsing_orderbook::{OrderbookRecoveryMessage, OrderbookSnapshotMessage, OrderbookUpdateMessage};
 use crate::parsing::parsing_trade::TradeUpdateMessage;
+use crate::parsing::parsing_user::{OpenOrdersMessage, PositionsMessage, UserOrderMessage, UserTradeMessage};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum MessageType {
@@ -11,6 +12,12 @@ pub enum MessageType {
     OrderbookUpdate,
     Unknown,
     Pong,
+    UserOrders,
+    UserTrades,
+    OpenOrders,
+    Positions,
+    Instruments,
+    OrderbookRecovery,
 }
 
 #[derive(Debug, Clone)]
@@ -22,4 +29,10 @@ pub enum DeribitMessage {
     OrderbookUpdate(OrderbookUpdateMessage),
     Unknown,
     Pong(PongMessage),
+    UserOrders(UserOrderMessage),
+    UserTrades(UserTradeMessage),
+    OpenOrders(OpenOrdersMessage),
+    Positions(PositionsMessage),
+    Instruments(InstrumentsMessage),
+    OrderbookRecovery(OrderbookRecoveryMessage),
 }
\ No newline at end of file
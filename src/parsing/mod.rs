@@ -5,6 +5,7 @@ pub mod parsing_orderbook;
 pub mod exchange_message_type;
 pub mod parsing_fast;
 pub mod parsing_fast_orderbook;
+pub mod parsing_user;
 
 use simd_json::borrowed::Value as BorrowedValue;
 use simd_json::derived::ValueObjectAccess;
@@ -55,6 +56,14 @@ impl MessageParser {
             .ok_or_else(|| ParseError::MissingField(field.to_string()))
     }
 
+    // Deribit sends integral amounts without a decimal point, as_f64 would reject them
+    #[inline]
+    fn get_number(value: &BorrowedValue, field: &str) -> Result<f64, ParseError> {
+        value.get(field)
+            .and_then(|v| v.cast_f64())
+            .ok_or_else(|| ParseError::MissingField(field.to_string()))
+    }
+
     #[inline]
     fn get_usize(value: &BorrowedValue, field: &str) -> Result<usize, ParseError> {
         value.get(field)
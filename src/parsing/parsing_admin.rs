@@ -3,6 +3,7 @@ use simd_json::BorrowedValue;
 use simd_json::value::prelude::*;
 use crate::parsing::{MessageParser, ParseError};
 use crate::parsing::exchange_message_type::DeribitMessage;
+use crate::price::ContractType;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct AuthMessage {
@@ -14,6 +15,7 @@ pub struct AuthMessage {
 #[derive(Debug, Clone, Deserialize)]
 pub struct AuthResult {
     pub access_token: String,
+    pub refresh_token: String,
     pub expires_in: u64,
     pub token_type: String,
     pub scope: String,
@@ -26,6 +28,21 @@ pub struct SubscriptionMessage {
     pub result: Vec<String>,
 }
 
+// Built by parse_instruments_owned, the contract type is derived from several fields
+#[derive(Debug, Clone)]
+pub struct InstrumentsMessage {
+    pub id: u64,
+    pub instruments: Vec<InstrumentInfo>,
+}
+
+// Only what the feed handler needs from public/get_instruments
+#[derive(Debug, Clone)]
+pub struct InstrumentInfo {
+    pub instrument_name: String,
+    pub tick_size: f64,
+    pub contract_type: ContractType,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct PongMessage {
     pub us_in: usize,
@@ -41,6 +58,7 @@ impl MessageParser {
             .ok_or_else(|| ParseError::MissingField("result".to_string()))?;
 
         let access_token = Self::get_string(result, "access_token")?;
+        let refresh_token = Self::get_string(result, "refresh_token")?;
         let expires_in = Self::get_u64(result, "expires_in")?;
         let token_type = Self::get_string(result, "token_type")?;
         let scope = Self::get_string(result, "scope")?;
@@ -50,6 +68,7 @@ impl MessageParser {
             id,
             result: AuthResult {
                 access_token,
+                refresh_token,
                 expires_in,
                 token_type,
                 scope,
@@ -89,4 +108,32 @@ impl MessageParser {
             us_diff,
         }))
     }
-}
\ No newline at end of file
+
+    // "reversed" futures (and perpetuals) are quoted in USD contracts, "reversed" options are not
+    fn contract_type(instrument: &BorrowedValue) -> ContractType {
+        let field = |name: &str| instrument.get(name).and_then(|v| v.as_str());
+        match (field("kind"), field("instrument_type")) {
+            (Some("future"), Some("reversed")) => ContractType::Inverse,
+            _ => ContractType::Linear,
+        }
+    }
+
+    pub fn parse_instruments_owned(value: &BorrowedValue) -> Result<DeribitMessage, ParseError> {
+        let id = Self::get_u64(value, "id")?;
+        let result = value.get("result")
+            .ok_or_else(|| ParseError::MissingField("result".to_string()))?
+            .as_array()
+            .ok_or_else(|| ParseError::InvalidFormat("result not array".to_string()))?;
+
+        let instruments: Result<Vec<InstrumentInfo>, ParseError> = result
+            .iter()
+            .map(|i| Ok(InstrumentInfo {
+                instrument_name: Self::get_string(i, "instrument_name")?,
+                tick_size: Self::get_number(i, "tick_size")?,
+                contract_type: Self::contract_type(i),
+            }))
+            .collect();
+
+        Ok(DeribitMessage::Instruments(InstrumentsMessage { id, instruments: instruments? }))
+    }
+}
@@ -3,6 +3,7 @@ use haiku_common::shm_accessor::market_data_type::TradeEvent;
 use smallvec::SmallVec;
 use std::collections::HashMap;
 use crate::parsing::parsing_fast_orderbook::OrderbookResult;
+use crate::price::Decimal;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -31,6 +32,8 @@ impl FastMarketData {
 
 pub struct StreamingParser {
     pub(crate) instrument_map: HashMap<String, usize>,
+    // snapshot levels kept per instrument idx, the rest of the snapshot is skipped
+    pub(crate) snapshot_depths: Vec<usize>,
 }
 
 // This need refactoring if it goes in prod, as the code is a bit disgusting and not readable
@@ -41,8 +44,19 @@ impl StreamingParser {
     const TRADES_PATTERN: &'static [u8] = b"trades.";
     const BOOK_PATTERN: &'static [u8] = b"book.";
     const DATA_PATTERN: &'static [u8] = b"data";
+    pub(crate) const DEFAULT_SNAPSHOT_DEPTH: usize = 10;
+
     pub fn new(instrument_map: HashMap<String, usize>) -> Self {
-        Self { instrument_map }
+        let nb_instruments = instrument_map.values().max().map_or(0, |idx| idx + 1);
+        Self {
+            instrument_map,
+            snapshot_depths: vec![Self::DEFAULT_SNAPSHOT_DEPTH; nb_instruments],
+        }
+    }
+
+    pub fn with_snapshot_depths(mut self, snapshot_depths: Vec<usize>) -> Self {
+        self.snapshot_depths = snapshot_depths;
+        self
     }
 
     pub fn parse_fast_new(&self, buffer: &[u8]) -> Result<Option<FastMarketData>, ParseError> {
@@ -398,6 +412,72 @@ impl StreamingParser {
         Ok((result, end))
     }
 
+    // Same grammar as parse_f64_new_new but keeps the digits, so prices can be turned into ticks exactly
+    #[inline]
+    pub(crate) fn parse_decimal(buffer: &[u8], pos: usize) -> Result<(Decimal, usize), ParseError> {
+        let overflow = || ParseError::InvalidFormat("Number too large".to_string());
+        let start = pos;
+        let mut end = start;
+        let mut mantissa = 0i64;
+        let mut exponent = 0i32;
+        let mut negative = false;
+
+        if end < buffer.len() && buffer[end] == 45 { // '-'
+            negative = true;
+            end += 1;
+        }
+        let digits_start = end;
+
+        while end < buffer.len() && buffer[end].is_ascii_digit() {
+            mantissa = mantissa
+                .checked_mul(10)
+                .and_then(|m| m.checked_add((buffer[end] - 48) as i64))
+                .ok_or_else(overflow)?;
+            end += 1;
+        }
+
+        if end < buffer.len() && buffer[end] == 46 { // '.'
+            end += 1;
+            while end < buffer.len() && buffer[end].is_ascii_digit() {
+                mantissa = mantissa
+                    .checked_mul(10)
+                    .and_then(|m| m.checked_add((buffer[end] - 48) as i64))
+                    .ok_or_else(overflow)?;
+                exponent -= 1;
+                end += 1;
+            }
+        }
+
+        if end == digits_start {
+            return Err(ParseError::InvalidFormat("Expected number".to_string()));
+        }
+
+        if end < buffer.len() && (buffer[end] == 101 || buffer[end] == 69) { // 'e' or 'E'
+            end += 1;
+            let mut exp_negative = false;
+            if end < buffer.len() && buffer[end] == 45 {
+                exp_negative = true;
+                end += 1;
+            } else if end < buffer.len() && buffer[end] == 43 {
+                end += 1;
+            }
+            let mut exp = 0i32;
+            while end < buffer.len() && buffer[end].is_ascii_digit() {
+                exp = exp
+                    .checked_mul(10)
+                    .and_then(|e| e.checked_add((buffer[end] - 48) as i32))
+                    .ok_or_else(overflow)?;
+                end += 1;
+            }
+            exponent += if exp_negative { -exp } else { exp };
+        }
+
+        if negative {
+            mantissa = -mantissa;
+        }
+        Ok((Decimal::new(mantissa, exponent), end))
+    }
+
     #[inline]
     pub(crate) fn parse_f64_new(buffer: &[u8], pos: usize) -> Result<(f64, usize), ParseError> {
         let start = pos;
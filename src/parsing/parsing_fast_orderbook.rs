@@ -5,6 +5,7 @@ use smallvec::SmallVec;
 
 
 #[repr(C)]
+#[derive(Debug, Clone)]
 pub struct OrderbookUpdateDataRaw {
     pub prev_change_id: u64,
     pub bid_updates: SmallVec<[OrderbookLevel; 16]>, // we should monitor the if we have issue with the heap
@@ -18,6 +19,8 @@ pub struct OrderbookResult {
     pub timestamp: u64,
     pub instrument_idx: usize, 
     pub update_data: OrderbookUpdateDataRaw,
+    // full book replacing the current one (subscription snapshot or get_order_book), not a delta
+    pub is_snapshot: bool,
 }
 
 impl OrderbookUpdateDataRaw {
@@ -67,12 +70,14 @@ impl OrderbookResult {
         timestamp: u64,
         instrument_idx: usize,
         update_data: OrderbookUpdateDataRaw,
+        is_snapshot: bool,
     ) -> Self {
         Self {
             change_id,
             timestamp,
             instrument_idx,
             update_data,
+            is_snapshot,
         }
     }
 }
@@ -134,8 +139,14 @@ impl StreamingParser {
         }
         pos = pos + 7;
 
+        let instrument_idx = self.instrument_map[instrument_name];
         let ob_data = if data_type == 0 {
-            self.parse_orderbook_snapshot_direct(buffer, pos)?
+            let max_levels = self
+                .snapshot_depths
+                .get(instrument_idx)
+                .copied()
+                .unwrap_or(Self::DEFAULT_SNAPSHOT_DEPTH);
+            self.parse_orderbook_snapshot_direct(buffer, pos, max_levels)?
         } else if data_type == 1 {
             self.parse_order_book_update(buffer, pos)?
         } else {
@@ -143,7 +154,7 @@ impl StreamingParser {
                 "parse_orderbook_fast: data type not 0 or 1".to_string(),
             ));
         };
-        Ok(OrderbookResult::new(change_id, timestamp,self.instrument_map[instrument_name], ob_data))
+        Ok(OrderbookResult::new(change_id, timestamp, instrument_idx, ob_data, data_type == 0))
     }
 
     fn parse_order_book_update(
@@ -192,11 +203,12 @@ impl StreamingParser {
         &self,
         buffer: &[u8],
         mut pos: usize,
+        max_levels: usize,
     ) -> Result<OrderbookUpdateDataRaw, ParseError> {
         // ["new",3770.7,260189.0],["new",3770.65,2.5e4],["new",3770.6,1.5e4],...
         let mut ob_data = OrderbookUpdateDataRaw::new();
         let mut i = 0;
-        while i < 10 {
+        while i < max_levels {
             if let Some((bid, new_pos)) = self.parse_orderbook_entry(buffer, pos) {
                 ob_data.add_bid(bid);
                 pos = new_pos;
@@ -207,7 +219,7 @@ impl StreamingParser {
         }
         i = 0;
         pos = self.skip_to_asks_section(&buffer, pos)?;
-        while i < 10 {
+        while i < max_levels {
             if let Some((ask, new_pos)) = self.parse_orderbook_entry(buffer, pos) {
                 ob_data.add_ask(ask);
                 pos = new_pos;
@@ -234,8 +246,9 @@ impl StreamingParser {
         buffer: &[u8],
         mut pos: usize,
     ) -> Option<(OrderbookLevel, usize)> {
-        // It should start at the [ before the action so ["new" for example
-        if buffer[pos] == 93 {
+        // It should start at the [ before the action so ["new" for example. Anything else ends the side,
+        // the ] of the side or the end of the message when a snapshot has fewer levels than asked for
+        if buffer.get(pos) != Some(&b'[') {
             None
         } else {
             pos = pos + 2;
@@ -253,7 +266,7 @@ impl StreamingParser {
                 return None;
             }
             pos = pos + 2;
-            let (price, new_pos) = match Self::parse_f64_new_new(buffer, pos) {
+            let (price, new_pos) = match Self::parse_decimal(buffer, pos) {
                 Ok(ok) => ok,
                 Err(e) => {
                     return None;
@@ -267,7 +280,7 @@ impl StreamingParser {
                 }
             };
             pos = new_pos + 2;
-            let level = OrderbookLevel::from_action_number(action, price as f32, size as f32)?;
+            let level = OrderbookLevel::from_action_number(action, price, size as f32)?;
 
             Some((level, pos))
         }
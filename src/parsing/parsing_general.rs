@@ -2,6 +2,7 @@ use simd_json::BorrowedValue;
 use simd_json::value::prelude::*;
 use crate::parsing::{MessageParser, ParseError};
 use crate::parsing::exchange_message_type::{DeribitMessage, MessageType};
+use crate::deribit_helper::request_id;
 
 impl MessageParser {
     pub fn parse_bytes(buffer: &mut [u8]) -> Result<DeribitMessage, ParseError> {
@@ -20,6 +21,12 @@ impl MessageParser {
             MessageType::OrderbookUpdate => Self::parse_orderbook_update_owned(&value),
             MessageType::Unknown => Ok(DeribitMessage::Unknown),
             MessageType::Pong => Self::parse_ping_pong(&value),
+            MessageType::UserOrders => Self::parse_user_orders_owned(&value),
+            MessageType::UserTrades => Self::parse_user_trades_owned(&value),
+            MessageType::OpenOrders => Self::parse_open_orders_owned(&value),
+            MessageType::Positions => Self::parse_positions_owned(&value),
+            MessageType::Instruments => Self::parse_instruments_owned(&value),
+            MessageType::OrderbookRecovery => Self::parse_orderbook_recovery_owned(&value),
         }
     }
 
@@ -33,6 +40,12 @@ impl MessageParser {
                     if channel.starts_with("trades.") {
                         return Ok(MessageType::TradeUpdate);
                     }
+                    if channel.starts_with("user.orders.") {
+                        return Ok(MessageType::UserOrders);
+                    }
+                    if channel.starts_with("user.trades.") {
+                        return Ok(MessageType::UserTrades);
+                    }
                     if channel.starts_with("book.") {
                         if let Some(msg_type) = value.get("params")
                             .and_then(|p| p.get("data"))
@@ -52,6 +65,14 @@ impl MessageParser {
         }
 
         if let Some(result) = value.get("result") {
+            // replies sharing a shape with subscriptions (arrays) are told apart by their request id
+            match value.get("id").and_then(|i| i.as_u64()) {
+                Some(id) if request_id::OPEN_ORDERS.contains(&id) => return Ok(MessageType::OpenOrders),
+                Some(id) if request_id::POSITIONS.contains(&id) => return Ok(MessageType::Positions),
+                Some(request_id::INSTRUMENTS) => return Ok(MessageType::Instruments),
+                Some(id) if request_id::ORDER_BOOK.contains(&id) => return Ok(MessageType::OrderbookRecovery),
+                _ => {}
+            }
             if result.get("access_token").is_some() {
                 return Ok(MessageType::Auth);
             }
@@ -3,6 +3,7 @@ use simd_json::BorrowedValue;
 use simd_json::value::prelude::*;
 use crate::parsing::{MessageParser, ParseError};
 use crate::parsing::exchange_message_type::DeribitMessage;
+use crate::price::Decimal;
 
 
 // THIS IS DEFAULT PARSER, NOT USED ANYMORE
@@ -83,23 +84,68 @@ impl OrderbookAction {
 #[derive(Debug, Clone)]
 pub struct OrderbookLevel {
     pub action: OrderbookAction,
-    pub price: f32,
+    pub price: Decimal, // exact, turned into ticks by the book
     pub size: f32,
 }
 
 impl OrderbookLevel {
-    pub fn from_vec(action: String, price: f32, size: f32) -> Option<Self> {
+    // `price` as parsed to f64, not through f32 which would lose the decimal
+    pub fn from_vec(action: String, price: f64, size: f32) -> Option<Self> {
         let action = OrderbookAction::from_str(&action)?;
+        let price = Decimal::from_f64(price).ok()?;
         Some(Self { action, price, size })
     }
 
-    pub fn from_action_number(action: i32, price: f32, size: f32) -> Option<Self> {
+    pub fn from_action_number(action: i32, price: Decimal, size: f32) -> Option<Self> {
         let action = OrderbookAction::from_number(action)?;
         Some(Self { action, price, size })
     }
 }
 
+// public/get_order_book reply, levels are [price, amount] without action
+#[derive(Debug, Clone)]
+pub struct OrderbookRecoveryMessage {
+    pub id: u64,
+    pub timestamp: u64,
+    pub change_id: u64,
+    pub instrument_name: String,
+    pub bids: Vec<(f64, f64)>,
+    pub asks: Vec<(f64, f64)>,
+}
+
 impl MessageParser {
+    pub fn parse_orderbook_recovery_owned(value: &BorrowedValue) -> Result<DeribitMessage, ParseError> {
+        let id = Self::get_u64(value, "id")?;
+        let result = value.get("result")
+            .ok_or_else(|| ParseError::MissingField("result".to_string()))?;
+
+        let pairs = |field: &str| -> Result<Vec<(f64, f64)>, ParseError> {
+            result.get(field)
+                .and_then(|v| v.as_array())
+                .ok_or_else(|| ParseError::MissingField(field.to_string()))?
+                .iter()
+                .map(|level| {
+                    let arr = level.as_array()
+                        .filter(|arr| arr.len() == 2)
+                        .ok_or_else(|| ParseError::InvalidFormat("level not [price, amount]".to_string()))?;
+                    match (arr[0].cast_f64(), arr[1].cast_f64()) {
+                        (Some(price), Some(amount)) => Ok((price, amount)),
+                        _ => Err(ParseError::InvalidFormat("level not number".to_string())),
+                    }
+                })
+                .collect()
+        };
+
+        Ok(DeribitMessage::OrderbookRecovery(OrderbookRecoveryMessage {
+            id,
+            timestamp: Self::get_u64(result, "timestamp")?,
+            change_id: Self::get_u64(result, "change_id")?,
+            instrument_name: Self::get_string(result, "instrument_name")?,
+            bids: pairs("bids")?,
+            asks: pairs("asks")?,
+        }))
+    }
+
     pub fn parse_orderbook_snapshot_owned(value: &BorrowedValue) -> Result<DeribitMessage, ParseError> {
         let params = value.get("params")
             .ok_or_else(|| ParseError::MissingField("params".to_string()))?;
@@ -0,0 +1,163 @@
+use serde::Deserialize;
+use simd_json::BorrowedValue;
+use simd_json::value::prelude::*;
+use crate::parsing::{MessageParser, ParseError};
+use crate::parsing::exchange_message_type::DeribitMessage;
+
+// Private channels (user.orders / user.trades) and the replies used to reconcile them.
+// Low rate, so they go through the slow parser.
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserOrderMessage {
+    pub orders: Vec<UserOrder>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserTradeMessage {
+    pub trades: Vec<UserTrade>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OpenOrdersMessage {
+    pub id: u64,
+    pub orders: Vec<UserOrder>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PositionsMessage {
+    pub id: u64,
+    pub positions: Vec<UserPosition>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserOrder {
+    pub order_id: String,
+    pub instrument_name: String,
+    pub direction: i8,
+    pub order_state: String,
+    pub price: f64,
+    pub amount: f64,
+    pub filled_amount: f64,
+    pub average_price: f64,
+    pub last_update_timestamp: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserTrade {
+    pub trade_id: String,
+    pub order_id: String,
+    pub instrument_name: String,
+    pub direction: i8,
+    pub price: f64,
+    pub amount: f64,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserPosition {
+    pub instrument_name: String,
+    pub size: f64, // signed, negative when short
+    pub average_price: f64,
+}
+
+impl MessageParser {
+    pub fn parse_user_orders_owned(value: &BorrowedValue) -> Result<DeribitMessage, ParseError> {
+        let data = Self::get_params_data(value)?;
+        // raw channels push a single order, aggregated ones an array of orders
+        let orders = match data.as_array() {
+            Some(arr) => arr.iter().map(Self::parse_user_order).collect::<Result<Vec<_>, _>>()?,
+            None => vec![Self::parse_user_order(data)?],
+        };
+        Ok(DeribitMessage::UserOrders(UserOrderMessage { orders }))
+    }
+
+    pub fn parse_user_trades_owned(value: &BorrowedValue) -> Result<DeribitMessage, ParseError> {
+        let data = Self::get_params_data(value)?
+            .as_array()
+            .ok_or_else(|| ParseError::InvalidFormat("data not array".to_string()))?;
+        let trades: Result<Vec<UserTrade>, ParseError> = data
+            .iter()
+            .map(Self::parse_user_trade)
+            .collect();
+
+        Ok(DeribitMessage::UserTrades(UserTradeMessage { trades: trades? }))
+    }
+
+    pub fn parse_open_orders_owned(value: &BorrowedValue) -> Result<DeribitMessage, ParseError> {
+        let id = Self::get_u64(value, "id")?;
+        let result = Self::get_result_array(value)?;
+        let orders: Result<Vec<UserOrder>, ParseError> = result
+            .iter()
+            .map(Self::parse_user_order)
+            .collect();
+
+        Ok(DeribitMessage::OpenOrders(OpenOrdersMessage { id, orders: orders? }))
+    }
+
+    pub fn parse_positions_owned(value: &BorrowedValue) -> Result<DeribitMessage, ParseError> {
+        let id = Self::get_u64(value, "id")?;
+        let result = Self::get_result_array(value)?;
+        let positions: Result<Vec<UserPosition>, ParseError> = result
+            .iter()
+            .map(|p| Ok(UserPosition {
+                instrument_name: Self::get_string(p, "instrument_name")?,
+                size: Self::get_number(p, "size")?,
+                average_price: Self::get_number(p, "average_price")?,
+            }))
+            .collect();
+
+        Ok(DeribitMessage::Positions(PositionsMessage { id, positions: positions? }))
+    }
+
+    #[inline]
+    fn get_params_data<'v, 'a>(value: &'v BorrowedValue<'a>) -> Result<&'v BorrowedValue<'a>, ParseError> {
+        value.get("params")
+            .ok_or_else(|| ParseError::MissingField("params".to_string()))?
+            .get("data")
+            .ok_or_else(|| ParseError::MissingField("data".to_string()))
+    }
+
+    #[inline]
+    fn get_result_array<'v, 'a>(value: &'v BorrowedValue<'a>) -> Result<&'v Vec<BorrowedValue<'a>>, ParseError> {
+        value.get("result")
+            .ok_or_else(|| ParseError::MissingField("result".to_string()))?
+            .as_array()
+            .ok_or_else(|| ParseError::InvalidFormat("result not array".to_string()))
+    }
+
+    #[inline]
+    fn parse_direction(value: &BorrowedValue) -> Result<i8, ParseError> {
+        match Self::get_string(value, "direction")?.as_str() {
+            "buy" => Ok(1),
+            "sell" => Ok(-1),
+            other => Err(ParseError::InvalidFormat(format!("unknown direction {}", other))),
+        }
+    }
+
+    fn parse_user_order(value: &BorrowedValue) -> Result<UserOrder, ParseError> {
+        Ok(UserOrder {
+            order_id: Self::get_string(value, "order_id")?,
+            instrument_name: Self::get_string(value, "instrument_name")?,
+            direction: Self::parse_direction(value)?,
+            order_state: Self::get_string(value, "order_state")?,
+            // market orders have no limit price
+            price: Self::get_number(value, "price").unwrap_or(0.0),
+            amount: Self::get_number(value, "amount")?,
+            filled_amount: Self::get_number(value, "filled_amount").unwrap_or(0.0),
+            average_price: Self::get_number(value, "average_price").unwrap_or(0.0),
+            last_update_timestamp: Self::get_u64(value, "last_update_timestamp")?,
+        })
+    }
+
+    fn parse_user_trade(value: &BorrowedValue) -> Result<UserTrade, ParseError> {
+        Ok(UserTrade {
+            trade_id: Self::get_string(value, "trade_id")?,
+            order_id: Self::get_string(value, "order_id")?,
+            instrument_name: Self::get_string(value, "instrument_name")?,
+            direction: Self::parse_direction(value)?,
+            price: Self::get_number(value, "price")?,
+            amount: Self::get_number(value, "amount")?,
+            timestamp: Self::get_u64(value, "timestamp")?,
+        })
+    }
+}
@@ -0,0 +1,194 @@
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum PriceError {
+    #[error("invalid tick size {0}")]
+    InvalidTickSize(f64),
+    #[error("invalid decimal {0}")]
+    InvalidDecimal(String),
+    #[error("price {mantissa}e{exponent} cannot be expressed in ticks")]
+    OutOfRange { mantissa: i64, exponent: i32 },
+}
+
+// Exact decimal as sent by Deribit: mantissa * 10^exponent, "3770.65" is (377065, -2), "3.9e3" is (39, 2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decimal {
+    pub mantissa: i64,
+    pub exponent: i32,
+}
+
+impl Decimal {
+    #[inline]
+    pub const fn new(mantissa: i64, exponent: i32) -> Self {
+        Self { mantissa, exponent }
+    }
+
+    #[inline]
+    pub fn to_f64(&self) -> f64 {
+        self.mantissa as f64 * 10f64.powi(self.exponent)
+    }
+
+    // For values that already went through a float (slow parser, metadata), the shortest
+    // representation printed by Rust round-trips, so it is the decimal the exchange sent
+    pub fn from_f64(value: f64) -> Result<Self, PriceError> {
+        if !value.is_finite() {
+            return Err(PriceError::InvalidDecimal(value.to_string()));
+        }
+        Self::parse(&format!("{}", value))
+    }
+
+    pub fn parse(s: &str) -> Result<Self, PriceError> {
+        let invalid = || PriceError::InvalidDecimal(s.to_string());
+        let (number, exp_part) = match s.find(['e', 'E']) {
+            Some(idx) => (&s[..idx], Some(&s[idx + 1..])),
+            None => (s, None),
+        };
+        let (int_part, frac_part) = number.split_once('.').unwrap_or((number, ""));
+        let negative = int_part.starts_with('-');
+        let int_digits = int_part.trim_start_matches(['-', '+']);
+        if int_digits.is_empty() && frac_part.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut mantissa: i64 = 0;
+        for c in int_digits.chars().chain(frac_part.chars()) {
+            let digit = c.to_digit(10).ok_or_else(invalid)? as i64;
+            mantissa = mantissa
+                .checked_mul(10)
+                .and_then(|m| m.checked_add(digit))
+                .ok_or_else(invalid)?;
+        }
+        let mut exponent = -(frac_part.len() as i32);
+        if let Some(exp_part) = exp_part {
+            exponent += exp_part.parse::<i32>().map_err(|_| invalid())?;
+        }
+        Ok(Self::new(if negative { -mantissa } else { mantissa }, exponent))
+    }
+}
+
+// What the size of a level or a trade is counted in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ContractType {
+    // size in units of the base currency (linear USDC contracts, options): notional = price * size
+    #[default]
+    Linear,
+    // Deribit BTC / ETH futures and perpetuals: the size is already the USD notional
+    Inverse,
+}
+
+impl ContractType {
+    // Notional in the quote currency of `size` traded or quoted at `price`
+    #[inline]
+    pub fn notional(self, price: f64, size: f64) -> f64 {
+        match self {
+            ContractType::Linear => price * size,
+            ContractType::Inverse => size,
+        }
+    }
+}
+
+// A price converted to ticks, `off_grid` when it was not a multiple of the tick size and got rounded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ticks {
+    pub ticks: i64,
+    pub off_grid: bool,
+}
+
+// Instrument tick size, prices in the books are integer multiples of it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TickSize {
+    tick: Decimal,
+    value: f64,
+}
+
+impl TickSize {
+    pub fn new(tick_size: f64) -> Result<Self, PriceError> {
+        let tick = Decimal::from_f64(tick_size).map_err(|_| PriceError::InvalidTickSize(tick_size))?;
+        if tick.mantissa <= 0 {
+            return Err(PriceError::InvalidTickSize(tick_size));
+        }
+        Ok(Self { tick, value: tick_size })
+    }
+
+    #[inline]
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    // Exact for prices on the tick grid, prices off the grid are rounded to the nearest tick.
+    // An exponent too far from the tick one would overflow, the price is refused.
+    #[inline]
+    pub fn to_ticks(&self, price: Decimal) -> Result<Ticks, PriceError> {
+        let out_of_range = || PriceError::OutOfRange { mantissa: price.mantissa, exponent: price.exponent };
+        let shift = price.exponent - self.tick.exponent;
+        let scale = 10i128.checked_pow(shift.unsigned_abs()).ok_or_else(out_of_range)?;
+        let (numerator, denominator) = if shift >= 0 {
+            ((price.mantissa as i128).checked_mul(scale).ok_or_else(out_of_range)?, self.tick.mantissa as i128)
+        } else {
+            (price.mantissa as i128, (self.tick.mantissa as i128).checked_mul(scale).ok_or_else(out_of_range)?)
+        };
+        let ticks = numerator / denominator;
+        let remainder = numerator % denominator;
+        let rounded = if remainder.abs() * 2 >= denominator {
+            ticks + numerator.signum()
+        } else {
+            ticks
+        };
+        Ok(Ticks { ticks: i64::try_from(rounded).map_err(|_| out_of_range())?, off_grid: remainder != 0 })
+    }
+
+    // For prices that already went through a float (trades are f32 in TradeEvent), the nearest tick
+    #[inline]
+    pub fn nearest_ticks(&self, price: f64) -> i64 {
+        (price / self.value).round() as i64
+    }
+
+    // Only used when publishing
+    #[inline]
+    pub fn to_price(&self, ticks: i64) -> f64 {
+        let price = Decimal::new(ticks * self.tick.mantissa, self.tick.exponent);
+        price.to_f64()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decimal_parse() {
+        assert_eq!(Decimal::parse("3770.65").unwrap(), Decimal::new(377065, -2));
+        assert_eq!(Decimal::parse("3.9e3").unwrap(), Decimal::new(39, 2));
+        assert_eq!(Decimal::parse("1.5e-4").unwrap(), Decimal::new(15, -5));
+        assert_eq!(Decimal::parse("-2").unwrap(), Decimal::new(-2, 0));
+        assert_eq!(Decimal::from_f64(0.0001).unwrap(), Decimal::new(1, -4));
+        assert!(Decimal::parse("abc").is_err());
+        assert!(Decimal::parse("").is_err());
+    }
+
+    #[test]
+    fn test_ticks_are_exact() {
+        let tick = TickSize::new(0.001).unwrap();
+        // f32 steps by ~0.008 around 100k, both are the same f32 but not the same tick
+        assert_eq!(100000.001f32, 100000.002f32);
+        let a = tick.to_ticks(Decimal::parse("100000.001").unwrap()).unwrap().ticks;
+        let b = tick.to_ticks(Decimal::parse("100000.002").unwrap()).unwrap().ticks;
+        assert_eq!(a, 100000001);
+        assert_eq!(b, 100000002);
+        assert_eq!(tick.to_price(a), 100000.001);
+
+        let tick = TickSize::new(0.05).unwrap();
+        assert_eq!(tick.to_ticks(Decimal::parse("3.9e3").unwrap()).unwrap(), Ticks { ticks: 78000, off_grid: false });
+        assert_eq!(tick.to_ticks(Decimal::parse("3770.65").unwrap()).unwrap(), Ticks { ticks: 75413, off_grid: false });
+        assert_eq!(tick.to_ticks(Decimal::parse("3770.66").unwrap()).unwrap(), Ticks { ticks: 75413, off_grid: true });
+    }
+
+    #[test]
+    fn test_ticks_out_of_range() {
+        let tick = TickSize::new(0.5).unwrap();
+        assert!(matches!(tick.to_ticks(Decimal::parse("1e40").unwrap()), Err(PriceError::OutOfRange { .. })));
+        assert!(matches!(tick.to_ticks(Decimal::parse("1e-40").unwrap()), Err(PriceError::OutOfRange { .. })));
+        assert!(matches!(tick.to_ticks(Decimal::parse("9e30").unwrap()), Err(PriceError::OutOfRange { .. })));
+    }
+}
@@ -0,0 +1,331 @@
+use std::path::{Path, PathBuf};
+use tokio::signal;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{Duration, Instant};
+use tracing::{info, warn};
+use haiku_common::metadata::ShmMetadata;
+use haiku_common::shm_accessor::market_data_type::TradeEvent;
+use crate::config_global::Config;
+use crate::deribit::{ParsedFrame, parse_frame, router_task};
+use crate::journal::{JournalReader, journal_files};
+use crate::parsing::MessageParser;
+use crate::parsing::exchange_message_type::DeribitMessage;
+use crate::parsing::parsing_admin::InstrumentInfo;
+use crate::parsing::parsing_fast::StreamingParser;
+use crate::parsing::parsing_fast_orderbook::OrderbookResult;
+use crate::shm_writer::WriterClock;
+use crate::sinks::SinkConfig;
+use crate::sinks::sink_export::ExportSinkConfig;
+use crate::{books_without_tick_size, contract_types_by_index, spawn_writer, tick_sizes_by_index, writer_setup};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pacing {
+    // relative to the recorded receive times, 1.0 is real time
+    Speed(f64),
+    Asap,
+}
+
+impl Pacing {
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Pacing::Speed(speed) => speed.is_finite() && *speed > 0.0,
+            Pacing::Asap => true,
+        }
+    }
+
+    // When a frame received `elapsed_ns` after the first one is due, from the start of the replay
+    #[inline]
+    pub fn offset(&self, elapsed_ns: u64) -> Option<Duration> {
+        match self {
+            Pacing::Speed(speed) => Some(Duration::from_secs_f64(elapsed_ns as f64 / 1e9 / speed)),
+            Pacing::Asap => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    // a journal file or a directory of them
+    pub journal: PathBuf,
+    pub pacing: Pacing,
+}
+
+#[derive(Debug, Default)]
+struct ReplayStats {
+    frames: u64,
+    parse_errors: u64,
+}
+
+fn journal_paths(journal: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let files = if journal.is_dir() { journal_files(journal)? } else { vec![journal.to_path_buf()] };
+    if files.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("no journal file in {}", journal.display())));
+    }
+    Ok(files)
+}
+
+// The public/get_instruments reply recorded at startup, it gives the tick sizes the books were built with
+fn recorded_instruments(files: &[PathBuf]) -> std::io::Result<Option<Vec<InstrumentInfo>>> {
+    for path in files {
+        for frame in JournalReader::open(path)? {
+            let Ok(frame) = frame else { break };
+            if !frame.payload.contains("\"tick_size\"") {
+                continue;
+            }
+            let mut buffer = frame.payload.into_bytes();
+            if let Ok(DeribitMessage::Instruments(msg)) = MessageParser::parse_bytes(&mut buffer) {
+                return Ok(Some(msg.instruments));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Every frame goes through the same parsers and channels as on the live connection, in recorded order.
+// Unlike the websocket task the sends wait for room: a replay must not lose what the writer is slow to take.
+async fn feed(
+    files: Vec<PathBuf>,
+    pacing: Pacing,
+    streaming_parser: StreamingParser,
+    fast_trade_tx: mpsc::Sender<TradeEvent>,
+    fast_orderbook_tx: mpsc::Sender<OrderbookResult>,
+    parsed_tx: mpsc::Sender<DeribitMessage>,
+) -> std::io::Result<ReplayStats> {
+    let mut parse_buffer = Vec::with_capacity(4096);
+    let mut stats = ReplayStats::default();
+    let mut first_ts: Option<u64> = None;
+    let start = Instant::now();
+
+    for path in files {
+        info!("replay: reading {}", path.display());
+        for frame in JournalReader::open(&path)? {
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(e) => {
+                    warn!("replay: {} ends early: {}", path.display(), e);
+                    break;
+                }
+            };
+            let origin_ts = *first_ts.get_or_insert(frame.recv_ts_ns);
+            if let Some(offset) = pacing.offset(frame.recv_ts_ns.saturating_sub(origin_ts)) {
+                tokio::time::sleep_until(start + offset).await;
+            }
+            stats.frames += 1;
+
+            // a closed channel means the pipeline stopped, nothing left to feed
+            let sent = match parse_frame(&streaming_parser, &mut parse_buffer, &frame.payload) {
+                Ok(ParsedFrame::Trades(trades)) => {
+                    let mut sent = true;
+                    for trade in trades {
+                        sent &= fast_trade_tx.send(trade).await.is_ok();
+                    }
+                    sent
+                }
+                Ok(ParsedFrame::Orderbook(orderbook)) => fast_orderbook_tx.send(orderbook).await.is_ok(),
+                Ok(ParsedFrame::Other(msg)) => parsed_tx.send(msg).await.is_ok(),
+                Err((parser, e)) => {
+                    warn!("replay: {} error on frame {}: {}", parser, stats.frames, e);
+                    stats.parse_errors += 1;
+                    true
+                }
+            };
+            if !sent {
+                warn!("replay: the pipeline stopped, ending the replay");
+                return Ok(stats);
+            }
+        }
+    }
+    Ok(stats)
+}
+
+// A batch export is a replay as fast as possible into the export sink only, with every book update written
+pub fn export_config(mut cfg: Config, export: ExportSinkConfig) -> Config {
+    cfg.sinks = vec![SinkConfig::Export(ExportSinkConfig { wait_when_full: true, ..export })];
+    cfg.analytics = None;
+    cfg.order_flow = None;
+    cfg.bars = None;
+    cfg.default_conflation_us = 0;
+    for book_depth in &mut cfg.book_depth {
+        book_depth.conflation_us = None;
+    }
+    // not the segment of a feed handler running on the same host
+    cfg.book_change_shm = format!("{}_export", cfg.book_change_shm);
+    cfg
+}
+
+// Drives the recorded frames through the parsers, the books and the sinks of the config. Nothing is sent
+// to the exchange: resyncs and recoveries the writer asks for only happen if the journal recorded them.
+pub async fn run(cfg: &Config, metadata: &ShmMetadata, options: ReplayOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let files = journal_paths(&options.journal)?;
+    let instrument_index = metadata.clone_instrument_index();
+    let nb_instruments = metadata.max_instruments;
+
+    let Some(instruments) = recorded_instruments(&files)? else {
+        return Err(format!("no instruments reply in {}, the journal must cover the startup of the feed handler", options.journal.display()).into());
+    };
+    let tick_sizes = tick_sizes_by_index(&instruments, &instrument_index, nb_instruments);
+    let contract_types = contract_types_by_index(&instruments, &instrument_index, nb_instruments);
+    let missing = books_without_tick_size(&cfg.channels, &instrument_index, &tick_sizes);
+    if !missing.is_empty() {
+        warn!("replay: no recorded tick size for books {:?}, their updates are dropped", missing);
+    }
+    let snapshot_depths = cfg
+        .book_settings(&instrument_index, nb_instruments)
+        .iter()
+        .map(|settings| settings.snapshot_levels())
+        .collect();
+    let streaming_parser = StreamingParser::new(instrument_index).with_snapshot_depths(snapshot_depths);
+
+    let (parsed_tx, parsed_rx) = mpsc::channel(100);
+    let (control_tx, mut control_rx) = mpsc::channel(100);
+    let (fast_trade_tx, fast_trade_rx) = mpsc::channel(1000);
+    let (fast_orderbook_tx, fast_orderbook_rx) = mpsc::channel(1000);
+    let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+
+    let router = tokio::spawn(router_task(parsed_rx, control_tx, fast_orderbook_tx.clone(), shutdown_tx.subscribe()));
+    let setup = writer_setup(cfg, metadata, &tick_sizes, &contract_types, WriterClock::recorded())?;
+    let (_writer_cmd_tx, mut writer_event_rx, writer) = spawn_writer(setup, fast_trade_rx, fast_orderbook_rx, shutdown_rx);
+    tokio::spawn(async move {
+        while let Some(event) = writer_event_rx.recv().await {
+            warn!("replay: writer asked for {:?}, nothing is requested during a replay", event);
+        }
+    });
+    // replies and private updates of the recording, the order tracker is not replayed
+    tokio::spawn(async move { while control_rx.recv().await.is_some() {} });
+
+    info!("replay: {} file(s), pacing {:?}", files.len(), options.pacing);
+    let started = Instant::now();
+    tokio::select! {
+        stats = feed(files, options.pacing, streaming_parser, fast_trade_tx, fast_orderbook_tx, parsed_tx) => {
+            let stats = stats?;
+            info!("replay: {} frames in {:?}, {} parse errors", stats.frames, started.elapsed(), stats.parse_errors);
+        }
+        _ = signal::ctrl_c() => {
+            warn!("replay: interrupted");
+            let _ = shutdown_tx.send(());
+        }
+    }
+
+    // the senders are gone, the router then the writer stop once they drained what was fed
+    router.await??;
+    writer.await??;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use haiku_common::shm_accessor::market_data_type::OrderbookData;
+    use crate::deribit_helper::request_id;
+    use crate::journal::{JournalFrame, JournalWriter};
+    use crate::orderbook_management::BookSettings;
+    use crate::shm_slots::ShmSlots;
+    use crate::shm_writer::{WriterSetup, shm_writer_task};
+    use crate::sinks::{FanOutSink, MarketDataSink, SinkError};
+
+    // (instrument_idx, timestamp, flag, bid sizes) of every book it is sent
+    type PublishedBooks = Arc<Mutex<Vec<(usize, u64, u8, Vec<f32>)>>>;
+
+    struct RecordingSink(PublishedBooks);
+
+    impl MarketDataSink for RecordingSink {
+        fn name(&self) -> &str {
+            "recording"
+        }
+
+        fn on_book(&mut self, instrument_idx: usize, book: &OrderbookData, timestamp: u64, flag: u8) -> Result<(), SinkError> {
+            self.0.lock().unwrap().push((instrument_idx, timestamp, flag, book.bid_sizes.to_vec()));
+            Ok(())
+        }
+
+        fn on_trade(&mut self, _trade: &TradeEvent) -> Result<(), SinkError> {
+            Ok(())
+        }
+    }
+
+    // The instruments reply, a snapshot at 1000ms then a delta every ms up to 1020ms
+    fn write_journal(dir: &Path) -> Vec<PathBuf> {
+        let mut payloads = vec![
+            format!(r#"{{"jsonrpc":"2.0","id":{},"result":[{{"instrument_name":"BTC-PERPETUAL","tick_size":0.5,"kind":"future","instrument_type":"reversed"}}]}}"#, request_id::INSTRUMENTS),
+            r#"{"jsonrpc":"2.0","method":"subscription","params":{"channel":"book.BTC-PERPETUAL.raw","data":{"timestamp":1000,"type":"snapshot","change_id":10,"instrument_name":"BTC-PERPETUAL","bids":[["new",100.0,10.0],["new",99.5,5.0]],"asks":[["new",100.5,10.0]]}}}"#.to_string(),
+        ];
+        for i in 1..=20u64 {
+            payloads.push(format!(
+                r#"{{"jsonrpc":"2.0","method":"subscription","params":{{"channel":"book.BTC-PERPETUAL.raw","data":{{"timestamp":{},"type":"change","change_id":{},"instrument_name":"BTC-PERPETUAL","bids":[["change",100.0,{}.0]],"asks":[],"prev_change_id":{}}}}}}}"#,
+                1000 + i, 10 + i, 10 + i, 9 + i,
+            ));
+        }
+        let mut writer = JournalWriter::new(dir.to_path_buf(), 1 << 20, Duration::from_secs(3600));
+        for (i, payload) in payloads.into_iter().enumerate() {
+            writer.append(&JournalFrame { connection_id: 1, recv_ts_ns: 1_000_000 * i as u64, payload }).unwrap();
+        }
+        writer.close().unwrap();
+        journal_files(dir).unwrap()
+    }
+
+    // Journal -> parsers -> books -> conflation -> sink, like `run` without the SHM segments of the config
+    async fn replay_books(files: Vec<PathBuf>, run: usize) -> Vec<(usize, u64, u8, Vec<f32>)> {
+        let instrument_index = HashMap::from([("BTC-PERPETUAL".to_string(), 0usize)]);
+        let instruments = recorded_instruments(&files).unwrap().unwrap();
+        let tick_sizes = tick_sizes_by_index(&instruments, &instrument_index, 1);
+        let book_settings = vec![BookSettings { depth: 10, full_depth: false, conflation: Duration::from_millis(5) }];
+
+        let (parsed_tx, mut parsed_rx) = mpsc::channel(100);
+        tokio::spawn(async move { while parsed_rx.recv().await.is_some() {} });
+        let (fast_trade_tx, fast_trade_rx) = mpsc::channel(1000);
+        let (fast_orderbook_tx, fast_orderbook_rx) = mpsc::channel(1000);
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let (_command_tx, command_rx) = mpsc::channel(1);
+        let (event_tx, _event_rx) = mpsc::channel(16);
+
+        let books = PublishedBooks::default();
+        let sink = FanOutSink::new(vec![Box::new(RecordingSink(books.clone()))]);
+        let slots_path = std::env::temp_dir().join(format!("haiku_fh_replay_test_{}_{}", std::process::id(), run));
+        let book_changes = ShmSlots::create_at(&slots_path, 1).unwrap();
+        let setup = WriterSetup {
+            sink,
+            analytics: None,
+            order_flow: None,
+            book_changes,
+            trade_checks: None,
+            bars: None,
+            book_settings,
+            tick_sizes,
+            max_crossed: Duration::from_secs(60),
+            clock: WriterClock::recorded(),
+        };
+        let writer = tokio::spawn(shm_writer_task(fast_trade_rx, fast_orderbook_rx, shutdown_rx, command_rx, event_tx, setup));
+        feed(files, Pacing::Asap, StreamingParser::new(instrument_index), fast_trade_tx, fast_orderbook_tx, parsed_tx).await.unwrap();
+        writer.await.unwrap().unwrap();
+        std::fs::remove_file(slots_path).unwrap();
+        Arc::try_unwrap(books).unwrap().into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_replays_publish_the_same_books() {
+        let dir = std::env::temp_dir().join(format!("haiku_fh_replay_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = write_journal(&dir);
+
+        let first = replay_books(files.clone(), 0).await;
+        let second = replay_books(files, 1).await;
+        assert_eq!(first, second);
+        // conflated on the recorded clock: the snapshot, then a write every 5ms of exchange time
+        let timestamps: Vec<u64> = first.iter().map(|&(_, timestamp, _, _)| timestamp).collect();
+        assert_eq!(timestamps, vec![1000, 1005, 1010, 1015, 1020]);
+        assert_eq!(first[4].3[0], 30.0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pacing() {
+        assert_eq!(Pacing::Speed(1.0).offset(1_500_000_000), Some(Duration::from_millis(1_500)));
+        assert_eq!(Pacing::Speed(4.0).offset(2_000_000_000), Some(Duration::from_millis(500)));
+        assert_eq!(Pacing::Asap.offset(2_000_000_000), None);
+        assert!(!Pacing::Speed(0.0).is_valid());
+        assert!(!Pacing::Speed(f64::NAN).is_valid());
+    }
+}
@@ -0,0 +1,137 @@
+use memmap2::MmapMut;
+use std::fs::OpenOptions;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering, fence};
+
+// One fixed size record per instrument index in a memory mapped file, for the data SHMAccessor has no room for.
+// Each slot is a seqlock: the sequence is odd while the writer is in the middle of an update,
+// readers retry until they see the same even sequence before and after copying the record.
+//
+// Layout: header (magic, record size, number of slots), then for each slot a u64 sequence and the record.
+
+const MAGIC: u64 = 0x4841_494b_5553_4c54; // "HAIKUSLT"
+const HEADER_SIZE: usize = 3 * size_of::<u64>();
+
+pub struct ShmSlots<T: Copy> {
+    mmap: MmapMut,
+    nb_slots: usize,
+    _record: PhantomData<T>,
+}
+
+impl<T: Copy> ShmSlots<T> {
+    const SLOT_SIZE: usize = (size_of::<u64>() + size_of::<T>()).next_multiple_of(size_of::<u64>());
+
+    // /dev/shm/<name>, next to the segments of SHMAccessor
+    pub fn create(name: &str, nb_slots: usize) -> std::io::Result<Self> {
+        Self::create_at(Path::new("/dev/shm").join(name), nb_slots)
+    }
+
+    pub fn create_at(path: impl AsRef<Path>, nb_slots: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len((HEADER_SIZE + nb_slots * Self::SLOT_SIZE) as u64)?;
+        let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+
+        for (i, value) in [MAGIC, size_of::<T>() as u64, nb_slots as u64].iter().enumerate() {
+            mmap[i * 8..(i + 1) * 8].copy_from_slice(&value.to_ne_bytes());
+        }
+        Ok(Self { mmap, nb_slots, _record: PhantomData })
+    }
+
+    #[inline]
+    pub fn nb_slots(&self) -> usize {
+        self.nb_slots
+    }
+
+    #[inline(always)]
+    fn slot_offset(idx: usize) -> usize {
+        HEADER_SIZE + idx * Self::SLOT_SIZE
+    }
+
+    #[inline(always)]
+    fn sequence(&self, idx: usize) -> &AtomicU64 {
+        // the offset is 8 bytes aligned and the mapping page aligned
+        unsafe { &*(self.mmap.as_ptr().add(Self::slot_offset(idx)) as *const AtomicU64) }
+    }
+
+    pub fn write(&mut self, idx: usize, record: &T) {
+        if idx >= self.nb_slots {
+            return;
+        }
+        let seq = self.sequence(idx).load(Ordering::Relaxed);
+        self.sequence(idx).store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe {
+            let data = self.mmap.as_mut_ptr().add(Self::slot_offset(idx) + size_of::<u64>()) as *mut T;
+            std::ptr::write_unaligned(data, *record);
+        }
+        self.sequence(idx).store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    // Number of writes to the slot, enough for a reader to know whether it changed
+    #[inline]
+    pub fn version(&self, idx: usize) -> u64 {
+        if idx >= self.nb_slots {
+            return 0;
+        }
+        self.sequence(idx).load(Ordering::Acquire) / 2
+    }
+
+    // Latest consistent record and its sequence, None if the slot was never written
+    pub fn read(&self, idx: usize) -> Option<(u64, T)> {
+        if idx >= self.nb_slots {
+            return None;
+        }
+        loop {
+            let before = self.sequence(idx).load(Ordering::Acquire);
+            if before == 0 {
+                return None;
+            }
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let record = unsafe {
+                let data = self.mmap.as_ptr().add(Self::slot_offset(idx) + size_of::<u64>()) as *const T;
+                std::ptr::read_unaligned(data)
+            };
+            fence(Ordering::Acquire);
+            if self.sequence(idx).load(Ordering::Relaxed) == before {
+                return Some((before / 2, record));
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    #[repr(C)]
+    struct Record {
+        a: f64,
+        b: u32,
+    }
+
+    #[test]
+    fn test_write_then_read() {
+        let path = std::env::temp_dir().join(format!("haiku_fh_slots_test_{}", std::process::id()));
+        let mut slots = ShmSlots::<Record>::create_at(&path, 4).unwrap();
+        assert_eq!(slots.read(1), None);
+
+        slots.write(1, &Record { a: 1.5, b: 7 });
+        slots.write(1, &Record { a: 2.5, b: 8 });
+        assert_eq!(slots.read(1), Some((2, Record { a: 2.5, b: 8 })));
+        assert_eq!(slots.read(0), None);
+        // out of range writes are ignored
+        slots.write(4, &Record { a: 0.0, b: 0 });
+        std::fs::remove_file(path).unwrap();
+    }
+}
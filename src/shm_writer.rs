@@ -1,98 +1,517 @@
+use crate::bars::BarPublisher;
+use crate::book_analytics::AnalyticsPublisher;
+use crate::book_dump::BookDump;
+use crate::conflation::{BookChange, Conflator, DuePublish};
 use crate::deribit_helper::DeribitError;
-use crate::orderbook_management::OrderbookManagerV2;
+use crate::health::HEALTH;
+use crate::metrics::{METRICS, Queue};
+use crate::order_flow::{OrderFlowPublisher, PendingFlow};
+use crate::orderbook_management::{BookSettings, OrderbookError, OrderbookManagerV2};
 use crate::parsing::parsing_fast_orderbook::OrderbookResult;
+use crate::price::TickSize;
+use crate::shm_slots::ShmSlots;
+use crate::trade_checks::TradeChecker;
 use haiku_common::latency_tracker::LatencyTracker;
-use haiku_common::shm_accessor::SHMAccessor;
-use haiku_common::shm_accessor::market_data_type::TradeEvent;
-use haiku_common::shm_accessor::trade_ring_buffer::TradeRingBuffer;
-use tokio::sync::{broadcast, mpsc};
+use crate::sinks::{FanOutSink, SinkStatus};
+use haiku_common::shm_accessor::market_data_type::{OrderbookData, TradeEvent};
+use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio::time::{Duration, Instant};
-use tracing::{error, info, warn};
+use tracing::{info, warn};
 
+// Requests from the control side, they never go through the hot path
+#[derive(Debug)]
+pub enum WriterCommand {
+    // the instrument is no longer subscribed, its book will be rebuilt from the next snapshot
+    ResetBook(usize),
+    // copy of the state of every book, for inspection
+    DumpBooks(oneshot::Sender<Vec<BookDump>>),
+    // the exchange changed the tick size of the instrument, its book is rebuilt in the new ticks
+    SetTickSize(usize, TickSize),
+}
+
+// Requests from the writer to the control side
+#[derive(Debug)]
+pub enum WriterEvent {
+    // the book stayed crossed or locked, or its tick size changed: it has been reset and needs a new snapshot
+    ResyncBook(usize),
+    // a delta is missing, the book has been reset and can be rebuilt from public/get_order_book
+    RecoverBook(usize),
+}
+
+// Snapshots replace the book, deltas are applied on top of it. None when there is nothing to publish,
+// otherwise the book and the exchange timestamp of its last update.
+fn apply_to_book(
+    ob_manager: &mut [Option<OrderbookManagerV2>],
+    orderbook_update: OrderbookResult,
+    order_flow: &mut Option<OrderFlowPublisher>,
+    trade_checks: &mut Option<TradeChecker>,
+    sink: &mut FanOutSink,
+    event_tx: &mpsc::Sender<WriterEvent>,
+    now: std::time::Instant,
+) -> Option<(OrderbookData, u64)> {
+    let instrument_idx = orderbook_update.instrument_idx;
+    METRICS.on_book_update(instrument_idx);
+    let Some(book) = ob_manager[instrument_idx].as_mut() else {
+        warn!("shm_writer_task: no tick size for book {}, update dropped", instrument_idx);
+        return None;
+    };
+    let (change_id, timestamp, is_snapshot) = (orderbook_update.change_id, orderbook_update.timestamp, orderbook_update.is_snapshot);
+    let mut latest = apply_one(book, orderbook_update, order_flow, trade_checks, sink, event_tx, now).map(|ob_data| (ob_data, timestamp));
+    if is_snapshot && latest.is_some() {
+        // deltas received while waiting for the recovery snapshot and not covered by it, the change_id
+        // chain is checked by apply_update, a hole starts another recovery
+        for delta in book.end_recovery(change_id) {
+            let delta_timestamp = delta.timestamp;
+            let delta = OrderbookResult::new(delta.change_id, delta.timestamp, instrument_idx, delta.update, false);
+            if let Some(ob_data) = apply_one(book, delta, order_flow, trade_checks, sink, event_tx, now) {
+                latest = Some((ob_data, delta_timestamp));
+            }
+        }
+        if !book.is_initialized() {
+            latest = None;
+        }
+    }
+    latest
+}
+
+// The order flow only comes from deltas, a snapshot is not something that traded or was quoted.
+fn apply_one(
+    book: &mut OrderbookManagerV2,
+    orderbook_update: OrderbookResult,
+    order_flow: &mut Option<OrderFlowPublisher>,
+    trade_checks: &mut Option<TradeChecker>,
+    sink: &mut FanOutSink,
+    event_tx: &mpsc::Sender<WriterEvent>,
+    now: std::time::Instant,
+) -> Option<OrderbookData> {
+    let instrument_idx = orderbook_update.instrument_idx;
+    let (change_id, timestamp) = (orderbook_update.change_id, orderbook_update.timestamp);
+    if !orderbook_update.is_snapshot && book.is_recovering() {
+        book.buffer_delta(orderbook_update.update_data, change_id, timestamp);
+        return None;
+    }
+    if let Some(checker) = trade_checks.as_mut() {
+        // only what the book is about to apply, not stale or out of sequence deltas
+        let applies = orderbook_update.is_snapshot
+            || (book.is_initialized() && orderbook_update.update_data.prev_change_id == book.last_change_id());
+        if applies {
+            checker.on_book_update(instrument_idx, book, &orderbook_update.update_data, timestamp);
+        }
+    }
+    let result = if orderbook_update.is_snapshot {
+        book.apply_snapshot(orderbook_update.update_data, change_id, now)
+    } else {
+        let pending = order_flow.as_ref().map(|_| PendingFlow::observe(book, &orderbook_update.update_data));
+        let result = book.apply_update(orderbook_update.update_data, change_id, now);
+        if let (Ok(_), Some(publisher), Some(pending)) = (&result, order_flow.as_mut(), pending) {
+            publisher.record(instrument_idx, timestamp, change_id, &pending.complete(book));
+        }
+        result
+    };
+    match result {
+        Ok(ob_data) => {
+            if orderbook_update.is_snapshot {
+                sink.on_status(SinkStatus::BookSnapshot(instrument_idx));
+            }
+            Some(ob_data)
+        }
+        // before the first snapshot, or deltas older than it
+        Err(OrderbookError::NotInitialized) | Err(OrderbookError::StaleUpdate { .. }) => None,
+        Err(e @ OrderbookError::SequenceGap { .. }) => {
+            warn!("shm_writer_task: book {} {}, recovering from a snapshot", instrument_idx, e);
+            METRICS.on_gap(instrument_idx);
+            book.reset();
+            book.start_recovery();
+            if let Some(checker) = trade_checks.as_mut() {
+                checker.reset(instrument_idx);
+            }
+            sink.on_status(SinkStatus::BookReset(instrument_idx));
+            METRICS.on_send(Queue::WriterEvents, event_tx.try_send(WriterEvent::RecoverBook(instrument_idx)));
+            None
+        }
+        Err(e) => {
+            warn!("shm_writer_task: update dropped for book {}: {}", instrument_idx, e);
+            None
+        }
+    }
+}
+
+// The trade is checked against the book as it is before being pushed
+fn handle_trade(
+    trade: TradeEvent,
+    sink: &mut FanOutSink,
+    ob_manager: &[Option<OrderbookManagerV2>],
+    trade_checks: &mut Option<TradeChecker>,
+    bars: &mut Option<BarPublisher>,
+) {
+    if let (Some(checker), Some(Some(book))) = (trade_checks.as_mut(), ob_manager.get(trade.instrument_idx as usize)) {
+        checker.on_trade(&trade, book);
+    }
+    METRICS.on_trade(trade.instrument_idx as usize);
+    sink.on_trade(&trade);
+    if let Some(bars) = bars.as_mut() {
+        bars.on_trade(&trade);
+    }
+}
+
+// After a reset, an empty book replaces the last one written so readers do not keep using levels the
+// feed handler no longer trusts. What the conflator merged before the reset is dropped.
+fn publish_reset(
+    sink: &mut FanOutSink,
+    book_changes: &mut ShmSlots<BookChange>,
+    analytics: &mut Option<AnalyticsPublisher>,
+    conflator: &mut Conflator,
+    instrument_idx: usize,
+    book: &OrderbookManagerV2,
+    timestamp: u64,
+) {
+    conflator.flush();
+    let due = DuePublish { timestamp, flag: 0b11 };
+    publish_book(sink, book_changes, analytics, instrument_idx, book, book.get_orderbook(), due);
+}
+
+// Writes the merged updates of the conflated books whose interval is over
+fn poll_conflators(
+    conflators: &mut [Conflator],
+    conflated: &[usize],
+    ob_manager: &[Option<OrderbookManagerV2>],
+    sink: &mut FanOutSink,
+    book_changes: &mut ShmSlots<BookChange>,
+    analytics: &mut Option<AnalyticsPublisher>,
+    now: std::time::Instant,
+) {
+    for &instrument_idx in conflated {
+        let Some(book) = ob_manager[instrument_idx].as_ref() else { continue };
+        let Some(due) = conflators[instrument_idx].poll(now) else { continue };
+        // reset since the merged updates, nothing worth writing until the next snapshot
+        if book.is_initialized() {
+            publish_book(sink, book_changes, analytics, instrument_idx, book, book.get_orderbook(), due);
+        }
+    }
+}
+
+// Sends a book to the sinks and writes what readers derive from it
+fn publish_book(
+    sink: &mut FanOutSink,
+    book_changes: &mut ShmSlots<BookChange>,
+    analytics: &mut Option<AnalyticsPublisher>,
+    instrument_idx: usize,
+    book: &OrderbookManagerV2,
+    ob_data: OrderbookData,
+    due: DuePublish,
+) {
+    sink.on_book(instrument_idx, &ob_data, due.timestamp, due.flag);
+    book_changes.write(instrument_idx, &BookChange { timestamp: due.timestamp, change_id: book.last_change_id() });
+    if let Some(publisher) = analytics.as_mut() {
+        publisher.publish(instrument_idx, book, due.timestamp);
+    }
+}
+
+// How often the crossed books and the time bars are checked
+const PERIODIC_CHECK: Duration = Duration::from_millis(100);
+// How often the writer checks in with /health, on the wall clock whatever the writer clock
+const HEARTBEAT: Duration = Duration::from_millis(100);
+
+// Time of the writer for conflation and the crossed book checks. Live it is the wall clock. In a replay it
+// follows the exchange timestamps (ms) of the book updates, so what gets published does not depend on the
+// replay speed or on how the updates were batched in the channels.
+#[derive(Debug, Clone, Copy)]
+pub enum WriterClock {
+    Wall,
+    Recorded {
+        origin: std::time::Instant,
+        first_ms: Option<u64>,
+        elapsed_ms: u64,
+    },
+}
+
+impl WriterClock {
+    pub fn recorded() -> Self {
+        WriterClock::Recorded { origin: std::time::Instant::now(), first_ms: None, elapsed_ms: 0 }
+    }
+
+    #[inline]
+    fn is_recorded(&self) -> bool {
+        matches!(self, WriterClock::Recorded { .. })
+    }
+
+    // The recorded clock never goes back
+    #[inline]
+    fn on_book_update(&mut self, timestamp_ms: u64) {
+        if let WriterClock::Recorded { first_ms, elapsed_ms, .. } = self {
+            let first = *first_ms.get_or_insert(timestamp_ms);
+            *elapsed_ms = (*elapsed_ms).max(timestamp_ms.saturating_sub(first));
+        }
+    }
+
+    #[inline]
+    fn now(&self) -> std::time::Instant {
+        match self {
+            WriterClock::Wall => std::time::Instant::now(),
+            WriterClock::Recorded { origin, elapsed_ms, .. } => *origin + Duration::from_millis(*elapsed_ms),
+        }
+    }
+}
+
+// Work done once per period of the writer clock
+struct Every {
+    period: Duration,
+    last: Option<std::time::Instant>,
+}
+
+impl Every {
+    fn new(period: Duration) -> Self {
+        Self { period, last: None }
+    }
+
+    #[inline]
+    fn is_due(&mut self, now: std::time::Instant) -> bool {
+        match self.last {
+            Some(last) if now.saturating_duration_since(last) < self.period => false,
+            _ => {
+                self.last = Some(now);
+                true
+            }
+        }
+    }
+}
+
+enum Input {
+    Trade(TradeEvent),
+    Book(OrderbookResult),
+}
+
+// What is already in the channels, taking them in turn so a burst on one does not hold the other back
+#[inline]
+fn try_next_input(
+    fast_trade_rx: &mut mpsc::Receiver<TradeEvent>,
+    fast_orderbook_rx: &mut mpsc::Receiver<OrderbookResult>,
+    trades_first: &mut bool,
+) -> Option<Input> {
+    *trades_first = !*trades_first;
+    if *trades_first {
+        fast_trade_rx.try_recv().map(Input::Trade).or_else(|_| fast_orderbook_rx.try_recv().map(Input::Book)).ok()
+    } else {
+        fast_orderbook_rx.try_recv().map(Input::Book).or_else(|_| fast_trade_rx.try_recv().map(Input::Trade)).ok()
+    }
+}
+
+// Where the writer publishes and how it keeps the books, the optional publishers are None when not configured
+pub struct WriterSetup {
+    pub sink: FanOutSink,
+    pub analytics: Option<AnalyticsPublisher>,
+    pub order_flow: Option<OrderFlowPublisher>,
+    pub book_changes: ShmSlots<BookChange>,
+    pub trade_checks: Option<TradeChecker>,
+    pub bars: Option<BarPublisher>,
+    pub book_settings: Vec<BookSettings>,
+    pub tick_sizes: Vec<Option<TickSize>>,
+    pub max_crossed: Duration,
+    pub clock: WriterClock,
+}
 
 pub async fn shm_writer_task(
     mut fast_trade_rx: mpsc::Receiver<TradeEvent>,
     mut fast_orderbook_rx: mpsc::Receiver<OrderbookResult>,
     mut shutdown_rx: broadcast::Receiver<()>,
-    mut shm_writer: SHMAccessor,
-    mut trade_buffer: TradeRingBuffer,
-    nb_instrument: usize,
+    mut command_rx: mpsc::Receiver<WriterCommand>,
+    event_tx: mpsc::Sender<WriterEvent>,
+    setup: WriterSetup,
 ) -> Result<(), DeribitError> {
+    let WriterSetup {
+        mut sink,
+        mut analytics,
+        mut order_flow,
+        mut book_changes,
+        mut trade_checks,
+        mut bars,
+        book_settings,
+        tick_sizes,
+        max_crossed,
+        mut clock,
+    } = setup;
 
     let mut latency_tracker = LatencyTracker::new(1000);
     let mut ob_manager = Vec::new();
 
-    for _ in 0..nb_instrument {
-        ob_manager.push(OrderbookManagerV2::new(10));
+    // no book for instruments the exchange did not list, main refuses to subscribe to them
+    for (settings, tick_size) in book_settings.iter().zip(tick_sizes) {
+        ob_manager.push(tick_size.map(|tick_size| OrderbookManagerV2::from_settings(settings, tick_size)));
     }
+    // exchange timestamp of the last update applied to each book
+    let mut last_updates = vec![0u64; ob_manager.len()];
+    let mut conflators: Vec<Conflator> = book_settings.iter().map(|settings| Conflator::new(settings.conflation)).collect();
+    let conflated: Vec<usize> = (0..conflators.len()).filter(|&idx| conflators[idx].is_enabled()).collect();
+    // the timer flushes what was merged when no update follows, it cannot tick faster than 1ms
+    let conflation_tick = book_settings
+        .iter()
+        .map(|settings| settings.conflation)
+        .filter(|conflation| !conflation.is_zero())
+        .min()
+        .map_or(Duration::from_secs(1), |conflation| conflation.max(Duration::from_millis(1)));
     let mut stats_timer = tokio::time::interval(Duration::from_secs(10));
+    // live the timers only wake the select up, the periodic work is checked on the writer clock after every
+    // message as a busy feed keeps the loop away from the select. A replay has nothing to wait for, but for the
+    // heartbeat which is on the wall clock.
+    let mut crossed_timer = tokio::time::interval(PERIODIC_CHECK);
+    let mut conflation_timer = tokio::time::interval(conflation_tick);
+    conflation_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut conflation_check = Every::new(conflation_tick);
+    let mut periodic_check = Every::new(PERIODIC_CHECK);
+    let mut heartbeat_timer = tokio::time::interval(HEARTBEAT);
+    heartbeat_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut heartbeat = Every::new(HEARTBEAT);
+    let mut trades_first = false;
 
     loop {
         // taskset was failing as the try_recv() wasn't yielding properly to the tokio scheduler so nothing happened (no writing)
         // this is not clean at all
-        // we drain the available trades/ob data and then we check for new ones, if we had to drain some channels then we retry immediately
-        let mut processed_any = false;
-
-        while let Ok(trade) = fast_trade_rx.try_recv() {
-            processed_any = true;
-            match trade_buffer.push_trade(trade) {
-                Err(e) => error!("Error pushing trade: {:?}", e),
-                _ => (),
-            }
-        }
+        // we take what is available in the trades/ob channels and only wait on them once they are empty,
+        // one message per pass so the periodic work below runs between any two of them
+        let input = match try_next_input(&mut fast_trade_rx, &mut fast_orderbook_rx, &mut trades_first) {
+            Some(input) => Some(input),
+            None => {
+                // every sender is gone: the end of a replay, or the connection tasks stopped
+                if fast_trade_rx.is_closed() && fast_orderbook_rx.is_closed() && fast_trade_rx.is_empty() && fast_orderbook_rx.is_empty() {
+                    for &instrument_idx in &conflated {
+                        let Some(book) = ob_manager[instrument_idx].as_ref() else { continue };
+                        let Some(due) = conflators[instrument_idx].flush() else { continue };
+                        if book.is_initialized() {
+                            publish_book(&mut sink, &mut book_changes, &mut analytics, instrument_idx, book, book.get_orderbook(), due);
+                        }
+                    }
+                    info!("shm_writer_task: input closed, stopping");
+                    sink.on_status(SinkStatus::Shutdown);
+                    return Ok(());
+                }
 
-        while let Ok(orderbook_update) = fast_orderbook_rx.try_recv() {
-            processed_any = true;
-            let start = Instant::now();
-            let flag = orderbook_update.update_data.flag;
-            let ob_data = ob_manager[orderbook_update.instrument_idx]
-                .apply_update(orderbook_update.update_data, orderbook_update.change_id)
-                .unwrap();
-
-            shm_writer
-                .write_orderbook_update_consistency_from_idx(
-                    orderbook_update.instrument_idx,
-                    ob_data,
-                    orderbook_update.timestamp,
-                    flag,
-                )
-                .expect("failed to write to SHM");
-
-            latency_tracker.record(start.elapsed());
-        }
+                tokio::select! {
 
-        if processed_any {
-            continue;
-        }
+                    Some(trade) = fast_trade_rx.recv() => Some(Input::Trade(trade)),
+
+                    Some(orderbook_update) = fast_orderbook_rx.recv() => Some(Input::Book(orderbook_update)),
 
-        tokio::select! {
+                    Some(command) = command_rx.recv() => {
+                        match command {
+                            WriterCommand::ResetBook(instrument_idx) => {
+                                info!("shm_writer_task: reset book {}", instrument_idx);
+                                if let Some(checker) = trade_checks.as_mut() {
+                                    checker.reset(instrument_idx);
+                                }
+                                sink.on_status(SinkStatus::BookReset(instrument_idx));
+                                if let Some(book) = ob_manager[instrument_idx].as_mut() {
+                                    book.reset();
+                                    publish_reset(&mut sink, &mut book_changes, &mut analytics, &mut conflators[instrument_idx], instrument_idx, book, last_updates[instrument_idx]);
+                                }
+                            }
+                            WriterCommand::DumpBooks(reply_tx) => {
+                                let dumps = ob_manager
+                                    .iter()
+                                    .enumerate()
+                                    .filter_map(|(idx, book)| book.as_ref().map(|book| BookDump::from_book(idx, book, last_updates[idx])))
+                                    .collect();
+                                let _ = reply_tx.send(dumps);
+                            }
+                            WriterCommand::SetTickSize(instrument_idx, tick_size) => {
+                                info!("shm_writer_task: tick size of book {} is now {}", instrument_idx, tick_size.value());
+                                let was_initialized = ob_manager[instrument_idx].as_ref().is_some_and(|book| book.is_initialized());
+                                ob_manager[instrument_idx] = Some(OrderbookManagerV2::from_settings(&book_settings[instrument_idx], tick_size));
+                                if let Some(checker) = trade_checks.as_mut() {
+                                    checker.reset(instrument_idx);
+                                }
+                                if was_initialized {
+                                    sink.on_status(SinkStatus::BookReset(instrument_idx));
+                                    METRICS.on_send(Queue::WriterEvents, event_tx.try_send(WriterEvent::ResyncBook(instrument_idx)));
+                                }
+                            }
+                        }
+                        None
+                    }
 
-            Some(trade) = fast_trade_rx.recv() => {
-                match trade_buffer.push_trade(trade) {
-                    Err(e) => error!("Error pushing trade: {:?}", e),
-                    _ => (),
+                    _ = conflation_timer.tick(), if !clock.is_recorded() => None,
+
+                    _ = crossed_timer.tick(), if !clock.is_recorded() => None,
+
+                    _ = heartbeat_timer.tick() => None,
+
+                    _ = stats_timer.tick() => {
+                        latency_tracker.print_stats("SHM WRITING");
+                        for (instrument_idx, book) in ob_manager.iter().enumerate() {
+                            let Some(book) = book.as_ref() else { continue };
+                            let violations = book.violations();
+                            if violations.total() > 0 {
+                                warn!("shm_writer_task: book {} invariant violations {:?}", instrument_idx, violations);
+                            }
+                        }
+                        if let Some(checker) = trade_checks.as_mut() {
+                            for instrument_idx in 0..ob_manager.len() {
+                                let counts = checker.counts(instrument_idx);
+                                if counts.flagged() > 0 {
+                                    warn!("shm_writer_task: book {} trade/book inconsistencies {:?}", instrument_idx, counts);
+                                }
+                            }
+                            // in exchange time, like the rest of the checks
+                            checker.prune(last_updates.iter().copied().max().unwrap_or(0));
+                        }
+                        None
+                    }
+
+                    _ = shutdown_rx.recv() => {
+                        sink.on_status(SinkStatus::Shutdown);
+                        return Ok(());
+                    }
                 }
             }
+        };
 
-            Some(orderbook_update) = fast_orderbook_rx.recv() => {
+        match input {
+            Some(Input::Trade(trade)) => handle_trade(trade, &mut sink, &ob_manager, &mut trade_checks, &mut bars),
+            Some(Input::Book(orderbook_update)) => {
                 let start = Instant::now();
+                clock.on_book_update(orderbook_update.timestamp);
+                let now = clock.now();
                 let flag = orderbook_update.update_data.flag;
-                let ob_data = ob_manager[orderbook_update.instrument_idx].apply_update(orderbook_update.update_data, orderbook_update.change_id).unwrap();
-                shm_writer.write_orderbook_update_consistency_from_idx(
-                    orderbook_update.instrument_idx,
-                    ob_data,
-                    orderbook_update.timestamp,
-                    flag,
-                ).expect("failed to write to SHM");
-                latency_tracker.record(start.elapsed());
+                let instrument_idx = orderbook_update.instrument_idx;
+                if let Some((ob_data, timestamp)) = apply_to_book(&mut ob_manager, orderbook_update, &mut order_flow, &mut trade_checks, &mut sink, &event_tx, now) {
+                    last_updates[instrument_idx] = timestamp;
+                    METRICS.on_book_applied(instrument_idx);
+                    if let (Some(due), Some(book)) = (
+                        conflators[instrument_idx].on_update(now, timestamp, flag),
+                        ob_manager[instrument_idx].as_ref(),
+                    ) {
+                        publish_book(&mut sink, &mut book_changes, &mut analytics, instrument_idx, book, ob_data, due);
+                    }
+                    latency_tracker.record(start.elapsed());
+                    METRICS.write_latency.observe(start.elapsed());
+                }
             }
+            None => {}
+        }
 
-            _ = stats_timer.tick() => {
-                latency_tracker.print_stats("SHM WRITING");
+        if heartbeat.is_due(std::time::Instant::now()) {
+            HEALTH.writer_heartbeat();
+        }
+        let now = clock.now();
+        if conflation_check.is_due(now) {
+            poll_conflators(&mut conflators, &conflated, &ob_manager, &mut sink, &mut book_changes, &mut analytics, now);
+        }
+        if periodic_check.is_due(now) {
+            // time bars of instruments without trades close on the exchange time of their book
+            if let Some(bars) = bars.as_mut() {
+                bars.on_clock(&last_updates);
             }
-
-            _ = shutdown_rx.recv() => {
-                return Ok(());
+            for (instrument_idx, book) in ob_manager.iter_mut().enumerate() {
+                let Some(book) = book.as_mut() else { continue };
+                if book.needs_resync(now, max_crossed) {
+                    warn!("shm_writer_task: book {} crossed for more than {:?}, resnapshotting", instrument_idx, max_crossed);
+                    book.reset();
+                    if let Some(checker) = trade_checks.as_mut() {
+                        checker.reset(instrument_idx);
+                    }
+                    sink.on_status(SinkStatus::BookReset(instrument_idx));
+                    publish_reset(&mut sink, &mut book_changes, &mut analytics, &mut conflators[instrument_idx], instrument_idx, book, last_updates[instrument_idx]);
+                    METRICS.on_send(Queue::WriterEvents, event_tx.try_send(WriterEvent::ResyncBook(instrument_idx)));
+                }
             }
         }
     }
@@ -0,0 +1,232 @@
+pub mod sink_export;
+pub mod sink_multicast;
+pub mod sink_server;
+pub mod sink_shm;
+pub mod sink_websocket;
+
+use std::fmt;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::error;
+use crate::metrics::METRICS;
+use haiku_common::metadata::ShmMetadata;
+use haiku_common::shm_accessor::market_data_type::{OrderbookData, TradeEvent};
+use crate::sinks::sink_export::{ExportSink, ExportSinkConfig};
+use crate::sinks::sink_multicast::{MulticastSink, MulticastSinkConfig};
+use crate::sinks::sink_server::{ServerSink, ServerSinkConfig};
+use crate::sinks::sink_shm::{ShmSink, ShmSinkConfig};
+use crate::sinks::sink_websocket::{WebsocketSink, WebsocketSinkConfig};
+
+#[derive(Debug, Error)]
+pub enum SinkError {
+    #[error("shared memory: {0}")]
+    Shm(String),
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("parquet: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+    #[error("{0}")]
+    Config(String),
+    #[error("{0}: its thread stopped")]
+    Stopped(String),
+}
+
+// Changes of the feed that are not a book or a trade
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkStatus {
+    // the book was dropped and waits for a snapshot, its last published state is stale
+    BookReset(usize),
+    // the next book of this instrument is a snapshot, not the continuation of the previous one
+    BookSnapshot(usize),
+    Shutdown,
+}
+
+// Where the writer sends what it publishes. Called from the writer task, a sink must not block:
+// anything slow (network, disk) belongs to a task of its own behind a channel.
+pub trait MarketDataSink: Send {
+    fn name(&self) -> &str;
+
+    // `timestamp` is the exchange one, `flag` the sides touched since the last call (bit 0 bids, bit 1 asks)
+    fn on_book(&mut self, instrument_idx: usize, book: &OrderbookData, timestamp: u64, flag: u8) -> Result<(), SinkError>;
+
+    fn on_trade(&mut self, trade: &TradeEvent) -> Result<(), SinkError>;
+
+    fn on_status(&mut self, _status: SinkStatus) -> Result<(), SinkError> {
+        Ok(())
+    }
+}
+
+// Output of the feed handler, in the config:
+//   "sinks": [{ "type": "shm", "orderbook_shm": "rust_integration", ... }, { "type": "export", "dir": "/data/export" }]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkConfig {
+    Shm(ShmSinkConfig),
+    Export(ExportSinkConfig),
+    Multicast(MulticastSinkConfig),
+    Server(ServerSinkConfig),
+    Websocket(WebsocketSinkConfig),
+}
+
+pub fn default_sinks() -> Vec<SinkConfig> {
+    vec![SinkConfig::Shm(ShmSinkConfig::default())]
+}
+
+// a sink failing on every message logs once per interval
+const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+// Errors of one sink, logged at most once per ERROR_LOG_INTERVAL with the count of those that were not
+#[derive(Debug, Default)]
+struct SinkErrors {
+    last_logged: Option<Instant>,
+    suppressed: u64,
+}
+
+impl SinkErrors {
+    // Some(errors not logged since the previous log) when this one is logged
+    fn should_log(&mut self, now: Instant) -> Option<u64> {
+        METRICS.sink_errors.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        match self.last_logged {
+            Some(last) if now.duration_since(last) < ERROR_LOG_INTERVAL => {
+                self.suppressed += 1;
+                None
+            }
+            _ => {
+                self.last_logged = Some(now);
+                Some(std::mem::take(&mut self.suppressed))
+            }
+        }
+    }
+
+    fn report(&mut self, name: &str, what: fmt::Arguments, e: &SinkError) {
+        match self.should_log(Instant::now()) {
+            Some(0) => error!("sink {}: {}: {}", name, what, e),
+            Some(suppressed) => error!("sink {}: {}: {} ({} more errors not logged)", name, what, e, suppressed),
+            None => {}
+        }
+    }
+}
+
+// Sends everything to each sink in turn, an error in one sink is logged and does not stop the others
+#[derive(Default)]
+pub struct FanOutSink {
+    sinks: Vec<Box<dyn MarketDataSink>>,
+    // by sink
+    errors: Vec<SinkErrors>,
+}
+
+impl FanOutSink {
+    pub fn new(sinks: Vec<Box<dyn MarketDataSink>>) -> Self {
+        let errors = sinks.iter().map(|_| SinkErrors::default()).collect();
+        Self { sinks, errors }
+    }
+
+    pub fn from_config(configs: &[SinkConfig], metadata: &ShmMetadata) -> Result<Self, SinkError> {
+        let mut sinks: Vec<Box<dyn MarketDataSink>> = Vec::with_capacity(configs.len());
+        for config in configs {
+            match config {
+                SinkConfig::Shm(shm) => sinks.push(Box::new(ShmSink::new(shm, metadata)?)),
+                SinkConfig::Export(export) => sinks.push(Box::new(ExportSink::new(export, &metadata.clone_instrument_index())?)),
+                SinkConfig::Multicast(multicast) => sinks.push(Box::new(MulticastSink::new(multicast)?)),
+                SinkConfig::Server(server) => sinks.push(Box::new(ServerSink::new(server, &metadata.clone_instrument_index())?)),
+                SinkConfig::Websocket(websocket) => sinks.push(Box::new(WebsocketSink::new(websocket, &metadata.clone_instrument_index())?)),
+            }
+        }
+        Ok(Self::new(sinks))
+    }
+
+    pub fn push(&mut self, sink: Box<dyn MarketDataSink>) {
+        self.sinks.push(sink);
+        self.errors.push(SinkErrors::default());
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.sinks.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    #[inline]
+    pub fn on_book(&mut self, instrument_idx: usize, book: &OrderbookData, timestamp: u64, flag: u8) {
+        for (sink, errors) in self.sinks.iter_mut().zip(&mut self.errors) {
+            if let Err(e) = sink.on_book(instrument_idx, book, timestamp, flag) {
+                errors.report(sink.name(), format_args!("book {} not written", instrument_idx), &e);
+            }
+        }
+    }
+
+    #[inline]
+    pub fn on_trade(&mut self, trade: &TradeEvent) {
+        for (sink, errors) in self.sinks.iter_mut().zip(&mut self.errors) {
+            if let Err(e) = sink.on_trade(trade) {
+                errors.report(sink.name(), format_args!("trade not written"), &e);
+            }
+        }
+    }
+
+    pub fn on_status(&mut self, status: SinkStatus) {
+        for (sink, errors) in self.sinks.iter_mut().zip(&mut self.errors) {
+            if let Err(e) = sink.on_status(status) {
+                errors.report(sink.name(), format_args!("status {:?} not written", status), &e);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keeps what it receives, fails the trades
+    struct RecordingSink {
+        books: std::sync::Arc<std::sync::Mutex<Vec<(usize, u64)>>>,
+    }
+
+    impl MarketDataSink for RecordingSink {
+        fn name(&self) -> &str {
+            "recording"
+        }
+
+        fn on_book(&mut self, instrument_idx: usize, _book: &OrderbookData, timestamp: u64, _flag: u8) -> Result<(), SinkError> {
+            self.books.lock().unwrap().push((instrument_idx, timestamp));
+            Ok(())
+        }
+
+        fn on_trade(&mut self, _trade: &TradeEvent) -> Result<(), SinkError> {
+            Err(SinkError::Shm("full".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_fan_out() {
+        let books = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut fan_out = FanOutSink::default();
+        fan_out.push(Box::new(RecordingSink { books: books.clone() }));
+        fan_out.push(Box::new(RecordingSink { books: books.clone() }));
+
+        let book = OrderbookData { bid_prices: [0.0; 10], ask_prices: [0.0; 10], bid_sizes: [0.0; 10], ask_sizes: [0.0; 10] };
+        let trade = TradeEvent { instrument_idx: 0, price: 1.0, size: 1.0, side: 1, timestamp_ns: 0, trade_id: 0, padding: [0; 6] };
+        fan_out.on_trade(&trade);
+        fan_out.on_book(2, &book, 42, 0b11);
+        assert_eq!(*books.lock().unwrap(), vec![(2, 42), (2, 42)]);
+
+        let configs: Vec<SinkConfig> = serde_json::from_str(r#"[{"type": "shm", "orderbook_shm": "fh_test"}]"#).unwrap();
+        assert!(matches!(&configs[0], SinkConfig::Shm(shm) if shm.orderbook_shm == "fh_test" && shm.trades_shm == "rust_integration_trades_buffer"));
+    }
+
+    #[test]
+    fn test_error_log_throttling() {
+        let start = Instant::now();
+        let mut errors = SinkErrors::default();
+        assert_eq!(errors.should_log(start), Some(0));
+        assert_eq!(errors.should_log(start + Duration::from_millis(10)), None);
+        assert_eq!(errors.should_log(start + Duration::from_millis(20)), None);
+        assert_eq!(errors.should_log(start + ERROR_LOG_INTERVAL), Some(2));
+    }
+}
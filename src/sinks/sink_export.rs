@@ -0,0 +1,585 @@
+use parquet::basic::Compression;
+use parquet::data_type::{DoubleType, FloatType, Int32Type, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+use std::thread::JoinHandle;
+use tracing::{error, info, warn};
+use haiku_common::shm_accessor::market_data_type::{OrderbookData, TradeEvent};
+use crate::orderbook_management::SHM_BOOK_LEVELS;
+use crate::sinks::{MarketDataSink, SinkError, SinkStatus};
+
+// Normalized tables for research, per instrument in <dir>/<instrument>/:
+//
+// trades.csv / trades.parquet, one row per trade
+//   ts        INT64   exchange time, ms since the epoch (TradeEvent.timestamp_ns holds ms despite its name)
+//   seq       INT64   publication order within the instrument, shared with the book table
+//   price     DOUBLE  the f32 price of the feed handler widened from its shortest decimal form
+//   size      FLOAT
+//   side      INT32  taker side, 1 buy, 0 sell
+//   trade_id  INT64
+//
+// book.csv / book.parquet, one row per book written by the feed handler
+//   ts, seq   as above
+//   bid_price_<n>, bid_size_<n>, ask_price_<n>, ask_size_<n>   DOUBLE prices and FLOAT sizes, for n in 1..=levels
+//                                                             from the touch, 0 when the book has fewer levels
+//
+// Every column is required. CSV files start with a header line of the column names.
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    Csv,
+    Parquet,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "parquet" => Ok(ExportFormat::Parquet),
+            other => Err(format!("unknown export format {}, expected csv or parquet", other)),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ExportSinkConfig {
+    pub dir: String,
+    #[serde(default = "default_formats")]
+    pub formats: Vec<ExportFormat>,
+    // book levels per side, at most the 10 of the SHM layout
+    #[serde(default = "default_levels")]
+    pub levels: usize,
+    // rows buffered per Parquet row group
+    #[serde(default = "default_row_group_rows")]
+    pub row_group_rows: usize,
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+    // a full queue makes the writer wait for the disk instead of dropping rows, for batch exports
+    #[serde(default)]
+    pub wait_when_full: bool,
+}
+
+fn default_formats() -> Vec<ExportFormat> {
+    vec![ExportFormat::Csv, ExportFormat::Parquet]
+}
+
+fn default_levels() -> usize {
+    SHM_BOOK_LEVELS
+}
+
+fn default_row_group_rows() -> usize {
+    100_000
+}
+
+fn default_queue_size() -> usize {
+    65536
+}
+
+impl ExportSinkConfig {
+    pub fn new(dir: String) -> Self {
+        Self {
+            dir,
+            formats: default_formats(),
+            levels: default_levels(),
+            row_group_rows: default_row_group_rows(),
+            queue_size: default_queue_size(),
+            wait_when_full: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Table {
+    Trades,
+    Book,
+}
+
+impl Table {
+    fn name(&self) -> &'static str {
+        match self {
+            Table::Trades => "trades",
+            Table::Book => "book",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColumnKind {
+    Int64,
+    Int32,
+    Float,
+    Double,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value {
+    Int64(i64),
+    Int32(i32),
+    Float(f32),
+    Double(f64),
+}
+
+// A plain cast would export 100.1 as 100.09999847..., going through the decimal form keeps the price as printed
+fn price_value(price: f32) -> Value {
+    Value::Double(price.to_string().parse().unwrap_or(price as f64))
+}
+
+fn columns(table: Table, levels: usize) -> Vec<(String, ColumnKind)> {
+    let mut columns = vec![("ts".to_string(), ColumnKind::Int64), ("seq".to_string(), ColumnKind::Int64)];
+    match table {
+        Table::Trades => {
+            columns.push(("price".to_string(), ColumnKind::Double));
+            columns.push(("size".to_string(), ColumnKind::Float));
+            columns.push(("side".to_string(), ColumnKind::Int32));
+            columns.push(("trade_id".to_string(), ColumnKind::Int64));
+        }
+        Table::Book => {
+            for n in 1..=levels {
+                columns.push((format!("bid_price_{}", n), ColumnKind::Double));
+                columns.push((format!("bid_size_{}", n), ColumnKind::Float));
+                columns.push((format!("ask_price_{}", n), ColumnKind::Double));
+                columns.push((format!("ask_size_{}", n), ColumnKind::Float));
+            }
+        }
+    }
+    columns
+}
+
+fn trade_row(seq: u64, trade: &TradeEvent) -> Vec<Value> {
+    vec![
+        // exchange ms, the field of the SHM layout is misnamed
+        Value::Int64(trade.timestamp_ns as i64),
+        Value::Int64(seq as i64),
+        price_value(trade.price),
+        Value::Float(trade.size),
+        Value::Int32(trade.side as i32),
+        Value::Int64(trade.trade_id as i64),
+    ]
+}
+
+fn book_row(seq: u64, timestamp: u64, book: &OrderbookData, levels: usize) -> Vec<Value> {
+    let mut row = Vec::with_capacity(2 + 4 * levels);
+    row.push(Value::Int64(timestamp as i64));
+    row.push(Value::Int64(seq as i64));
+    for n in 0..levels {
+        row.push(price_value(book.bid_prices[n]));
+        row.push(Value::Float(book.bid_sizes[n]));
+        row.push(price_value(book.ask_prices[n]));
+        row.push(Value::Float(book.ask_sizes[n]));
+    }
+    row
+}
+
+fn parquet_schema(table: Table, columns: &[(String, ColumnKind)]) -> String {
+    let fields: String = columns
+        .iter()
+        .map(|(name, kind)| {
+            let kind = match kind {
+                ColumnKind::Int64 => "INT64",
+                ColumnKind::Int32 => "INT32",
+                ColumnKind::Float => "FLOAT",
+                ColumnKind::Double => "DOUBLE",
+            };
+            format!("  REQUIRED {} {};\n", kind, name)
+        })
+        .collect();
+    format!("message {} {{\n{}}}", table.name(), fields)
+}
+
+enum ColumnBuffer {
+    Int64(Vec<i64>),
+    Int32(Vec<i32>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+}
+
+// Rows are kept by column until a row group is full
+struct ParquetTable {
+    writer: SerializedFileWriter<File>,
+    buffers: Vec<ColumnBuffer>,
+    rows: usize,
+    row_group_rows: usize,
+}
+
+impl ParquetTable {
+    fn create(path: &Path, table: Table, columns: &[(String, ColumnKind)], row_group_rows: usize) -> Result<Self, SinkError> {
+        let schema = Arc::new(parse_message_type(&parquet_schema(table, columns))?);
+        let properties = Arc::new(WriterProperties::builder().set_compression(Compression::SNAPPY).build());
+        let writer = SerializedFileWriter::new(File::create(path)?, schema, properties)?;
+        let buffers = columns
+            .iter()
+            .map(|(_, kind)| match kind {
+                ColumnKind::Int64 => ColumnBuffer::Int64(Vec::new()),
+                ColumnKind::Int32 => ColumnBuffer::Int32(Vec::new()),
+                ColumnKind::Float => ColumnBuffer::Float(Vec::new()),
+                ColumnKind::Double => ColumnBuffer::Double(Vec::new()),
+            })
+            .collect();
+        Ok(Self { writer, buffers, rows: 0, row_group_rows: row_group_rows.max(1) })
+    }
+
+    fn push(&mut self, row: &[Value]) -> Result<(), SinkError> {
+        for (buffer, value) in self.buffers.iter_mut().zip(row) {
+            match (buffer, *value) {
+                (ColumnBuffer::Int64(values), Value::Int64(value)) => values.push(value),
+                (ColumnBuffer::Int32(values), Value::Int32(value)) => values.push(value),
+                (ColumnBuffer::Float(values), Value::Float(value)) => values.push(value),
+                (ColumnBuffer::Double(values), Value::Double(value)) => values.push(value),
+                _ => unreachable!("rows are built from the columns of their table"),
+            }
+        }
+        self.rows += 1;
+        if self.rows >= self.row_group_rows {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+        let mut row_group = self.writer.next_row_group()?;
+        for buffer in &mut self.buffers {
+            let Some(mut column) = row_group.next_column()? else { break };
+            match buffer {
+                ColumnBuffer::Int64(values) => {
+                    column.typed::<Int64Type>().write_batch(values, None, None)?;
+                    values.clear();
+                }
+                ColumnBuffer::Int32(values) => {
+                    column.typed::<Int32Type>().write_batch(values, None, None)?;
+                    values.clear();
+                }
+                ColumnBuffer::Float(values) => {
+                    column.typed::<FloatType>().write_batch(values, None, None)?;
+                    values.clear();
+                }
+                ColumnBuffer::Double(values) => {
+                    column.typed::<DoubleType>().write_batch(values, None, None)?;
+                    values.clear();
+                }
+            }
+            column.close()?;
+        }
+        row_group.close()?;
+        self.rows = 0;
+        Ok(())
+    }
+
+    // The footer makes the file readable, a table that is not closed is lost
+    fn close(mut self) -> Result<(), SinkError> {
+        self.flush()?;
+        self.writer.close()?;
+        Ok(())
+    }
+}
+
+struct CsvTable {
+    writer: BufWriter<File>,
+}
+
+impl CsvTable {
+    fn create(path: &Path, columns: &[(String, ColumnKind)]) -> Result<Self, SinkError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let header: Vec<&str> = columns.iter().map(|(name, _)| name.as_str()).collect();
+        writeln!(writer, "{}", header.join(","))?;
+        Ok(Self { writer })
+    }
+
+    fn push(&mut self, row: &[Value]) -> Result<(), SinkError> {
+        for (i, value) in row.iter().enumerate() {
+            if i > 0 {
+                self.writer.write_all(b",")?;
+            }
+            match value {
+                Value::Int64(value) => write!(self.writer, "{}", value)?,
+                Value::Int32(value) => write!(self.writer, "{}", value)?,
+                Value::Float(value) => write!(self.writer, "{}", value)?,
+                Value::Double(value) => write!(self.writer, "{}", value)?,
+            }
+        }
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn close(mut self) -> Result<(), SinkError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+enum ExportRow {
+    Trade { seq: u64, trade: TradeEvent },
+    Book { instrument_idx: usize, seq: u64, timestamp: u64, book: OrderbookData },
+}
+
+// Owned by the export thread, tables are created with the first row of their instrument
+struct ExportTables {
+    dir: PathBuf,
+    instruments: Vec<String>,
+    formats: Vec<ExportFormat>,
+    levels: usize,
+    row_group_rows: usize,
+    csv: HashMap<(usize, Table), CsvTable>,
+    parquet: HashMap<(usize, Table), ParquetTable>,
+}
+
+impl ExportTables {
+    fn write(&mut self, row: ExportRow) -> Result<(), SinkError> {
+        let (instrument_idx, table, values) = match row {
+            ExportRow::Trade { seq, trade } => (trade.instrument_idx as usize, Table::Trades, trade_row(seq, &trade)),
+            ExportRow::Book { instrument_idx, seq, timestamp, book } => {
+                (instrument_idx, Table::Book, book_row(seq, timestamp, &book, self.levels))
+            }
+        };
+        let key = (instrument_idx, table);
+        let dir = self.dir.join(self.instruments.get(instrument_idx).map_or_else(|| instrument_idx.to_string(), |name| name.clone()));
+        let columns = columns(table, self.levels);
+        for format in &self.formats {
+            match format {
+                ExportFormat::Csv => {
+                    let csv = match self.csv.entry(key) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => {
+                            std::fs::create_dir_all(&dir)?;
+                            entry.insert(CsvTable::create(&dir.join(format!("{}.csv", table.name())), &columns)?)
+                        }
+                    };
+                    csv.push(&values)?;
+                }
+                ExportFormat::Parquet => {
+                    let parquet = match self.parquet.entry(key) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => {
+                            std::fs::create_dir_all(&dir)?;
+                            let path = dir.join(format!("{}.parquet", table.name()));
+                            entry.insert(ParquetTable::create(&path, table, &columns, self.row_group_rows)?)
+                        }
+                    };
+                    parquet.push(&values)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn close(self) {
+        for ((instrument_idx, table), csv) in self.csv {
+            if let Err(e) = csv.close() {
+                error!("export: {} {} csv not closed: {}", instrument_idx, table.name(), e);
+            }
+        }
+        for ((instrument_idx, table), parquet) in self.parquet {
+            if let Err(e) = parquet.close() {
+                error!("export: {} {} parquet not closed: {}", instrument_idx, table.name(), e);
+            }
+        }
+        info!("export: tables written to {}", self.dir.display());
+    }
+}
+
+fn export_thread(rx: Receiver<ExportRow>, mut tables: ExportTables) {
+    while let Ok(row) = rx.recv() {
+        if let Err(e) = tables.write(row) {
+            error!("export: row lost: {}", e);
+        }
+    }
+    tables.close();
+}
+
+// Hands the books and trades to a thread writing the tables, rows are numbered here so the
+// order of publication is kept across the two tables of an instrument
+pub struct ExportSink {
+    name: String,
+    tx: Option<SyncSender<ExportRow>>,
+    thread: Option<JoinHandle<()>>,
+    seqs: Vec<u64>,
+    wait_when_full: bool,
+    dropped: u64,
+}
+
+impl ExportSink {
+    pub fn new(config: &ExportSinkConfig, instrument_index: &HashMap<String, usize>) -> Result<Self, SinkError> {
+        if config.levels == 0 || config.levels > SHM_BOOK_LEVELS {
+            return Err(SinkError::Config(format!("export levels must be between 1 and {}, got {}", SHM_BOOK_LEVELS, config.levels)));
+        }
+        if config.formats.is_empty() {
+            return Err(SinkError::Config("export needs at least one format".to_string()));
+        }
+        std::fs::create_dir_all(&config.dir)?;
+        let nb_instruments = instrument_index.values().max().map_or(0, |idx| idx + 1);
+        let mut instruments: Vec<String> = (0..nb_instruments).map(|idx| idx.to_string()).collect();
+        for (instrument, &idx) in instrument_index {
+            instruments[idx] = instrument.clone();
+        }
+        let tables = ExportTables {
+            dir: PathBuf::from(&config.dir),
+            instruments,
+            formats: config.formats.clone(),
+            levels: config.levels,
+            row_group_rows: config.row_group_rows,
+            csv: HashMap::new(),
+            parquet: HashMap::new(),
+        };
+        let (tx, rx) = sync_channel(config.queue_size.max(1));
+        let thread = std::thread::Builder::new().name("fh-export".to_string()).spawn(move || export_thread(rx, tables))?;
+        Ok(Self {
+            name: format!("export {}", config.dir),
+            tx: Some(tx),
+            thread: Some(thread),
+            seqs: vec![0; nb_instruments],
+            wait_when_full: config.wait_when_full,
+            dropped: 0,
+        })
+    }
+
+    fn next_seq(&mut self, instrument_idx: usize) -> u64 {
+        if instrument_idx >= self.seqs.len() {
+            self.seqs.resize(instrument_idx + 1, 0);
+        }
+        self.seqs[instrument_idx] += 1;
+        self.seqs[instrument_idx]
+    }
+
+    fn send(&mut self, row: ExportRow) -> Result<(), SinkError> {
+        let Some(tx) = self.tx.as_ref() else {
+            return Ok(());
+        };
+        if self.wait_when_full {
+            return tx.send(row).map_err(|_| SinkError::Stopped(self.name.clone()));
+        }
+        match tx.try_send(row) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                if self.dropped == 0 {
+                    warn!("export: queue full, rows are dropped");
+                }
+                self.dropped += 1;
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(_)) => Err(SinkError::Stopped(self.name.clone())),
+        }
+    }
+
+    // Waits for the tables to be closed, the only place the sink blocks
+    fn finish(&mut self) {
+        self.tx = None;
+        if let Some(Err(_)) = self.thread.take().map(|thread| thread.join()) {
+            error!("export: thread panicked, tables may be incomplete");
+        }
+        if self.dropped > 0 {
+            warn!("export: {} rows dropped, the queue was full", self.dropped);
+            self.dropped = 0;
+        }
+    }
+}
+
+impl MarketDataSink for ExportSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_book(&mut self, instrument_idx: usize, book: &OrderbookData, timestamp: u64, _flag: u8) -> Result<(), SinkError> {
+        let seq = self.next_seq(instrument_idx);
+        self.send(ExportRow::Book { instrument_idx, seq, timestamp, book: *book })
+    }
+
+    fn on_trade(&mut self, trade: &TradeEvent) -> Result<(), SinkError> {
+        let seq = self.next_seq(trade.instrument_idx as usize);
+        self.send(ExportRow::Trade { seq, trade: *trade })
+    }
+
+    fn on_status(&mut self, status: SinkStatus) -> Result<(), SinkError> {
+        if status == SinkStatus::Shutdown {
+            self.finish();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ExportSink {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::basic::Type as PhysicalType;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::RowAccessor;
+
+    #[test]
+    fn test_export_tables() {
+        let dir = std::env::temp_dir().join(format!("haiku_fh_export_test_{}", std::process::id()));
+        let mut config = ExportSinkConfig::new(dir.to_string_lossy().to_string());
+        config.levels = 2;
+        let instrument_index = HashMap::from([("BTC-PERPETUAL".to_string(), 0)]);
+        let mut sink = ExportSink::new(&config, &instrument_index).unwrap();
+
+        let mut book = OrderbookData { bid_prices: [0.0; 10], ask_prices: [0.0; 10], bid_sizes: [0.0; 10], ask_sizes: [0.0; 10] };
+        book.bid_prices[0] = 100.1;
+        book.bid_sizes[0] = 2.0;
+        book.ask_prices[0] = 101.0;
+        book.ask_sizes[0] = 1.5;
+        let trade = TradeEvent { instrument_idx: 0, price: 101.0, size: 0.5, side: 1, timestamp_ns: 1_001, trade_id: 42, padding: [0; 6] };
+        sink.on_book(0, &book, 1_000, 0b11).unwrap();
+        sink.on_trade(&trade).unwrap();
+        sink.on_status(SinkStatus::Shutdown).unwrap();
+
+        let book_csv = std::fs::read_to_string(dir.join("BTC-PERPETUAL/book.csv")).unwrap();
+        assert_eq!(
+            book_csv,
+            "ts,seq,bid_price_1,bid_size_1,ask_price_1,ask_size_1,bid_price_2,bid_size_2,ask_price_2,ask_size_2\n1000,1,100.1,2,101,1.5,0,0,0,0\n"
+        );
+        let trades_csv = std::fs::read_to_string(dir.join("BTC-PERPETUAL/trades.csv")).unwrap();
+        assert_eq!(trades_csv, "ts,seq,price,size,side,trade_id\n1001,2,101,0.5,1,42\n");
+
+        let trades = SerializedFileReader::new(File::open(dir.join("BTC-PERPETUAL/trades.parquet")).unwrap()).unwrap();
+        let metadata = trades.metadata().file_metadata();
+        assert_eq!(metadata.num_rows(), 1);
+        let schema: Vec<(&str, PhysicalType)> = metadata.schema_descr().columns().iter().map(|c| (c.name(), c.physical_type())).collect();
+        assert_eq!(
+            schema,
+            [
+                ("ts", PhysicalType::INT64),
+                ("seq", PhysicalType::INT64),
+                ("price", PhysicalType::DOUBLE),
+                ("size", PhysicalType::FLOAT),
+                ("side", PhysicalType::INT32),
+                ("trade_id", PhysicalType::INT64),
+            ]
+        );
+        let row = trades.get_row_iter(None).unwrap().next().unwrap().unwrap();
+        assert_eq!((row.get_long(0).unwrap(), row.get_long(1).unwrap()), (1_001, 2));
+        assert_eq!((row.get_double(2).unwrap(), row.get_float(3).unwrap()), (101.0, 0.5));
+        assert_eq!((row.get_int(4).unwrap(), row.get_long(5).unwrap()), (1, 42));
+
+        let book = SerializedFileReader::new(File::open(dir.join("BTC-PERPETUAL/book.parquet")).unwrap()).unwrap();
+        let metadata = book.metadata().file_metadata();
+        assert_eq!(metadata.num_rows(), 1);
+        assert_eq!(metadata.schema_descr().num_columns(), 2 + 4 * 2);
+        assert_eq!(metadata.schema_descr().column(2).name(), "bid_price_1");
+        assert_eq!(metadata.schema_descr().column(2).physical_type(), PhysicalType::DOUBLE);
+        let row = book.get_row_iter(None).unwrap().next().unwrap().unwrap();
+        assert_eq!((row.get_long(0).unwrap(), row.get_long(1).unwrap()), (1_000, 1));
+        assert_eq!((row.get_double(2).unwrap(), row.get_float(3).unwrap(), row.get_float(5).unwrap()), (100.1, 2.0, 1.5));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,436 @@
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use haiku_common::shm_accessor::market_data_type::{OrderbookData, TradeEvent};
+use crate::orderbook_management::SHM_BOOK_LEVELS;
+use crate::sinks::{MarketDataSink, SinkError, SinkStatus};
+use crate::wire::{MAX_MESSAGE_SIZE, WireMessage, encode, status_channel};
+
+// a retransmission request cannot ask for more than this many messages
+const MAX_RETRANSMIT: u32 = 10_000;
+// clients served at the same time, the next ones are closed right away
+const MAX_RETRANSMIT_CLIENTS: usize = 16;
+// a client idle or not reading for this long is closed
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MulticastSinkConfig {
+    // destination "ip:port", a multicast group or a plain address (e.g. loopback for tests)
+    pub group: String,
+    // local IPv4 address the packets leave from
+    #[serde(default = "default_interface")]
+    pub interface: String,
+    #[serde(default = "default_ttl")]
+    pub ttl: u32,
+    // local subscribers receive the packets too
+    #[serde(default = "default_loopback")]
+    pub loopback: bool,
+    // book levels per side in each book message
+    #[serde(default = "default_levels")]
+    pub levels: usize,
+    // "ip:port" of the TCP retransmission/snapshot service, none when not set
+    #[serde(default)]
+    pub retransmit_addr: Option<String>,
+    // messages kept per channel for retransmission
+    #[serde(default = "default_retransmit_messages")]
+    pub retransmit_messages: usize,
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+}
+
+fn default_interface() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_ttl() -> u32 {
+    1
+}
+
+fn default_loopback() -> bool {
+    true
+}
+
+fn default_levels() -> usize {
+    SHM_BOOK_LEVELS
+}
+
+fn default_retransmit_messages() -> usize {
+    1024
+}
+
+fn default_queue_size() -> usize {
+    65536
+}
+
+// Request to the retransmission service, 16 bytes:
+//   op u8 (1 range, 2 snapshot), padding u8, channel u16, count u32, from seq u64
+// The reply is every message still kept with a sequence in [from, from + count), each prefixed by its
+// length (u32), then a length of 0. Messages dropped before being sent are missing from it as well.
+// A snapshot is the last book message of the channel, with its sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetransmitRequest {
+    Range { channel: u16, from_seq: u64, count: u32 },
+    Snapshot { channel: u16 },
+}
+
+impl RetransmitRequest {
+    pub fn encode(&self) -> [u8; 16] {
+        let (op, channel, count, from_seq) = match *self {
+            RetransmitRequest::Range { channel, from_seq, count } => (1u8, channel, count, from_seq),
+            RetransmitRequest::Snapshot { channel } => (2u8, channel, 0, 0),
+        };
+        let mut request = [0u8; 16];
+        request[0] = op;
+        request[2..4].copy_from_slice(&channel.to_le_bytes());
+        request[4..8].copy_from_slice(&count.to_le_bytes());
+        request[8..16].copy_from_slice(&from_seq.to_le_bytes());
+        request
+    }
+
+    fn decode(request: &[u8; 16]) -> Option<Self> {
+        let channel = u16::from_le_bytes([request[2], request[3]]);
+        match request[0] {
+            1 => Some(RetransmitRequest::Range {
+                channel,
+                count: u32::from_le_bytes(request[4..8].try_into().ok()?),
+                from_seq: u64::from_le_bytes(request[8..16].try_into().ok()?),
+            }),
+            2 => Some(RetransmitRequest::Snapshot { channel }),
+            _ => None,
+        }
+    }
+}
+
+// Client side of the retransmission service, the encoded messages in sequence order
+pub fn fetch(stream: &mut TcpStream, request: RetransmitRequest) -> std::io::Result<Vec<Vec<u8>>> {
+    stream.write_all(&request.encode())?;
+    let mut messages = Vec::new();
+    loop {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len == 0 {
+            return Ok(messages);
+        }
+        let mut message = vec![0u8; len];
+        stream.read_exact(&mut message)?;
+        messages.push(message);
+    }
+}
+
+#[derive(Default)]
+struct ChannelHistory {
+    // increasing sequences, oldest first, with holes where messages were dropped
+    messages: VecDeque<(u64, Vec<u8>)>,
+    last_book: Option<Vec<u8>>,
+}
+
+struct RetransmitStore {
+    channels: HashMap<u16, ChannelHistory>,
+    capacity: usize,
+}
+
+impl RetransmitStore {
+    fn push(&mut self, channel: u16, seq: u64, message: &[u8], is_book: bool) {
+        let history = self.channels.entry(channel).or_default();
+        if history.messages.len() >= self.capacity {
+            history.messages.pop_front();
+        }
+        history.messages.push_back((seq, message.to_vec()));
+        if is_book {
+            history.last_book = Some(message.to_vec());
+        }
+    }
+
+    fn get(&self, request: RetransmitRequest) -> Vec<Vec<u8>> {
+        match request {
+            RetransmitRequest::Range { channel, from_seq, count } => {
+                let Some(history) = self.channels.get(&channel) else { return Vec::new() };
+                let end_seq = from_seq.saturating_add(count.min(MAX_RETRANSMIT) as u64);
+                let first = history.messages.partition_point(|(seq, _)| *seq < from_seq);
+                history
+                    .messages
+                    .range(first..)
+                    .take_while(|(seq, _)| *seq < end_seq)
+                    .map(|(_, message)| message.clone())
+                    .collect()
+            }
+            RetransmitRequest::Snapshot { channel } => {
+                self.channels.get(&channel).and_then(|history| history.last_book.clone()).into_iter().collect()
+            }
+        }
+    }
+}
+
+fn serve_client(mut stream: TcpStream, store: &Mutex<RetransmitStore>) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(RETRANSMIT_TIMEOUT))?;
+    stream.set_write_timeout(Some(RETRANSMIT_TIMEOUT))?;
+    let mut request = [0u8; 16];
+    loop {
+        match stream.read_exact(&mut request) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+        let messages = match RetransmitRequest::decode(&request) {
+            Some(request) => store.lock().unwrap_or_else(|e| e.into_inner()).get(request),
+            None => Vec::new(),
+        };
+        let mut reply = Vec::with_capacity(messages.iter().map(|message| 4 + message.len()).sum::<usize>() + 4);
+        for message in messages {
+            reply.extend_from_slice(&(message.len() as u32).to_le_bytes());
+            reply.extend_from_slice(&message);
+        }
+        reply.extend_from_slice(&0u32.to_le_bytes());
+        stream.write_all(&reply)?;
+    }
+}
+
+// One thread per client, they only come after a loss: at most MAX_RETRANSMIT_CLIENTS of them
+fn serve_retransmit(listener: TcpListener, store: Arc<Mutex<RetransmitStore>>) {
+    let clients = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("multicast: retransmission client not accepted: {}", e);
+                continue;
+            }
+        };
+        if clients.fetch_add(1, Ordering::Relaxed) >= MAX_RETRANSMIT_CLIENTS {
+            clients.fetch_sub(1, Ordering::Relaxed);
+            warn!("multicast: {} retransmission clients already, {:?} closed", MAX_RETRANSMIT_CLIENTS, stream.peer_addr().ok());
+            continue;
+        }
+        let (store, served) = (store.clone(), clients.clone());
+        let spawned = std::thread::Builder::new().name("fh-retransmit".to_string()).spawn(move || {
+            let peer = stream.peer_addr().ok();
+            if let Err(e) = serve_client(stream, &store) {
+                warn!("multicast: retransmission client {:?}: {}", peer, e);
+            }
+            served.fetch_sub(1, Ordering::Relaxed);
+        });
+        if let Err(e) = spawned {
+            clients.fetch_sub(1, Ordering::Relaxed);
+            error!("multicast: cannot serve a retransmission client: {}", e);
+        }
+    }
+}
+
+// Encodes and sends the messages, keeps them for the retransmission service
+fn publish_thread(rx: Receiver<(u16, u64, WireMessage)>, socket: UdpSocket, group: SocketAddr, store: Option<Arc<Mutex<RetransmitStore>>>) {
+    let mut packet = Vec::with_capacity(MAX_MESSAGE_SIZE);
+    let mut send_errors: u64 = 0;
+    while let Ok((channel, seq, message)) = rx.recv() {
+        packet.clear();
+        encode(&mut packet, channel, seq, &message);
+        if let Err(e) = socket.send_to(&packet, group) {
+            send_errors += 1;
+            if send_errors.is_power_of_two() {
+                error!("multicast: send to {} failed ({} so far): {}", group, send_errors, e);
+            }
+        }
+        if let Some(store) = &store {
+            store.lock().unwrap_or_else(|e| e.into_inner()).push(channel, seq, &packet, message.is_book());
+        }
+    }
+}
+
+fn udp_socket(group: SocketAddr, interface: Ipv4Addr, config: &MulticastSinkConfig) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    if group.ip().is_multicast() {
+        socket.set_multicast_if_v4(&interface)?;
+        socket.set_multicast_ttl_v4(config.ttl)?;
+        socket.set_multicast_loop_v4(config.loopback)?;
+    }
+    socket.bind(&SocketAddr::new(IpAddr::V4(interface), 0).into())?;
+    Ok(socket.into())
+}
+
+// Books, trades and status in the binary format of crate::wire, one message per datagram.
+// Sequences are given before the queue: a message dropped because the queue is full leaves a gap the
+// consumers see, and repair with the next book or a snapshot.
+pub struct MulticastSink {
+    name: String,
+    tx: Option<SyncSender<(u16, u64, WireMessage)>>,
+    // last sequence given, by channel
+    seqs: HashMap<u16, u64>,
+    thread: Option<JoinHandle<()>>,
+    levels: u8,
+    retransmit_addr: Option<SocketAddr>,
+    dropped: u64,
+}
+
+impl MulticastSink {
+    pub fn new(config: &MulticastSinkConfig) -> Result<Self, SinkError> {
+        let invalid = |reason: String| SinkError::Config(format!("multicast: {}", reason));
+        let group: SocketAddr = config.group.parse().map_err(|e| invalid(format!("group {}: {}", config.group, e)))?;
+        if !group.is_ipv4() {
+            return Err(invalid(format!("group {} is not IPv4", config.group)));
+        }
+        let interface: Ipv4Addr = config.interface.parse().map_err(|e| invalid(format!("interface {}: {}", config.interface, e)))?;
+        if config.levels == 0 || config.levels > SHM_BOOK_LEVELS {
+            return Err(invalid(format!("levels must be between 1 and {}, got {}", SHM_BOOK_LEVELS, config.levels)));
+        }
+        let socket = udp_socket(group, interface, config)?;
+
+        let (store, retransmit_addr) = match &config.retransmit_addr {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr)?;
+                let local_addr = listener.local_addr()?;
+                let store = Arc::new(Mutex::new(RetransmitStore { channels: HashMap::new(), capacity: config.retransmit_messages.max(1) }));
+                let served = store.clone();
+                std::thread::Builder::new().name("fh-retransmit".to_string()).spawn(move || serve_retransmit(listener, served))?;
+                info!("multicast: retransmission service on {}", local_addr);
+                (Some(store), Some(local_addr))
+            }
+            None => (None, None),
+        };
+
+        let (tx, rx) = sync_channel(config.queue_size.max(1));
+        let thread = std::thread::Builder::new()
+            .name("fh-multicast".to_string())
+            .spawn(move || publish_thread(rx, socket, group, store))?;
+        Ok(Self {
+            name: format!("multicast {}", config.group),
+            tx: Some(tx),
+            seqs: HashMap::new(),
+            thread: Some(thread),
+            levels: config.levels as u8,
+            retransmit_addr,
+            dropped: 0,
+        })
+    }
+
+    // Address the retransmission service listens on, useful when bound to port 0
+    pub fn retransmit_addr(&self) -> Option<SocketAddr> {
+        self.retransmit_addr
+    }
+
+    fn send(&mut self, channel: u16, message: WireMessage) -> Result<(), SinkError> {
+        let Some(tx) = self.tx.as_ref() else {
+            return Ok(());
+        };
+        let seq = self.seqs.entry(channel).or_insert(0);
+        *seq += 1;
+        match tx.try_send((channel, *seq, message)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                if self.dropped == 0 {
+                    warn!("multicast: queue full, messages are dropped");
+                }
+                self.dropped += 1;
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(_)) => Err(SinkError::Stopped(self.name.clone())),
+        }
+    }
+
+    // Waits for the queued messages to be sent
+    fn finish(&mut self) {
+        self.tx = None;
+        if let Some(Err(_)) = self.thread.take().map(|thread| thread.join()) {
+            error!("multicast: thread panicked");
+        }
+        if self.dropped > 0 {
+            warn!("multicast: {} messages dropped, the queue was full", self.dropped);
+            self.dropped = 0;
+        }
+    }
+}
+
+impl MarketDataSink for MulticastSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    fn on_book(&mut self, instrument_idx: usize, book: &OrderbookData, timestamp: u64, flag: u8) -> Result<(), SinkError> {
+        self.send(instrument_idx as u16, WireMessage::Book { timestamp, flag, levels: self.levels, book: *book })
+    }
+
+    #[inline]
+    fn on_trade(&mut self, trade: &TradeEvent) -> Result<(), SinkError> {
+        self.send(trade.instrument_idx as u16, WireMessage::Trade(*trade))
+    }
+
+    fn on_status(&mut self, status: SinkStatus) -> Result<(), SinkError> {
+        let result = self.send(status_channel(status), WireMessage::Status(status));
+        if status == SinkStatus::Shutdown {
+            self.finish();
+        }
+        result
+    }
+}
+
+impl Drop for MulticastSink {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::{ALL_CHANNELS, decode};
+
+    #[test]
+    fn test_loopback() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(std::time::Duration::from_secs(2))).unwrap();
+        let config: MulticastSinkConfig = serde_json::from_value(serde_json::json!({
+            "group": receiver.local_addr().unwrap().to_string(),
+            "interface": "127.0.0.1",
+            "levels": 2,
+            "retransmit_addr": "127.0.0.1:0",
+        }))
+        .unwrap();
+        let mut sink = MulticastSink::new(&config).unwrap();
+
+        let mut book = OrderbookData { bid_prices: [0.0; 10], ask_prices: [0.0; 10], bid_sizes: [0.0; 10], ask_sizes: [0.0; 10] };
+        book.bid_prices[0] = 100.5;
+        let trade = TradeEvent { instrument_idx: 2, price: 101.0, size: 0.5, side: 1, timestamp_ns: 1_001, trade_id: 42, padding: [0; 6] };
+        sink.on_book(2, &book, 1_000, 0b01).unwrap();
+        sink.on_trade(&trade).unwrap();
+        sink.on_status(SinkStatus::Shutdown).unwrap();
+
+        let mut packet = [0u8; MAX_MESSAGE_SIZE];
+        let mut headers = Vec::new();
+        for _ in 0..3 {
+            let (len, _) = receiver.recv_from(&mut packet).unwrap();
+            let (header, _, size) = decode(&packet[..len]).unwrap();
+            assert_eq!(size, len);
+            headers.push((header.channel, header.seq));
+        }
+        assert_eq!(headers, vec![(2, 1), (2, 2), (ALL_CHANNELS, 1)]);
+
+        let mut stream = TcpStream::connect(sink.retransmit_addr().unwrap()).unwrap();
+        let range = fetch(&mut stream, RetransmitRequest::Range { channel: 2, from_seq: 2, count: 10 }).unwrap();
+        assert_eq!(range.len(), 1);
+        assert!(matches!(decode(&range[0]).unwrap().1, WireMessage::Trade(trade) if trade.trade_id == 42));
+        let snapshot = fetch(&mut stream, RetransmitRequest::Snapshot { channel: 2 }).unwrap();
+        assert!(matches!(decode(&snapshot[0]).unwrap(), (header, WireMessage::Book { book, .. }, _) if header.seq == 1 && book.bid_prices[0] == 100.5));
+    }
+
+    #[test]
+    fn test_retransmit_with_drops() {
+        // 3 was dropped before being sent, 1 is out of the history
+        let mut store = RetransmitStore { channels: HashMap::new(), capacity: 4 };
+        for seq in [1, 2, 4, 5, 6] {
+            store.push(0, seq, &[seq as u8], false);
+        }
+        let range = |from_seq, count| store.get(RetransmitRequest::Range { channel: 0, from_seq, count });
+        assert_eq!(range(2, 3), vec![vec![2], vec![4]]);
+        assert_eq!(range(1, 2), vec![vec![2]]);
+        assert_eq!(range(3, 10), vec![vec![4], vec![5], vec![6]]);
+        assert!(range(7, 10).is_empty());
+    }
+}
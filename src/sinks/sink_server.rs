@@ -0,0 +1,552 @@
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::os::unix::fs::FileTypeExt;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::Notify;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{info, warn};
+use haiku_common::shm_accessor::market_data_type::{OrderbookData, TradeEvent};
+use crate::orderbook_management::SHM_BOOK_LEVELS;
+use crate::sinks::{MarketDataSink, SinkError, SinkStatus};
+use crate::wire::{ALL_CHANNELS, LevelChange, MAX_LEVEL_CHANGES, WireMessage, encode};
+
+// Subscription server for local consumers, over TCP and/or a Unix socket.
+//
+// A client sends one JSON line per request, the first one choosing the format:
+//   {"subscribe": ["BTC-PERPETUAL", "ETH-PERPETUAL"], "format": "binary" | "json"}
+// For each instrument it receives the current book, then the changes of the top of the book, the trades
+// and the book status, in the order the feed handler published them. The sequence counts the messages
+// of an instrument, the snapshot carries the sequence it is current at.
+//
+// binary: each message of crate::wire prefixed by its length (u32, little endian). The snapshot and the
+//         book after a reset are book messages, the rest book deltas.
+// json:   one object per line, "type" being snapshot, book, trade, status, shutdown or error. "bids" and
+//         "asks" are [price, size] pairs, in a book message only the levels that changed, size 0 removing one.
+//
+// A client that does not read fast enough to keep `client_queue` messages or less waiting is disconnected.
+// The writer only queues the messages, each client task encodes them in the format of its client.
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ServerSinkConfig {
+    // "ip:port"
+    #[serde(default)]
+    pub tcp_addr: Option<String>,
+    #[serde(default)]
+    pub unix_path: Option<String>,
+    #[serde(default = "default_client_queue")]
+    pub client_queue: usize,
+    // levels per side of the snapshots, the deltas cover the same levels
+    #[serde(default = "default_levels")]
+    pub levels: usize,
+}
+
+fn default_client_queue() -> usize {
+    4096
+}
+
+fn default_levels() -> usize {
+    SHM_BOOK_LEVELS
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientFormat {
+    Binary,
+    #[default]
+    Json,
+}
+
+#[derive(Deserialize, Debug)]
+struct ClientRequest {
+    subscribe: Vec<String>,
+    #[serde(default)]
+    format: Option<ClientFormat>,
+}
+
+// A message queued for a client, encoded by its task
+#[derive(Clone, Copy)]
+struct Update {
+    channel: u16,
+    seq: u64,
+    message: WireMessage,
+    snapshot: bool,
+}
+
+struct Client {
+    peer: String,
+    instruments: Vec<bool>,
+    tx: mpsc::Sender<Update>,
+    // told when the hub drops the client, its task stops without writing what is still queued
+    kicked: Arc<Notify>,
+}
+
+#[derive(Clone, Copy)]
+struct PublishedBook {
+    book: OrderbookData,
+    timestamp: u64,
+    flag: u8,
+}
+
+// State shared by the writer (through the sink) and the client tasks, the lock is never held across an await
+struct Hub {
+    instrument_index: HashMap<String, usize>,
+    names: Arc<Vec<String>>,
+    levels: usize,
+    seqs: Vec<u64>,
+    books: Vec<Option<PublishedBook>>,
+    // the next book goes out whole, after a book snapshot
+    whole_next: Vec<bool>,
+    clients: HashMap<u64, Client>,
+    next_client_id: u64,
+}
+
+// Appends the update to `buf`
+fn frame(buf: &mut Vec<u8>, format: ClientFormat, name: &str, update: &Update) {
+    match format {
+        ClientFormat::Binary => {
+            let start = buf.len();
+            buf.extend_from_slice(&[0; 4]);
+            encode(buf, update.channel, update.seq, &update.message);
+            let len = (buf.len() - start - 4) as u32;
+            buf[start..start + 4].copy_from_slice(&len.to_le_bytes());
+        }
+        ClientFormat::Json => {
+            let line = json_message(name, update.seq, &update.message, update.snapshot);
+            // writing to a Vec cannot fail
+            let _ = serde_json::to_writer(&mut *buf, &line);
+            buf.push(b'\n');
+        }
+    }
+}
+
+fn json_message(name: &str, seq: u64, message: &WireMessage, snapshot: bool) -> serde_json::Value {
+    match message {
+        WireMessage::Book { timestamp, levels, book, .. } => {
+            let side = |prices: &[f32], sizes: &[f32]| -> Vec<(f32, f32)> {
+                prices.iter().zip(sizes).take(*levels as usize).filter(|(price, _)| **price > 0.0).map(|(p, s)| (*p, *s)).collect()
+            };
+            json!({
+                "type": if snapshot { "snapshot" } else { "book" },
+                "instrument": name,
+                "seq": seq,
+                "ts": timestamp,
+                "bids": side(&book.bid_prices, &book.bid_sizes),
+                "asks": side(&book.ask_prices, &book.ask_sizes),
+            })
+        }
+        WireMessage::BookDelta { timestamp, count, changes, .. } => {
+            let side = |is_bid: bool| -> Vec<(f32, f32)> {
+                changes[..*count as usize].iter().filter(|c| c.is_bid == is_bid).map(|c| (c.price, c.size)).collect()
+            };
+            json!({"type": "book", "instrument": name, "seq": seq, "ts": timestamp, "bids": side(true), "asks": side(false)})
+        }
+        WireMessage::Trade(trade) => json!({
+            "type": "trade",
+            "instrument": name,
+            "seq": seq,
+            "ts": trade.timestamp_ns,
+            "price": trade.price,
+            "size": trade.size,
+            "side": if trade.side == 1 { "buy" } else { "sell" },
+            "trade_id": trade.trade_id,
+        }),
+        WireMessage::Status(SinkStatus::Shutdown) => json!({"type": "shutdown"}),
+        WireMessage::Status(status) => json!({
+            "type": "status",
+            "instrument": name,
+            "seq": seq,
+            "status": if matches!(status, SinkStatus::BookReset(_)) { "reset" } else { "snapshot" },
+        }),
+    }
+}
+
+// Changes of the top `levels` levels between two books, a level leaving the top is removed
+fn book_delta(previous: &OrderbookData, book: &OrderbookData, levels: usize) -> ([LevelChange; MAX_LEVEL_CHANGES], usize) {
+    let mut changes = [LevelChange::default(); MAX_LEVEL_CHANGES];
+    let mut count = 0;
+    let sides = [
+        (true, &previous.bid_prices, &previous.bid_sizes, &book.bid_prices, &book.bid_sizes),
+        (false, &previous.ask_prices, &previous.ask_sizes, &book.ask_prices, &book.ask_sizes),
+    ];
+    for (is_bid, old_prices, old_sizes, prices, sizes) in sides {
+        let old = || old_prices.iter().zip(old_sizes.iter()).take(levels).filter(|(price, _)| **price > 0.0);
+        let new = || prices.iter().zip(sizes.iter()).take(levels).filter(|(price, _)| **price > 0.0);
+        for (&price, &size) in new() {
+            if !old().any(|(&p, &s)| p == price && s == size) {
+                changes[count] = LevelChange { is_bid, price, size };
+                count += 1;
+            }
+        }
+        for (&price, _) in old() {
+            if !new().any(|(&p, _)| p == price) {
+                changes[count] = LevelChange { is_bid, price, size: 0.0 };
+                count += 1;
+            }
+        }
+    }
+    (changes, count)
+}
+
+impl Hub {
+    // Queues the message for the clients of the channel, drops those that are too slow
+    fn publish(&mut self, channel: u16, seq: u64, message: &WireMessage) {
+        let idx = channel as usize;
+        let update = Update { channel, seq, message: *message, snapshot: false };
+        let mut slow = Vec::new();
+        for (&id, client) in &self.clients {
+            if channel != ALL_CHANNELS && !client.instruments.get(idx).copied().unwrap_or(false) {
+                continue;
+            }
+            if let Err(TrySendError::Full(_)) = client.tx.try_send(update) {
+                slow.push(id);
+            }
+        }
+        for id in slow {
+            if let Some(client) = self.clients.remove(&id) {
+                warn!("server: client {} too slow, more than {} messages waiting, disconnected", client.peer, client.tx.max_capacity());
+                client.kicked.notify_one();
+            }
+        }
+    }
+
+    fn next_seq(&mut self, idx: usize) -> u64 {
+        if idx >= self.seqs.len() {
+            self.seqs.resize(idx + 1, 0);
+            self.books.resize(idx + 1, None);
+            self.whole_next.resize(idx + 1, false);
+        }
+        self.seqs[idx] += 1;
+        self.seqs[idx]
+    }
+
+    fn on_book(&mut self, idx: usize, book: &OrderbookData, timestamp: u64, flag: u8) {
+        let previous = self.books.get(idx).copied().flatten();
+        let whole = self.whole_next.get(idx).copied().unwrap_or(false);
+        let message = match previous {
+            Some(previous) if !whole => {
+                let (changes, count) = book_delta(&previous.book, book, self.levels);
+                if count == 0 {
+                    // the change is deeper than what the clients get
+                    return;
+                }
+                WireMessage::BookDelta { timestamp, flag, count: count as u8, changes }
+            }
+            _ => WireMessage::Book { timestamp, flag, levels: self.levels as u8, book: *book },
+        };
+        let seq = self.next_seq(idx);
+        self.books[idx] = Some(PublishedBook { book: *book, timestamp, flag });
+        self.whole_next[idx] = false;
+        self.publish(idx as u16, seq, &message);
+    }
+
+    fn on_status(&mut self, status: SinkStatus) {
+        match status {
+            SinkStatus::BookReset(idx) | SinkStatus::BookSnapshot(idx) => {
+                let seq = self.next_seq(idx);
+                if matches!(status, SinkStatus::BookReset(_)) {
+                    self.books[idx] = None;
+                }
+                self.whole_next[idx] = true;
+                self.publish(idx as u16, seq, &WireMessage::Status(status));
+            }
+            SinkStatus::Shutdown => {
+                self.publish(ALL_CHANNELS, 0, &WireMessage::Status(status));
+                // their tasks stop once what is queued is written
+                self.clients.clear();
+            }
+        }
+    }
+
+    fn register(&mut self, peer: String, tx: mpsc::Sender<Update>, kicked: Arc<Notify>) -> u64 {
+        self.next_client_id += 1;
+        let instruments = vec![false; self.names.len()];
+        self.clients.insert(self.next_client_id, Client { peer, instruments, tx, kicked });
+        self.next_client_id
+    }
+
+    // Sends the current book of each new instrument, in the client queue before any later update
+    fn subscribe(&mut self, id: u64, instruments: &[String]) -> Result<(), String> {
+        let indexes = instruments
+            .iter()
+            .map(|name| self.instrument_index.get(name).copied().ok_or_else(|| format!("unknown instrument {}", name)))
+            .collect::<Result<Vec<usize>, String>>()?;
+        let Some(client) = self.clients.get_mut(&id) else {
+            return Ok(());
+        };
+        for idx in indexes {
+            if client.instruments[idx] {
+                continue;
+            }
+            client.instruments[idx] = true;
+            let Some(Some(published)) = self.books.get(idx) else { continue };
+            let message = WireMessage::Book { timestamp: published.timestamp, flag: published.flag, levels: self.levels as u8, book: published.book };
+            let snapshot = Update { channel: idx as u16, seq: self.seqs[idx], message, snapshot: true };
+            if client.tx.try_send(snapshot).is_err() {
+                return Err("too many snapshots at once".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn lock(hub: &Mutex<Hub>) -> std::sync::MutexGuard<'_, Hub> {
+    hub.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+async fn write_error<W: AsyncWrite + Unpin>(writer: &mut W, format: ClientFormat, reason: &str) {
+    if format == ClientFormat::Json {
+        let line = format!("{}\n", json!({"type": "error", "message": reason}));
+        let _ = writer.write_all(line.as_bytes()).await;
+    }
+}
+
+async fn serve_client<S>(stream: S, peer: String, hub: Arc<Mutex<Hub>>, client_queue: usize)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    let Ok(Some(line)) = lines.next_line().await else { return };
+    let request: ClientRequest = match serde_json::from_str(&line) {
+        Ok(request) => request,
+        Err(e) => {
+            write_error(&mut writer, ClientFormat::Json, &format!("invalid request: {}", e)).await;
+            return;
+        }
+    };
+    let format = request.format.unwrap_or_default();
+    let (tx, mut rx) = mpsc::channel(client_queue.max(1));
+    let kicked = Arc::new(Notify::new());
+    let (id, names) = {
+        let mut hub = lock(&hub);
+        (hub.register(peer.clone(), tx, kicked.clone()), hub.names.clone())
+    };
+    info!("server: client {} connected ({:?})", peer, format);
+    let mut buf = Vec::new();
+
+    let mut request = Some(request);
+    loop {
+        if let Some(request) = request.take() {
+            let subscribed = lock(&hub).subscribe(id, &request.subscribe);
+            if let Err(reason) = subscribed {
+                warn!("server: client {} request refused: {}", peer, reason);
+                write_error(&mut writer, format, &reason).await;
+                break;
+            }
+        }
+        tokio::select! {
+            _ = kicked.notified() => break,
+            update = rx.recv() => {
+                let Some(update) = update else { break };
+                buf.clear();
+                frame(&mut buf, format, names.get(update.channel as usize).map_or("", |name| name.as_str()), &update);
+                tokio::select! {
+                    written = writer.write_all(&buf) => if written.is_err() { break },
+                    _ = kicked.notified() => break,
+                }
+            }
+            line = lines.next_line() => {
+                let Ok(Some(line)) = line else { break };
+                match serde_json::from_str::<ClientRequest>(&line) {
+                    Ok(next) => request = Some(next),
+                    Err(e) => {
+                        write_error(&mut writer, format, &format!("invalid request: {}", e)).await;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    lock(&hub).clients.remove(&id);
+    let _ = writer.shutdown().await;
+    info!("server: client {} disconnected", peer);
+}
+
+// Books, trades and status to the subscribed clients. The sink only queues, every client has its own task
+// encoding and writing its messages.
+pub struct ServerSink {
+    name: String,
+    hub: Arc<Mutex<Hub>>,
+    tcp_addr: Option<std::net::SocketAddr>,
+}
+
+impl ServerSink {
+    pub fn new(config: &ServerSinkConfig, instrument_index: &HashMap<String, usize>) -> Result<Self, SinkError> {
+        if config.tcp_addr.is_none() && config.unix_path.is_none() {
+            return Err(SinkError::Config("server: tcp_addr or unix_path is needed".to_string()));
+        }
+        if config.levels == 0 || config.levels > SHM_BOOK_LEVELS {
+            return Err(SinkError::Config(format!("server: levels must be between 1 and {}, got {}", SHM_BOOK_LEVELS, config.levels)));
+        }
+        let runtime = tokio::runtime::Handle::try_current().map_err(|e| SinkError::Config(format!("server: {}", e)))?;
+        let nb_instruments = instrument_index.values().max().map_or(0, |idx| idx + 1);
+        let mut names = vec![String::new(); nb_instruments];
+        for (instrument, &idx) in instrument_index {
+            names[idx] = instrument.clone();
+        }
+        let hub = Arc::new(Mutex::new(Hub {
+            instrument_index: instrument_index.clone(),
+            names: Arc::new(names),
+            levels: config.levels,
+            seqs: vec![0; nb_instruments],
+            books: vec![None; nb_instruments],
+            whole_next: vec![false; nb_instruments],
+            clients: HashMap::new(),
+            next_client_id: 0,
+        }));
+        let client_queue = config.client_queue;
+
+        let mut tcp_addr = None;
+        if let Some(addr) = &config.tcp_addr {
+            let listener = std::net::TcpListener::bind(addr)?;
+            listener.set_nonblocking(true)?;
+            tcp_addr = Some(listener.local_addr()?);
+            let listener = {
+                let _guard = runtime.enter();
+                tokio::net::TcpListener::from_std(listener)?
+            };
+            let hub = hub.clone();
+            runtime.spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, peer)) => {
+                            let _ = stream.set_nodelay(true);
+                            tokio::spawn(serve_client(stream, peer.to_string(), hub.clone(), client_queue));
+                        }
+                        Err(e) => warn!("server: tcp client not accepted: {}", e),
+                    }
+                }
+            });
+            info!("server: listening on {}", addr);
+        }
+        if let Some(path) = &config.unix_path {
+            // the socket of a previous run, anything else at the path makes the bind fail
+            if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+            let listener = std::os::unix::net::UnixListener::bind(path)?;
+            listener.set_nonblocking(true)?;
+            let listener = {
+                let _guard = runtime.enter();
+                tokio::net::UnixListener::from_std(listener)?
+            };
+            let hub = hub.clone();
+            let accept_path = path.clone();
+            runtime.spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            tokio::spawn(serve_client(stream, accept_path.clone(), hub.clone(), client_queue));
+                        }
+                        Err(e) => warn!("server: unix client not accepted: {}", e),
+                    }
+                }
+            });
+            info!("server: listening on {}", path);
+        }
+
+        let name = format!("server {}", [config.tcp_addr.as_deref(), config.unix_path.as_deref()].into_iter().flatten().collect::<Vec<_>>().join(" "));
+        Ok(Self { name, hub, tcp_addr })
+    }
+
+    // Address of the TCP listener, useful when bound to port 0
+    pub fn tcp_addr(&self) -> Option<std::net::SocketAddr> {
+        self.tcp_addr
+    }
+}
+
+impl MarketDataSink for ServerSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    fn on_book(&mut self, instrument_idx: usize, book: &OrderbookData, timestamp: u64, flag: u8) -> Result<(), SinkError> {
+        lock(&self.hub).on_book(instrument_idx, book, timestamp, flag);
+        Ok(())
+    }
+
+    #[inline]
+    fn on_trade(&mut self, trade: &TradeEvent) -> Result<(), SinkError> {
+        let mut hub = lock(&self.hub);
+        let idx = trade.instrument_idx as usize;
+        let seq = hub.next_seq(idx);
+        hub.publish(idx as u16, seq, &WireMessage::Trade(*trade));
+        Ok(())
+    }
+
+    fn on_status(&mut self, status: SinkStatus) -> Result<(), SinkError> {
+        lock(&self.hub).on_status(status);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::Lines;
+    use tokio::net::tcp::OwnedReadHalf;
+
+    async fn next(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> serde_json::Value {
+        serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_json_subscription() {
+        let config: ServerSinkConfig = serde_json::from_str(r#"{"tcp_addr": "127.0.0.1:0", "levels": 2}"#).unwrap();
+        let instrument_index = HashMap::from([("BTC-PERPETUAL".to_string(), 0), ("ETH-PERPETUAL".to_string(), 1)]);
+        let mut sink = ServerSink::new(&config, &instrument_index).unwrap();
+
+        let mut book = OrderbookData { bid_prices: [0.0; 10], ask_prices: [0.0; 10], bid_sizes: [0.0; 10], ask_sizes: [0.0; 10] };
+        book.bid_prices[0] = 100.0;
+        book.bid_sizes[0] = 1.0;
+        book.ask_prices[0] = 101.0;
+        book.ask_sizes[0] = 2.0;
+        sink.on_book(0, &book, 1_000, 0b11).unwrap();
+
+        let stream = tokio::net::TcpStream::connect(sink.tcp_addr().unwrap()).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer.write_all(b"{\"subscribe\": [\"BTC-PERPETUAL\"], \"format\": \"json\"}\n").await.unwrap();
+
+        let snapshot = next(&mut lines).await;
+        assert_eq!(snapshot["type"], "snapshot");
+        assert_eq!(snapshot["seq"], 1);
+        assert_eq!(snapshot["bids"], json!([[100.0, 1.0]]));
+
+        // the bid moves up a tick, the ETH book is not subscribed
+        book.bid_prices[0] = 100.5;
+        sink.on_book(1, &book, 1_001, 0b01).unwrap();
+        sink.on_book(0, &book, 1_002, 0b01).unwrap();
+        let update = next(&mut lines).await;
+        assert_eq!((update["type"].as_str(), update["seq"].as_u64()), (Some("book"), Some(2)));
+        assert_eq!(update["bids"], json!([[100.5, 1.0], [100.0, 0.0]]));
+        assert_eq!(update["asks"], json!([]));
+
+        let trade = TradeEvent { instrument_idx: 0, price: 101.0, size: 0.5, side: 1, timestamp_ns: 1_003, trade_id: 42, padding: [0; 6] };
+        sink.on_trade(&trade).unwrap();
+        let trade = next(&mut lines).await;
+        assert_eq!((trade["type"].as_str(), trade["seq"].as_u64(), trade["side"].as_str()), (Some("trade"), Some(3), Some("buy")));
+
+        writer.write_all(b"{\"subscribe\": [\"SOL-PERPETUAL\"]}\n").await.unwrap();
+        assert_eq!(next(&mut lines).await["type"], "error");
+    }
+
+    #[tokio::test]
+    async fn test_unix_path_not_a_socket() {
+        let path = std::env::temp_dir().join(format!("haiku_fh_server_test_{}", std::process::id()));
+        std::fs::write(&path, b"not a socket").unwrap();
+        let config = ServerSinkConfig { tcp_addr: None, unix_path: Some(path.to_string_lossy().into_owned()), client_queue: 16, levels: 1 };
+        assert!(ServerSink::new(&config, &HashMap::new()).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"not a socket");
+        std::fs::remove_file(&path).unwrap();
+
+        // the socket left by a previous sink is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(ServerSink::new(&config, &HashMap::new()).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
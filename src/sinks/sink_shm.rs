@@ -0,0 +1,78 @@
+use serde::Deserialize;
+use haiku_common::metadata::ShmMetadata;
+use haiku_common::shm_accessor::SHMAccessor;
+use haiku_common::shm_accessor::market_data_type::{OrderbookData, TradeEvent};
+use haiku_common::shm_accessor::trade_ring_buffer::TradeRingBuffer;
+use crate::sinks::{MarketDataSink, SinkError};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ShmSinkConfig {
+    // segments created by HaikuSHM, see the README
+    #[serde(default = "default_orderbook_shm")]
+    pub orderbook_shm: String,
+    #[serde(default = "default_trades_shm")]
+    pub trades_shm: String,
+    #[serde(default = "default_trades_capacity")]
+    pub trades_capacity: usize,
+}
+
+fn default_orderbook_shm() -> String {
+    "rust_integration".to_string()
+}
+
+fn default_trades_shm() -> String {
+    "rust_integration_trades_buffer".to_string()
+}
+
+fn default_trades_capacity() -> usize {
+    1000
+}
+
+impl Default for ShmSinkConfig {
+    fn default() -> Self {
+        Self {
+            orderbook_shm: default_orderbook_shm(),
+            trades_shm: default_trades_shm(),
+            trades_capacity: default_trades_capacity(),
+        }
+    }
+}
+
+// Books to the SHMAccessor layout, trades to the Trade Ring Buffer
+pub struct ShmSink {
+    name: String,
+    shm_writer: SHMAccessor,
+    trade_buffer: TradeRingBuffer,
+}
+
+impl ShmSink {
+    pub fn new(config: &ShmSinkConfig, metadata: &ShmMetadata) -> Result<Self, SinkError> {
+        let shm_writer = SHMAccessor::new(&config.orderbook_shm, metadata)
+            .map_err(|e| SinkError::Shm(format!("cannot open {}: {:?}", config.orderbook_shm, e)))?;
+        let trade_buffer = TradeRingBuffer::new(&config.trades_shm, config.trades_capacity)
+            .map_err(|e| SinkError::Shm(format!("cannot open {}: {:?}", config.trades_shm, e)))?;
+        Ok(Self { name: format!("shm {}", config.orderbook_shm), shm_writer, trade_buffer })
+    }
+}
+
+impl MarketDataSink for ShmSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    fn on_book(&mut self, instrument_idx: usize, book: &OrderbookData, timestamp: u64, flag: u8) -> Result<(), SinkError> {
+        self.shm_writer
+            .write_orderbook_update_consistency_from_idx(instrument_idx, *book, timestamp, flag)
+            .map(|_| ())
+            .map_err(|e| SinkError::Shm(format!("{:?}", e)))
+    }
+
+    #[inline]
+    fn on_trade(&mut self, trade: &TradeEvent) -> Result<(), SinkError> {
+        self.trade_buffer
+            .push_trade(*trade)
+            .map(|_| ())
+            .map_err(|e| SinkError::Shm(format!("{:?}", e)))
+    }
+}
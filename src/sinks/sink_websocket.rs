@@ -0,0 +1,436 @@
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::MissedTickBehavior;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tracing::{info, warn};
+use haiku_common::shm_accessor::market_data_type::{OrderbookData, TradeEvent};
+use crate::sinks::{MarketDataSink, SinkError, SinkStatus};
+
+// Websocket gateway for the dashboards, JSON only.
+//
+// A client sends text messages such as
+//   {"subscribe": ["BTC-PERPETUAL"], "throttle_ms": 500}
+//   {"unsubscribe": ["BTC-PERPETUAL"]}
+// and is answered {"type": "subscribed", "instruments": [...]} or {"type": "error", "message": ...}.
+// It then receives, as text messages:
+//   {"type": "top", "instrument": ..., "ts": ..., "bid": [price, size] | null, "ask": [price, size] | null}
+//       at most once per instrument and throttle period, only when the top of the book changed;
+//       both sides null after a book reset
+//   {"type": "trade", "instrument": ..., "ts": ..., "price": ..., "size": ..., "side": "buy" | "sell", "trade_id": ...,
+//    "count": n, "buy_volume": ..., "sell_volume": ...}
+//       at most once per instrument and throttle period too: the last trade, with the number of trades and
+//       the taker buy/sell volume since the previous trade message
+//   {"type": "status", "instrument": ..., "status": "reset" | "snapshot"}
+//   {"type": "dropped", "messages": n}   status not sent because the client was behind
+//   {"type": "shutdown"}
+// The throttle period is the one asked for, not below `throttle_ms` of the config.
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct WebsocketSinkConfig {
+    // "ip:port"
+    pub addr: String,
+    #[serde(default = "default_throttle_ms")]
+    pub throttle_ms: u64,
+    // status waiting per client, beyond that they are dropped
+    #[serde(default = "default_client_queue")]
+    pub client_queue: usize,
+}
+
+fn default_throttle_ms() -> u64 {
+    100
+}
+
+fn default_client_queue() -> usize {
+    1024
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct ClientRequest {
+    #[serde(default)]
+    subscribe: Vec<String>,
+    #[serde(default)]
+    unsubscribe: Vec<String>,
+    #[serde(default)]
+    throttle_ms: Option<u64>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Top {
+    bid: Option<(f32, f32)>,
+    ask: Option<(f32, f32)>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct TopState {
+    top: Option<Top>,
+    timestamp: u64,
+    // incremented on each change, 0 until the first book
+    version: u64,
+}
+
+// Trades of an instrument not sent to a client yet, they go in one message at its next tick
+#[derive(Clone, Copy, Default)]
+struct PendingTrades {
+    last: Option<TradeEvent>,
+    count: u64,
+    buy_volume: f64,
+    sell_volume: f64,
+}
+
+struct Client {
+    instruments: Vec<bool>,
+    trades: Vec<PendingTrades>,
+    tx: mpsc::Sender<Arc<String>>,
+    dropped: u64,
+}
+
+struct Gateway {
+    instrument_index: HashMap<String, usize>,
+    names: Vec<String>,
+    tops: Vec<TopState>,
+    clients: HashMap<u64, Client>,
+    next_client_id: u64,
+}
+
+fn level(price: f32, size: f32) -> Option<(f32, f32)> {
+    (price > 0.0).then_some((price, size))
+}
+
+impl Gateway {
+    fn state(&mut self, idx: usize) -> &mut TopState {
+        if idx >= self.tops.len() {
+            self.tops.resize(idx + 1, TopState::default());
+        }
+        &mut self.tops[idx]
+    }
+
+    // Queues the message for the clients subscribed to the instrument, `None` for all of them
+    fn send(&mut self, idx: Option<usize>, message: &serde_json::Value) {
+        if self.clients.is_empty() {
+            return;
+        }
+        let message = Arc::new(message.to_string());
+        for client in self.clients.values_mut() {
+            if idx.is_some_and(|idx| !client.instruments.get(idx).copied().unwrap_or(false)) {
+                continue;
+            }
+            if let Err(TrySendError::Full(_)) = client.tx.try_send(message.clone()) {
+                client.dropped += 1;
+            }
+        }
+    }
+
+    // Returns the instruments now subscribed and the indexes of those in `request.subscribe`
+    fn subscribe(&mut self, id: u64, request: &ClientRequest) -> Result<(Vec<String>, Vec<usize>), String> {
+        let index = |names: &[String]| {
+            names
+                .iter()
+                .map(|name| self.instrument_index.get(name).copied().ok_or_else(|| format!("unknown instrument {}", name)))
+                .collect::<Result<Vec<usize>, String>>()
+        };
+        let (added, removed) = (index(&request.subscribe)?, index(&request.unsubscribe)?);
+        let Some(client) = self.clients.get_mut(&id) else {
+            return Ok((Vec::new(), Vec::new()));
+        };
+        for &idx in &added {
+            client.instruments[idx] = true;
+        }
+        for idx in removed {
+            client.instruments[idx] = false;
+            client.trades[idx] = PendingTrades::default();
+        }
+        let subscribed = client.instruments.iter().enumerate().filter(|(_, subscribed)| **subscribed).map(|(idx, _)| self.names[idx].clone());
+        Ok((subscribed.collect(), added))
+    }
+
+    // Tops of the subscribed instruments that changed since the versions in `sent`, which are updated, and
+    // the trades pending for the client
+    fn throttled(&mut self, id: u64, sent: &mut [u64]) -> (Vec<String>, u64) {
+        let Some(client) = self.clients.get_mut(&id) else {
+            return (Vec::new(), 0);
+        };
+        let mut messages = Vec::new();
+        for (idx, state) in self.tops.iter().enumerate() {
+            if !client.instruments.get(idx).copied().unwrap_or(false) || state.version == sent[idx] {
+                continue;
+            }
+            sent[idx] = state.version;
+            let top = state.top.unwrap_or(Top { bid: None, ask: None });
+            messages.push(json!({"type": "top", "instrument": self.names[idx], "ts": state.timestamp, "bid": top.bid, "ask": top.ask}).to_string());
+        }
+        for (idx, pending) in client.trades.iter_mut().enumerate() {
+            let PendingTrades { last: Some(trade), count, buy_volume, sell_volume } = std::mem::take(pending) else { continue };
+            messages.push(
+                json!({
+                    "type": "trade",
+                    "instrument": self.names[idx],
+                    "ts": trade.timestamp_ns,
+                    "price": trade.price,
+                    "size": trade.size,
+                    "side": if trade.side == 1 { "buy" } else { "sell" },
+                    "trade_id": trade.trade_id,
+                    "count": count,
+                    "buy_volume": buy_volume,
+                    "sell_volume": sell_volume,
+                })
+                .to_string(),
+            );
+        }
+        (messages, client.dropped)
+    }
+}
+
+fn lock(gateway: &Mutex<Gateway>) -> std::sync::MutexGuard<'_, Gateway> {
+    gateway.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+async fn serve_client(stream: TcpStream, peer: String, gateway: Arc<Mutex<Gateway>>, config: WebsocketSinkConfig) {
+    let ws = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            warn!("websocket: handshake with {} failed: {}", peer, e);
+            return;
+        }
+    };
+    let (mut write, mut read) = ws.split();
+    let (tx, mut rx) = mpsc::channel(config.client_queue.max(1));
+    let id = {
+        let mut gateway = lock(&gateway);
+        gateway.next_client_id += 1;
+        let id = gateway.next_client_id;
+        let nb_instruments = gateway.names.len();
+        let (instruments, trades) = (vec![false; nb_instruments], vec![PendingTrades::default(); nb_instruments]);
+        gateway.clients.insert(id, Client { instruments, trades, tx, dropped: 0 });
+        id
+    };
+    info!("websocket: client {} connected", peer);
+
+    let new_interval = |throttle_ms: u64| {
+        let mut interval = tokio::time::interval(Duration::from_millis(throttle_ms.max(config.throttle_ms).max(1)));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        interval
+    };
+    let mut interval = new_interval(config.throttle_ms);
+    let mut sent = vec![0u64; lock(&gateway).names.len()];
+    loop {
+        let mut outgoing = Vec::new();
+        tokio::select! {
+            _ = interval.tick() => {
+                let (tops, dropped) = {
+                    let mut gateway = lock(&gateway);
+                    let changed = gateway.throttled(id, &mut sent);
+                    if let Some(client) = gateway.clients.get_mut(&id) {
+                        client.dropped = 0;
+                    }
+                    changed
+                };
+                if dropped > 0 {
+                    outgoing.push(json!({"type": "dropped", "messages": dropped}).to_string());
+                }
+                outgoing.extend(tops);
+            }
+            message = rx.recv() => match message {
+                Some(message) => outgoing.push(message.as_ref().clone()),
+                // shutdown, or the gateway dropped the client
+                None => break,
+            },
+            request = read.next() => {
+                let text = match request {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                let reply = serde_json::from_str::<ClientRequest>(&text).map_err(|e| format!("invalid request: {}", e)).and_then(|request| {
+                    let (subscribed, added) = lock(&gateway).subscribe(id, &request)?;
+                    // a new subscription gets the current top at the next tick
+                    for idx in added {
+                        sent[idx] = 0;
+                    }
+                    if let Some(throttle_ms) = request.throttle_ms {
+                        interval = new_interval(throttle_ms);
+                    }
+                    Ok(subscribed)
+                });
+                outgoing.push(match reply {
+                    Ok(instruments) => json!({"type": "subscribed", "instruments": instruments}),
+                    Err(reason) => json!({"type": "error", "message": reason}),
+                }.to_string());
+            }
+        }
+        let mut written = Ok(());
+        for message in outgoing {
+            written = written.and(write.feed(Message::Text(message)).await);
+        }
+        if written.and(write.flush().await).is_err() {
+            break;
+        }
+    }
+    lock(&gateway).clients.remove(&id);
+    let _ = write.send(Message::Close(None)).await;
+    info!("websocket: client {} disconnected", peer);
+}
+
+// Top of the book, trades and status to the dashboards. The sink only queues, every client has its own task.
+pub struct WebsocketSink {
+    name: String,
+    gateway: Arc<Mutex<Gateway>>,
+    addr: std::net::SocketAddr,
+}
+
+impl WebsocketSink {
+    pub fn new(config: &WebsocketSinkConfig, instrument_index: &HashMap<String, usize>) -> Result<Self, SinkError> {
+        let runtime = tokio::runtime::Handle::try_current().map_err(|e| SinkError::Config(format!("websocket: {}", e)))?;
+        let nb_instruments = instrument_index.values().max().map_or(0, |idx| idx + 1);
+        let mut names = vec![String::new(); nb_instruments];
+        for (instrument, &idx) in instrument_index {
+            names[idx] = instrument.clone();
+        }
+        let gateway = Arc::new(Mutex::new(Gateway {
+            instrument_index: instrument_index.clone(),
+            names,
+            tops: vec![TopState::default(); nb_instruments],
+            clients: HashMap::new(),
+            next_client_id: 0,
+        }));
+
+        let listener = std::net::TcpListener::bind(&config.addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let listener = {
+            let _guard = runtime.enter();
+            tokio::net::TcpListener::from_std(listener)?
+        };
+        let accepting = gateway.clone();
+        let client_config = config.clone();
+        runtime.spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        let _ = stream.set_nodelay(true);
+                        tokio::spawn(serve_client(stream, peer.to_string(), accepting.clone(), client_config.clone()));
+                    }
+                    Err(e) => warn!("websocket: client not accepted: {}", e),
+                }
+            }
+        });
+        info!("websocket: listening on {}", addr);
+        Ok(Self { name: format!("websocket {}", config.addr), gateway, addr })
+    }
+
+    // Useful when bound to port 0
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+}
+
+impl MarketDataSink for WebsocketSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    fn on_book(&mut self, instrument_idx: usize, book: &OrderbookData, timestamp: u64, _flag: u8) -> Result<(), SinkError> {
+        let top = Top { bid: level(book.bid_prices[0], book.bid_sizes[0]), ask: level(book.ask_prices[0], book.ask_sizes[0]) };
+        let mut gateway = lock(&self.gateway);
+        let state = gateway.state(instrument_idx);
+        if state.top != Some(top) {
+            *state = TopState { top: Some(top), timestamp, version: state.version + 1 };
+        }
+        Ok(())
+    }
+
+    // Only adds the trade to what is pending for the subscribed clients, their tasks send it
+    #[inline]
+    fn on_trade(&mut self, trade: &TradeEvent) -> Result<(), SinkError> {
+        let mut gateway = lock(&self.gateway);
+        let idx = trade.instrument_idx as usize;
+        for client in gateway.clients.values_mut() {
+            if !client.instruments.get(idx).copied().unwrap_or(false) {
+                continue;
+            }
+            let pending = &mut client.trades[idx];
+            pending.last = Some(*trade);
+            pending.count += 1;
+            if trade.side == 1 {
+                pending.buy_volume += trade.size as f64;
+            } else {
+                pending.sell_volume += trade.size as f64;
+            }
+        }
+        Ok(())
+    }
+
+    fn on_status(&mut self, status: SinkStatus) -> Result<(), SinkError> {
+        let mut gateway = lock(&self.gateway);
+        match status {
+            SinkStatus::BookReset(idx) | SinkStatus::BookSnapshot(idx) => {
+                let is_reset = matches!(status, SinkStatus::BookReset(_));
+                if is_reset {
+                    let state = gateway.state(idx);
+                    *state = TopState { top: None, timestamp: state.timestamp, version: state.version + 1 };
+                }
+                let message = json!({"type": "status", "instrument": gateway.names.get(idx), "status": if is_reset { "reset" } else { "snapshot" }});
+                gateway.send(Some(idx), &message);
+            }
+            SinkStatus::Shutdown => {
+                gateway.send(None, &json!({"type": "shutdown"}));
+                // their tasks stop once what is queued is sent
+                gateway.clients.clear();
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+    async fn next(ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) -> serde_json::Value {
+        loop {
+            if let Message::Text(text) = ws.next().await.unwrap().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscription() {
+        let config: WebsocketSinkConfig = serde_json::from_str(r#"{"addr": "127.0.0.1:0", "throttle_ms": 10}"#).unwrap();
+        let instrument_index = HashMap::from([("BTC-PERPETUAL".to_string(), 0), ("ETH-PERPETUAL".to_string(), 1)]);
+        let mut sink = WebsocketSink::new(&config, &instrument_index).unwrap();
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", sink.local_addr())).await.unwrap();
+        ws.send(Message::Text(r#"{"subscribe": ["BTC-PERPETUAL", "DOGE-PERPETUAL"]}"#.to_string())).await.unwrap();
+        assert_eq!(next(&mut ws).await["type"], "error");
+        ws.send(Message::Text(r#"{"subscribe": ["BTC-PERPETUAL"]}"#.to_string())).await.unwrap();
+        assert_eq!(next(&mut ws).await, json!({"type": "subscribed", "instruments": ["BTC-PERPETUAL"]}));
+
+        let mut book = OrderbookData { bid_prices: [0.0; 10], ask_prices: [0.0; 10], bid_sizes: [0.0; 10], ask_sizes: [0.0; 10] };
+        book.bid_prices[0] = 100.0;
+        book.bid_sizes[0] = 1.0;
+        sink.on_book(1, &book, 1_000, 0b01).unwrap();
+        sink.on_book(0, &book, 1_001, 0b01).unwrap();
+        assert_eq!(next(&mut ws).await, json!({"type": "top", "instrument": "BTC-PERPETUAL", "ts": 1_001, "bid": [100.0, 1.0], "ask": null}));
+
+        // conflated until the next tick
+        let trade = TradeEvent { instrument_idx: 0, price: 100.0, size: 0.5, side: 0, timestamp_ns: 1_002, trade_id: 7, padding: [0; 6] };
+        sink.on_trade(&trade).unwrap();
+        sink.on_trade(&TradeEvent { size: 1.5, side: 1, trade_id: 8, ..trade }).unwrap();
+        sink.on_trade(&TradeEvent { instrument_idx: 1, ..trade }).unwrap();
+        let trade = next(&mut ws).await;
+        assert_eq!((trade["type"].as_str(), trade["side"].as_str(), trade["trade_id"].as_u64()), (Some("trade"), Some("buy"), Some(8)));
+        assert_eq!((trade["count"].as_u64(), trade["buy_volume"].as_f64(), trade["sell_volume"].as_f64()), (Some(2), Some(1.5), Some(0.5)));
+
+        sink.on_status(SinkStatus::Shutdown).unwrap();
+        assert_eq!(next(&mut ws).await["type"], "shutdown");
+    }
+}
@@ -0,0 +1,245 @@
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use tracing::warn;
+use haiku_common::shm_accessor::market_data_type::TradeEvent;
+use crate::orderbook_management::OrderbookManagerV2;
+use crate::parsing::parsing_fast_orderbook::OrderbookUpdateDataRaw;
+use crate::parsing::parsing_orderbook::OrderbookAction;
+
+// trades waiting for the book to consume their level, bounded in case the book stops updating
+const MAX_PENDING_TRADES: usize = 256;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TradeCheckConfig {
+    // ticks a trade can print beyond the touch it takes before it is flagged
+    #[serde(default)]
+    pub tolerance_ticks: i64,
+    // between a trade and the book update reducing the traded level, in exchange time
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+    // how long a price stays "seen" after it left the book
+    #[serde(default = "default_seen_retention_ms")]
+    pub seen_retention_ms: u64,
+    // a warning per flagged trade on top of the counters
+    #[serde(default)]
+    pub log_events: bool,
+}
+
+fn default_max_delay_ms() -> u64 {
+    100
+}
+
+fn default_seen_retention_ms() -> u64 {
+    60_000
+}
+
+// Per instrument, logged with the writer stats
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TradeCheckCounts {
+    pub trades: u64,
+    // buy above the best ask / sell below the best bid by more than the tolerance
+    pub trade_through: u64,
+    // price neither in the book nor in any update within the retention
+    pub unseen_price: u64,
+    // traded level not reduced by the book within max_delay_ms
+    pub late_consumption: u64,
+}
+
+impl TradeCheckCounts {
+    #[inline]
+    pub fn flagged(&self) -> u64 {
+        self.trade_through + self.unseen_price + self.late_consumption
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct SeenLevel {
+    last_seen_ms: u64,
+    last_reduced_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PendingTrade {
+    // side of the book the taker consumed
+    is_bid: bool,
+    ticks: i64,
+    timestamp: u64,
+}
+
+#[derive(Debug, Default)]
+struct InstrumentChecks {
+    // (is_bid, ticks) of every level that appeared in a snapshot or delta
+    seen: HashMap<(bool, i64), SeenLevel>,
+    pending: VecDeque<PendingTrade>,
+    counts: TradeCheckCounts,
+}
+
+// Cross-checks trades against the reconstructed books. Timestamps are the exchange ones (ms),
+// trades and book updates come from different channels so either can arrive first.
+pub struct TradeChecker {
+    config: TradeCheckConfig,
+    instruments: Vec<InstrumentChecks>,
+}
+
+impl TradeChecker {
+    pub fn new(config: TradeCheckConfig, nb_instruments: usize) -> Self {
+        let instruments = (0..nb_instruments).map(|_| InstrumentChecks::default()).collect();
+        Self { config, instruments }
+    }
+
+    #[inline]
+    pub fn counts(&self, instrument_idx: usize) -> TradeCheckCounts {
+        self.instruments.get(instrument_idx).map(|checks| checks.counts).unwrap_or_default()
+    }
+
+    // Before the update is applied, the previous sizes tell which levels it reduces
+    pub fn on_book_update(&mut self, instrument_idx: usize, book: &OrderbookManagerV2, update: &OrderbookUpdateDataRaw, timestamp: u64) {
+        let Some(checks) = self.instruments.get_mut(instrument_idx) else {
+            return;
+        };
+        let tick_size = book.tick_size();
+        for (levels, is_bid) in [(&update.bid_updates, true), (&update.ask_updates, false)] {
+            for level in levels.iter() {
+                // the book refuses the whole update
+                let Ok(price) = tick_size.to_ticks(level.price) else { continue };
+                let ticks = price.ticks;
+                let size = match level.action {
+                    OrderbookAction::Delete => 0.0,
+                    _ => level.size,
+                };
+                let seen = checks.seen.entry((is_bid, ticks)).or_default();
+                seen.last_seen_ms = timestamp;
+                if size < book.level_size(ticks, is_bid) {
+                    seen.last_reduced_ms = Some(timestamp);
+                    checks.pending.retain(|trade| trade.is_bid != is_bid || trade.ticks != ticks);
+                }
+            }
+        }
+
+        // what is still pending has waited too long
+        while let Some(trade) = checks.pending.front() {
+            if timestamp.saturating_sub(trade.timestamp) <= self.config.max_delay_ms {
+                break;
+            }
+            checks.counts.late_consumption += 1;
+            if self.config.log_events {
+                warn!("trade_checks: book {} did not reduce {} level {} within {}ms of the trade at {}",
+                    instrument_idx, if trade.is_bid { "bid" } else { "ask" }, trade.ticks, self.config.max_delay_ms, trade.timestamp);
+            }
+            checks.pending.pop_front();
+        }
+    }
+
+    pub fn on_trade(&mut self, trade: &TradeEvent, book: &OrderbookManagerV2) {
+        let instrument_idx = trade.instrument_idx as usize;
+        let Some(checks) = self.instruments.get_mut(instrument_idx) else {
+            return;
+        };
+        if !book.is_initialized() {
+            return;
+        }
+        checks.counts.trades += 1;
+        let ticks = book.tick_size().nearest_ticks(trade.price as f64);
+        let timestamp = trade.timestamp_ns;
+        // a buy takes the asks, a sell the bids
+        let is_bid = trade.side == 0;
+
+        let touch = if is_bid { book.bid_levels().next() } else { book.ask_levels().next() };
+        if let Some((touch, _)) = touch {
+            let beyond = if is_bid { touch - ticks } else { ticks - touch };
+            if beyond > self.config.tolerance_ticks {
+                checks.counts.trade_through += 1;
+                if self.config.log_events {
+                    warn!("trade_checks: book {} trade at {} ticks prints {} ticks through the touch {}", instrument_idx, ticks, beyond, touch);
+                }
+            }
+        }
+
+        let seen = checks.seen.get(&(is_bid, ticks)).copied();
+        let in_book = book.level_size(ticks, is_bid) > 0.0;
+        let recently_seen = seen.is_some_and(|seen| timestamp.saturating_sub(seen.last_seen_ms) <= self.config.seen_retention_ms);
+        if !in_book && !recently_seen {
+            checks.counts.unseen_price += 1;
+            if self.config.log_events {
+                warn!("trade_checks: book {} trade at {} ticks, price never seen on the {} side", instrument_idx, ticks, if is_bid { "bid" } else { "ask" });
+            }
+            return;
+        }
+
+        // the book may already have shown the consumption
+        let reduced = seen
+            .and_then(|seen| seen.last_reduced_ms)
+            .is_some_and(|reduced| reduced.abs_diff(timestamp) <= self.config.max_delay_ms);
+        if !reduced && checks.pending.len() < MAX_PENDING_TRADES {
+            checks.pending.push_back(PendingTrade { is_bid, ticks, timestamp });
+        }
+    }
+
+    // Forgets prices that left the book longer ago than the retention, called from the stats timer
+    pub fn prune(&mut self, now_ms: u64) {
+        let retention = self.config.seen_retention_ms;
+        for checks in &mut self.instruments {
+            checks.seen.retain(|_, seen| now_ms.saturating_sub(seen.last_seen_ms) <= retention);
+        }
+    }
+
+    // The book is about to be rebuilt, what it showed so far says nothing about the next trades
+    pub fn reset(&mut self, instrument_idx: usize) {
+        if let Some(checks) = self.instruments.get_mut(instrument_idx) {
+            checks.pending.clear();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::parsing::parsing_orderbook::OrderbookLevel;
+    use crate::price::{Decimal, TickSize};
+
+    fn level(action: OrderbookAction, price: f64, size: f32) -> OrderbookLevel {
+        OrderbookLevel { action, price: Decimal::from_f64(price).unwrap(), size }
+    }
+
+    fn trade(price: f32, side: u8, timestamp: u64) -> TradeEvent {
+        TradeEvent { instrument_idx: 0, price, size: 1.0, side, timestamp_ns: timestamp, trade_id: 0, padding: [0; 6] }
+    }
+
+    #[test]
+    fn test_trade_checks() {
+        let config = TradeCheckConfig { tolerance_ticks: 1, max_delay_ms: 100, seen_retention_ms: 60_000, log_events: false };
+        let mut checker = TradeChecker::new(config, 1);
+        let mut book = OrderbookManagerV2::new(10, TickSize::new(0.5).unwrap());
+        let mut snapshot = OrderbookUpdateDataRaw::new();
+        snapshot.add_bid(level(OrderbookAction::New, 100.0, 1.0));
+        snapshot.add_ask(level(OrderbookAction::New, 101.0, 1.0));
+        snapshot.add_ask(level(OrderbookAction::New, 102.0, 1.0));
+        checker.on_book_update(0, &book, &snapshot, 1_000);
+        book.apply_snapshot(snapshot, 1, Instant::now()).unwrap();
+
+        // buy at the best ask, consumed by the next delta
+        checker.on_trade(&trade(101.0, 1, 1_010), &book);
+        let mut delta = OrderbookUpdateDataRaw::new();
+        delta.set_prev_change_id(1);
+        delta.add_ask(level(OrderbookAction::Delete, 101.0, 0.0));
+        checker.on_book_update(0, &book, &delta, 1_020);
+        book.apply_update(delta, 2, Instant::now()).unwrap();
+
+        // sell 2 ticks through the best bid at a price never quoted
+        checker.on_trade(&trade(99.0, 0, 1_030), &book);
+        let mut delta = OrderbookUpdateDataRaw::new();
+        delta.set_prev_change_id(2);
+        delta.add_ask(level(OrderbookAction::New, 101.5, 1.0));
+        // buy at the new best ask, the level is never reduced
+        checker.on_trade(&trade(102.0, 1, 1_040), &book);
+        checker.on_book_update(0, &book, &delta, 1_500);
+
+        let counts = checker.counts(0);
+        assert_eq!(counts.trades, 3);
+        assert_eq!(counts.trade_through, 1);
+        assert_eq!(counts.unseen_price, 1);
+        assert_eq!(counts.late_consumption, 1);
+    }
+}
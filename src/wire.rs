@@ -0,0 +1,279 @@
+use thiserror::Error;
+use haiku_common::shm_accessor::market_data_type::{OrderbookData, TradeEvent};
+use crate::orderbook_management::SHM_BOOK_LEVELS;
+use crate::sinks::SinkStatus;
+
+// Binary encoding of the published market data, little endian, every message starts with a header:
+//   magic u16 (0x4648, "HF"), version u8, message type u8, channel u16, body length u16, sequence u64
+// The channel is the instrument index, ALL_CHANNELS for messages about the whole feed. The sequence
+// counts the messages of a channel from 1, a consumer seeing a jump knows what it missed.
+//
+// Bodies:
+//   book   (1): exchange ts u64, flag u8, levels u8, padding [u8; 6],
+//               then per level from the touch: bid price f32, bid size f32, ask price f32, ask size f32
+//   trade  (2): exchange ts u64, trade id u64, price f32, size f32, side u8 (1 buy), padding [u8; 7]
+//   status (3): code u8 (1 book reset, 2 book snapshot, 3 shutdown), padding [u8; 7]
+//   book delta (4): exchange ts u64, flag u8, count u8, padding [u8; 6],
+//               then per changed level: side u8 (1 bid, 0 ask), padding [u8; 3], price f32, size f32 (0 removes it)
+//
+// A book message carries the whole top of the book: losing one is repaired by the next. A book delta only
+// makes sense on top of the previous book of a stream without loss (the subscription server).
+
+pub const MAGIC: u16 = 0x4648;
+pub const VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 16;
+pub const ALL_CHANNELS: u16 = u16::MAX;
+// every level of the top of the book replaced, on both sides
+pub const MAX_LEVEL_CHANGES: usize = 4 * SHM_BOOK_LEVELS;
+// a book delta with MAX_LEVEL_CHANGES changes, the largest message
+pub const MAX_MESSAGE_SIZE: usize = HEADER_SIZE + 16 + 12 * MAX_LEVEL_CHANGES;
+
+const BOOK: u8 = 1;
+const TRADE: u8 = 2;
+const STATUS: u8 = 3;
+const BOOK_DELTA: u8 = 4;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum WireError {
+    #[error("message truncated, {0} bytes")]
+    Truncated(usize),
+    #[error("bad magic {0:#06x}")]
+    BadMagic(u16),
+    #[error("unsupported version {0}")]
+    UnsupportedVersion(u8),
+    #[error("unknown message type {0}")]
+    UnknownType(u8),
+    #[error("unknown status code {0}")]
+    UnknownStatus(u8),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LevelChange {
+    pub is_bid: bool,
+    pub price: f32,
+    pub size: f32,
+}
+
+#[derive(Clone, Copy)]
+pub enum WireMessage {
+    // levels beyond `levels` are left at 0
+    Book { timestamp: u64, flag: u8, levels: u8, book: OrderbookData },
+    Trade(TradeEvent),
+    Status(SinkStatus),
+    // the first `count` changes are used
+    BookDelta { timestamp: u64, flag: u8, count: u8, changes: [LevelChange; MAX_LEVEL_CHANGES] },
+}
+
+impl WireMessage {
+    #[inline]
+    pub fn is_book(&self) -> bool {
+        matches!(self, WireMessage::Book { .. })
+    }
+}
+
+// Channel of a status, the instrument it is about or the whole feed
+#[inline]
+pub fn status_channel(status: SinkStatus) -> u16 {
+    match status {
+        SinkStatus::BookReset(idx) | SinkStatus::BookSnapshot(idx) => idx as u16,
+        SinkStatus::Shutdown => ALL_CHANNELS,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub message_type: u8,
+    pub channel: u16,
+    pub seq: u64,
+}
+
+// Appends the message to `buf`
+pub fn encode(buf: &mut Vec<u8>, channel: u16, seq: u64, message: &WireMessage) {
+    let (message_type, body_len) = match message {
+        WireMessage::Book { levels, .. } => (BOOK, 16 + 16 * *levels as usize),
+        WireMessage::Trade(_) => (TRADE, 32),
+        WireMessage::Status(_) => (STATUS, 8),
+        WireMessage::BookDelta { count, .. } => (BOOK_DELTA, 16 + 12 * *count as usize),
+    };
+    buf.extend_from_slice(&MAGIC.to_le_bytes());
+    buf.push(VERSION);
+    buf.push(message_type);
+    buf.extend_from_slice(&channel.to_le_bytes());
+    buf.extend_from_slice(&(body_len as u16).to_le_bytes());
+    buf.extend_from_slice(&seq.to_le_bytes());
+    match message {
+        WireMessage::Book { timestamp, flag, levels, book } => {
+            buf.extend_from_slice(&timestamp.to_le_bytes());
+            buf.push(*flag);
+            buf.push(*levels);
+            buf.extend_from_slice(&[0; 6]);
+            for n in 0..*levels as usize {
+                for value in [book.bid_prices[n], book.bid_sizes[n], book.ask_prices[n], book.ask_sizes[n]] {
+                    buf.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+        WireMessage::Trade(trade) => {
+            buf.extend_from_slice(&trade.timestamp_ns.to_le_bytes());
+            buf.extend_from_slice(&trade.trade_id.to_le_bytes());
+            buf.extend_from_slice(&trade.price.to_le_bytes());
+            buf.extend_from_slice(&trade.size.to_le_bytes());
+            buf.push(trade.side);
+            buf.extend_from_slice(&[0; 7]);
+        }
+        WireMessage::Status(status) => {
+            let code = match status {
+                SinkStatus::BookReset(_) => 1,
+                SinkStatus::BookSnapshot(_) => 2,
+                SinkStatus::Shutdown => 3,
+            };
+            buf.push(code);
+            buf.extend_from_slice(&[0; 7]);
+        }
+        WireMessage::BookDelta { timestamp, flag, count, changes } => {
+            buf.extend_from_slice(&timestamp.to_le_bytes());
+            buf.push(*flag);
+            buf.push(*count);
+            buf.extend_from_slice(&[0; 6]);
+            for change in &changes[..*count as usize] {
+                buf.push(change.is_bid as u8);
+                buf.extend_from_slice(&[0; 3]);
+                buf.extend_from_slice(&change.price.to_le_bytes());
+                buf.extend_from_slice(&change.size.to_le_bytes());
+            }
+        }
+    }
+}
+
+#[inline]
+fn u64_at(data: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap_or_default())
+}
+
+#[inline]
+fn f32_at(data: &[u8], pos: usize) -> f32 {
+    f32::from_le_bytes(data[pos..pos + 4].try_into().unwrap_or_default())
+}
+
+// Decodes one message, returns it with its size in `data`
+pub fn decode(data: &[u8]) -> Result<(Header, WireMessage, usize), WireError> {
+    if data.len() < HEADER_SIZE {
+        return Err(WireError::Truncated(data.len()));
+    }
+    let magic = u16::from_le_bytes([data[0], data[1]]);
+    if magic != MAGIC {
+        return Err(WireError::BadMagic(magic));
+    }
+    if data[2] != VERSION {
+        return Err(WireError::UnsupportedVersion(data[2]));
+    }
+    let header = Header { message_type: data[3], channel: u16::from_le_bytes([data[4], data[5]]), seq: u64_at(data, 8) };
+    let size = HEADER_SIZE + u16::from_le_bytes([data[6], data[7]]) as usize;
+    if data.len() < size {
+        return Err(WireError::Truncated(data.len()));
+    }
+    let body = &data[HEADER_SIZE..size];
+    let message = match header.message_type {
+        BOOK if body.len() >= 16 => {
+            let levels = body[9].min(SHM_BOOK_LEVELS as u8);
+            if body.len() < 16 + 16 * levels as usize {
+                return Err(WireError::Truncated(data.len()));
+            }
+            let mut book = OrderbookData {
+                bid_prices: [0.0; SHM_BOOK_LEVELS],
+                ask_prices: [0.0; SHM_BOOK_LEVELS],
+                bid_sizes: [0.0; SHM_BOOK_LEVELS],
+                ask_sizes: [0.0; SHM_BOOK_LEVELS],
+            };
+            for n in 0..levels as usize {
+                let pos = 16 + 16 * n;
+                book.bid_prices[n] = f32_at(body, pos);
+                book.bid_sizes[n] = f32_at(body, pos + 4);
+                book.ask_prices[n] = f32_at(body, pos + 8);
+                book.ask_sizes[n] = f32_at(body, pos + 12);
+            }
+            WireMessage::Book { timestamp: u64_at(body, 0), flag: body[8], levels, book }
+        }
+        TRADE if body.len() >= 32 => WireMessage::Trade(TradeEvent {
+            instrument_idx: header.channel as u8,
+            price: f32_at(body, 16),
+            size: f32_at(body, 20),
+            side: body[24],
+            timestamp_ns: u64_at(body, 0),
+            trade_id: u64_at(body, 8),
+            padding: [0; 6],
+        }),
+        STATUS if !body.is_empty() => {
+            let idx = header.channel as usize;
+            WireMessage::Status(match body[0] {
+                1 => SinkStatus::BookReset(idx),
+                2 => SinkStatus::BookSnapshot(idx),
+                3 => SinkStatus::Shutdown,
+                other => return Err(WireError::UnknownStatus(other)),
+            })
+        }
+        BOOK_DELTA if body.len() >= 16 => {
+            let count = body[9].min(MAX_LEVEL_CHANGES as u8);
+            if body.len() < 16 + 12 * count as usize {
+                return Err(WireError::Truncated(data.len()));
+            }
+            let mut changes = [LevelChange::default(); MAX_LEVEL_CHANGES];
+            for (n, change) in changes.iter_mut().take(count as usize).enumerate() {
+                let pos = 16 + 12 * n;
+                *change = LevelChange { is_bid: body[pos] == 1, price: f32_at(body, pos + 4), size: f32_at(body, pos + 8) };
+            }
+            WireMessage::BookDelta { timestamp: u64_at(body, 0), flag: body[8], count, changes }
+        }
+        BOOK | TRADE | STATUS | BOOK_DELTA => return Err(WireError::Truncated(data.len())),
+        other => return Err(WireError::UnknownType(other)),
+    };
+    Ok((header, message, size))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut book = OrderbookData { bid_prices: [0.0; 10], ask_prices: [0.0; 10], bid_sizes: [0.0; 10], ask_sizes: [0.0; 10] };
+        book.bid_prices[0] = 100.5;
+        book.bid_sizes[0] = 2.0;
+        book.ask_prices[1] = 102.0;
+        let trade = TradeEvent { instrument_idx: 3, price: 101.0, size: 0.5, side: 1, timestamp_ns: 1_001, trade_id: 42, padding: [0; 6] };
+        let messages = [
+            WireMessage::Book { timestamp: 1_000, flag: 0b11, levels: 2, book },
+            WireMessage::Trade(trade),
+            WireMessage::Status(SinkStatus::BookReset(3)),
+            WireMessage::BookDelta { timestamp: 1_002, flag: 0b10, count: 1, changes: [LevelChange { is_bid: false, price: 102.0, size: 0.0 }; MAX_LEVEL_CHANGES] },
+        ];
+
+        let mut buf = Vec::new();
+        for (seq, message) in messages.iter().enumerate() {
+            encode(&mut buf, 3, seq as u64 + 1, message);
+        }
+        let mut pos = 0;
+        let mut decoded = Vec::new();
+        for seq in 1..=messages.len() as u64 {
+            let (header, message, size) = decode(&buf[pos..]).unwrap();
+            assert_eq!((header.channel, header.seq), (3, seq));
+            decoded.push(message);
+            pos += size;
+        }
+        assert_eq!(pos, buf.len());
+        assert!(matches!(decoded[0], WireMessage::Book { timestamp: 1_000, flag: 0b11, levels: 2, book }
+            if book.bid_prices[0] == 100.5 && book.bid_sizes[0] == 2.0 && book.ask_prices[1] == 102.0 && book.ask_prices[2] == 0.0));
+        assert!(matches!(decoded[1], WireMessage::Trade(trade)
+            if trade.instrument_idx == 3 && trade.price == 101.0 && trade.side == 1 && trade.timestamp_ns == 1_001 && trade.trade_id == 42));
+        assert!(matches!(decoded[2], WireMessage::Status(SinkStatus::BookReset(3))));
+        assert!(matches!(decoded[3], WireMessage::BookDelta { timestamp: 1_002, count: 1, changes, .. }
+            if changes[0] == LevelChange { is_bid: false, price: 102.0, size: 0.0 } && changes[1] == LevelChange::default()));
+        assert!(matches!(decode(&buf[..10]), Err(WireError::Truncated(10))));
+
+        let mut status = Vec::new();
+        encode(&mut status, 3, 1, &WireMessage::Status(SinkStatus::BookReset(3)));
+        status[HEADER_SIZE] = 9;
+        assert!(matches!(decode(&status), Err(WireError::UnknownStatus(9))));
+    }
+}